@@ -1,13 +1,64 @@
 use crate::tokens::Token;
 
 #[derive(Debug, PartialEq)]
-pub struct AST {
+pub struct Ast {
     pub statements: Vec<Statement>,
 }
 
 #[derive(Debug, PartialEq)]
 pub enum Statement {
-    FunctionCall { function_call: FunctionCall },
+    FunctionCall {
+        function_call: FunctionCall,
+    },
+    /// Compound assignments like `x += 1` have an operator
+    Assignment {
+        variable: String,
+        key: Option<Expr>,
+        operator: Option<BinaryOperator>,
+        value: Expr,
+    },
+    If {
+        condition: Expr,
+        statements: Vec<Statement>,
+        else_statements: Vec<Statement>,
+    },
+    For {
+        variable: String,
+        iterable: Expr,
+        statements: Vec<Statement>,
+    },
+    /// Aggregates items by the key in the first argument, e.g.
+    /// `group_by(_[0], "-count") { count(); bytes = sum(_[2]); }`
+    GroupBy {
+        args: Vec<Expr>,
+        statements: Vec<Statement>,
+    },
+    /// Runs the statements for the items from one that matches the start
+    /// condition to one that matches the end condition, e.g.
+    /// `range(/BEGIN TX/, /END TX/) { print(_) }`
+    Range {
+        args: Vec<Expr>,
+        statements: Vec<Statement>,
+    },
+    /// Skips the remaining statements for the current item
+    Next,
+    /// Only allowed at the top level; run before the first item
+    Begin {
+        statements: Vec<Statement>,
+    },
+    /// Only allowed at the top level; run after the last item
+    End {
+        statements: Vec<Statement>,
+    },
+}
+
+impl Statement {
+    fn ends_with_block(&self) -> bool {
+        !matches!(
+            self,
+            Statement::FunctionCall { .. } | Statement::Assignment { .. } | Statement::Next
+        )
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -19,49 +70,318 @@ pub struct FunctionCall {
 #[derive(Debug, Clone, PartialEq)]
 pub struct Expr {
     pub arr_expr: ArrExpr,
-    pub index: Option<i32>,
+    pub index: Option<Index>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Index {
+    Position(i32),
+    Expr(Box<Expr>),
+}
+
+impl From<ArrExpr> for Expr {
+    fn from(arr_expr: ArrExpr) -> Self {
+        Expr {
+            arr_expr,
+            index: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum ArrExpr {
-    FunctionExpr { function_call: FunctionCall },
-    ValueExpr { value: Value },
+    FunctionExpr {
+        function_call: FunctionCall,
+    },
+    ValueExpr {
+        value: Value,
+    },
+    MapLiteral {
+        entries: Vec<MapEntry>,
+    },
+    BinaryExpr {
+        operator: BinaryOperator,
+        lhs: Box<Expr>,
+        rhs: Box<Expr>,
+    },
+    UnaryExpr {
+        operator: UnaryOperator,
+        operand: Box<Expr>,
+    },
+    /// An expression in parentheses, or an expression that is indexed more
+    /// than once, e.g. `m["a"]` in `m["a"]["b"]`
+    NestedExpr {
+        expr: Box<Expr>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+    And,
+    Or,
+    Match,
+    NotMatch,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOperator {
+    Neg,
+    Not,
+}
+
+/// Binary operators by precedence, from lowest to highest
+const BINARY_OPERATORS: [&[(&str, BinaryOperator)]; 6] = [
+    &[("||", BinaryOperator::Or)],
+    &[("&&", BinaryOperator::And)],
+    &[
+        ("==", BinaryOperator::Eq),
+        ("!=", BinaryOperator::NotEq),
+        ("~", BinaryOperator::Match),
+        ("!~", BinaryOperator::NotMatch),
+    ],
+    &[
+        ("<", BinaryOperator::Lt),
+        ("<=", BinaryOperator::LtEq),
+        (">", BinaryOperator::Gt),
+        (">=", BinaryOperator::GtEq),
+    ],
+    &[("+", BinaryOperator::Add), ("-", BinaryOperator::Sub)],
+    &[
+        ("*", BinaryOperator::Mul),
+        ("/", BinaryOperator::Div),
+        ("%", BinaryOperator::Rem),
+    ],
+];
+
+const COMPOUND_ASSIGNMENTS: [(&str, BinaryOperator); 5] = [
+    ("+=", BinaryOperator::Add),
+    ("-=", BinaryOperator::Sub),
+    ("*=", BinaryOperator::Mul),
+    ("/=", BinaryOperator::Div),
+    ("%=", BinaryOperator::Rem),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MapEntry {
+    pub key: Expr,
+    pub value: Expr,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Item, // _
     Identifier(String),
-    // String(String),
+    String(String),
+    Bool(bool),
+    /// Decimal, hexadecimal or scientific notation, e.g. `12`, `0x1F`, `1.5e3`
+    Number(String),
+    Regex(String),
 }
 
+const KEYWORDS: [&str; 8] = ["begin", "end", "if", "else", "for", "in", "true", "false"];
+
 // TODO no syntax error handling yet; see
 // https://craftinginterpreters.com/parsing-expressions.html#syntax-errors
-pub fn parse(tokens: Vec<Token>) -> Result<AST, String> {
-    let mut token_slice = &tokens[..];
+pub fn parse(tokens: Vec<Token>) -> Result<Ast, String> {
+    let (statements, remainder) = parse_statements(&tokens, true)?;
+    match remainder.first() {
+        None => Ok(Ast { statements }),
+        Some(t) => Err(format!("Unexpected token '{:?}'", t)),
+    }
+}
+
+/// Like `parse`, but also returns the index of the first token of each
+/// top-level statement, so that errors can be located. On errors, returns the
+/// index of the first token of the statement that cannot be parsed.
+pub fn parse_located(tokens: &[Token]) -> Result<(Ast, Vec<usize>), (String, usize)> {
+    let mut statements = Vec::new();
+    let mut starts = Vec::new();
+    let mut remainder = tokens;
+    while let Some(token) = remainder.first() {
+        let start = tokens.len() - remainder.len();
+        if *token == Token::CloseBrace {
+            return Err((format!("Unexpected token '{:?}'", token), start));
+        }
+        let (statement, rest) = parse_next_statement(remainder, true).map_err(|e| (e, start))?;
+        statements.push(statement);
+        starts.push(start);
+        remainder = rest;
+    }
+    Ok((Ast { statements }, starts))
+}
+
+/// Parses a single expression, e.g. the key of `--split-by`
+pub fn parse_expression(tokens: Vec<Token>) -> Result<Expr, String> {
+    let (expr, remainder) = parse_expr(&tokens)?;
+    match remainder.first() {
+        None => Ok(expr),
+        Some(t) => Err(format!("Unexpected token '{:?}'", t)),
+    }
+}
+
+/// Parses statements until the end of the tokens or a closing brace. The
+/// semicolon after a statement that ends with a block is optional, as is the
+/// semicolon after the last statement.
+fn parse_statements(
+    mut tokens: &[Token],
+    top_level: bool,
+) -> Result<(Vec<Statement>, &[Token]), String> {
     let mut statements = Vec::new();
 
-    while !token_slice.is_empty() {
-        let (statement, remainder) = parse_statement(token_slice)?;
-        log::trace!("parsed statement {:?}", statement);
-        token_slice = remainder;
+    while !tokens.is_empty() && tokens[0] != Token::CloseBrace {
+        let (statement, remainder) = parse_next_statement(tokens, top_level)?;
         statements.push(statement);
+        tokens = remainder;
+    }
 
-        if !token_slice.is_empty() {
-            token_slice = expect_token(Token::Semicolon, token_slice)?;
+    Ok((statements, tokens))
+}
+
+/// Parses a statement and the semicolon after it, if any
+fn parse_next_statement(
+    tokens: &[Token],
+    top_level: bool,
+) -> Result<(Statement, &[Token]), String> {
+    let (statement, tokens) = parse_statement(tokens, top_level)?;
+    log::trace!("parsed statement {:?}", statement);
+    let tokens = match expect_token(Token::Semicolon, tokens) {
+        Ok(ts) => ts,
+        Err(_) if statement.ends_with_block() => tokens,
+        Err(_) if tokens.is_empty() || tokens[0] == Token::CloseBrace => tokens,
+        Err(e) => return Err(e),
+    };
+    Ok((statement, tokens))
+}
+
+fn parse_statement(tokens: &[Token], top_level: bool) -> Result<(Statement, &[Token]), String> {
+    match tokens {
+        [Token::Alphanum(kw), ..] if kw == "begin" || kw == "end" => {
+            if !top_level {
+                return Err(format!("{} blocks are only allowed at the top level", kw));
+            }
+            let (statements, tokens) = parse_block(&tokens[1..])?;
+            match kw.as_str() {
+                "begin" => Ok((Statement::Begin { statements }, tokens)),
+                _ => Ok((Statement::End { statements }, tokens)),
+            }
+        }
+        [Token::Alphanum(kw), ..] if kw == "if" => parse_if(tokens),
+        [Token::Alphanum(kw), ..] if kw == "for" => parse_for(tokens),
+        [Token::Alphanum(kw), Token::OpenParen, ..] if kw == "group_by" => {
+            let (FunctionCall { args, .. }, tokens) = parse_function_call(tokens)?;
+            let (statements, tokens) = parse_block(tokens)?;
+            Ok((Statement::GroupBy { args, statements }, tokens))
         }
+        [Token::Alphanum(kw), Token::OpenParen, ..] if kw == "range" => {
+            let (FunctionCall { args, .. }, tokens) = parse_function_call(tokens)?;
+            let (statements, tokens) = parse_block(tokens)?;
+            Ok((Statement::Range { args, statements }, tokens))
+        }
+        // `next(n)` is a function call, `next = ...` an assignment
+        [Token::Alphanum(kw), rest @ ..]
+            if kw == "next"
+                && matches!(
+                    rest.first(),
+                    None | Some(Token::Semicolon | Token::CloseBrace)
+                ) =>
+        {
+            Ok((Statement::Next, rest))
+        }
+        [Token::Alphanum(_), Token::OpenParen, ..] => {
+            let (function_call, tokens) = parse_function_call(tokens)?;
+            Ok((Statement::FunctionCall { function_call }, tokens))
+        }
+        _ => parse_assignment(tokens),
     }
+}
 
-    Ok(AST { statements })
+fn parse_block(tokens: &[Token]) -> Result<(Vec<Statement>, &[Token]), String> {
+    let tokens = expect_token(Token::OpenBrace, tokens)?;
+    let (statements, tokens) = parse_statements(tokens, false)?;
+    let tokens = expect_token(Token::CloseBrace, tokens)?;
+    Ok((statements, tokens))
 }
 
-fn parse_statement(tokens: &[Token]) -> Result<(Statement, &[Token]), String> {
-    let (function_call, tokens) = parse_function_call(tokens)?;
-    Ok((Statement::FunctionCall { function_call }, tokens))
+fn parse_if(tokens: &[Token]) -> Result<(Statement, &[Token]), String> {
+    let tokens = expect_keyword("if", tokens)?;
+    let (condition, tokens) = parse_expr(tokens)?;
+    let (statements, tokens) = parse_block(tokens)?;
+    let (else_statements, tokens) = match expect_keyword("else", tokens) {
+        Err(_) => (vec![], tokens),
+        Ok(ts @ [Token::Alphanum(kw), ..]) if kw == "if" => {
+            let (else_if, ts) = parse_if(ts)?;
+            (vec![else_if], ts)
+        }
+        Ok(ts) => parse_block(ts)?,
+    };
+    Ok((
+        Statement::If {
+            condition,
+            statements,
+            else_statements,
+        },
+        tokens,
+    ))
+}
+
+fn parse_for(tokens: &[Token]) -> Result<(Statement, &[Token]), String> {
+    let tokens = expect_keyword("for", tokens)?;
+    let (variable, tokens) = parse_identifier(tokens)?;
+    let tokens = expect_keyword("in", tokens)?;
+    let (iterable, tokens) = parse_expr(tokens)?;
+    let (statements, tokens) = parse_block(tokens)?;
+    Ok((
+        Statement::For {
+            variable,
+            iterable,
+            statements,
+        },
+        tokens,
+    ))
+}
+
+fn parse_assignment(tokens: &[Token]) -> Result<(Statement, &[Token]), String> {
+    let (variable, tokens) = parse_identifier(tokens)?;
+    let (key, tokens) = match expect_token(Token::OpenBracket, tokens) {
+        Ok(ts) => {
+            let (key, ts) = parse_expr(ts)?;
+            (Some(key), expect_token(Token::CloseBracket, ts)?)
+        }
+        Err(_) => (None, tokens),
+    };
+    let (operator, tokens) = match tokens.first() {
+        Some(Token::Operator(op)) => COMPOUND_ASSIGNMENTS
+            .iter()
+            .find(|(s, _)| s == op)
+            .map(|(_, operator)| (Some(*operator), &tokens[1..]))
+            .ok_or(format!("Expected assignment, got '{}'", op))?,
+        _ => (None, expect_token(Token::Assign, tokens)?),
+    };
+    let (value, tokens) = parse_expr(tokens)?;
+    Ok((
+        Statement::Assignment {
+            variable,
+            key,
+            operator,
+            value,
+        },
+        tokens,
+    ))
 }
 
 fn parse_function_call(tokens: &[Token]) -> Result<(FunctionCall, &[Token]), String> {
-    let (function_name, mut tokens) = parse_identifier(tokens)?;
+    let (function_name, mut tokens) = parse_function_name(tokens)?;
     tokens = expect_token(Token::OpenParen, tokens)?;
 
     let mut expressions = vec![];
@@ -76,7 +396,7 @@ fn parse_function_call(tokens: &[Token]) -> Result<(FunctionCall, &[Token]), Str
         expressions.push(expr);
         (done, tokens) = match expect_token(Token::CloseParen, tokens) {
             Ok(ts) => (true, ts),
-            Err(_) => (false, tokens),
+            Err(_) => (false, expect_token(Token::Comma, tokens)?),
         };
     }
 
@@ -90,57 +410,165 @@ fn parse_function_call(tokens: &[Token]) -> Result<(FunctionCall, &[Token]), Str
 }
 
 fn parse_expr(tokens: &[Token]) -> Result<(Expr, &[Token]), String> {
-    let (expr, tokens) = parse_arr_expr(tokens)?;
-    let (index, tokens) = match tokens {
-        [Token::OpenBracket, ..] => parse_array_index(tokens).map(|(v, t)| (Some(v), t))?,
-        _ => (None, tokens),
+    parse_binary_expr(tokens, 0)
+}
+
+/// Parses binary operators with the given precedence level or higher
+fn parse_binary_expr(tokens: &[Token], level: usize) -> Result<(Expr, &[Token]), String> {
+    let Some(operators) = BINARY_OPERATORS.get(level) else {
+        return parse_unary_expr(tokens);
     };
+    let (mut lhs, mut tokens) = parse_binary_expr(tokens, level + 1)?;
+    while let Some(Token::Operator(op)) = tokens.first() {
+        let Some((_, operator)) = operators.iter().find(|(s, _)| s == op) else {
+            break;
+        };
+        let (rhs, ts) = parse_binary_expr(&tokens[1..], level + 1)?;
+        lhs = Expr::from(ArrExpr::BinaryExpr {
+            operator: *operator,
+            lhs: Box::new(lhs),
+            rhs: Box::new(rhs),
+        });
+        tokens = ts;
+    }
+    Ok((lhs, tokens))
+}
+
+fn parse_unary_expr(tokens: &[Token]) -> Result<(Expr, &[Token]), String> {
+    let operator = match tokens.first() {
+        Some(Token::Operator(op)) if op == "-" => UnaryOperator::Neg,
+        Some(Token::Operator(op)) if op == "!" => UnaryOperator::Not,
+        _ => return parse_indexed_expr(tokens),
+    };
+    let (operand, tokens) = parse_unary_expr(&tokens[1..])?;
     Ok((
-        Expr {
-            arr_expr: expr,
-            index,
-        },
+        Expr::from(ArrExpr::UnaryExpr {
+            operator,
+            operand: Box::new(operand),
+        }),
         tokens,
     ))
 }
 
+fn parse_indexed_expr(tokens: &[Token]) -> Result<(Expr, &[Token]), String> {
+    let (expr, mut tokens) = parse_arr_expr(tokens)?;
+    let mut expr = Expr::from(expr);
+    while let [Token::OpenBracket, ..] = tokens {
+        let (index, ts) = parse_array_index(tokens)?;
+        if expr.index.is_some() {
+            expr = Expr::from(ArrExpr::NestedExpr {
+                expr: Box::new(expr),
+            });
+        }
+        expr.index = Some(index);
+        tokens = ts;
+    }
+    Ok((expr, tokens))
+}
+
 fn parse_arr_expr(tokens: &[Token]) -> Result<(ArrExpr, &[Token]), String> {
     parse_function_call(tokens)
         .map(|(function_call, ts)| (ArrExpr::FunctionExpr { function_call }, ts))
+        .or(parse_map(tokens).map(|(entries, ts)| (ArrExpr::MapLiteral { entries }, ts)))
+        .or(parse_parenthesized(tokens).map(|(expr, ts)| {
+            let expr = Box::new(expr);
+            (ArrExpr::NestedExpr { expr }, ts)
+        }))
         .or(parse_value(tokens).map(|(value, ts)| (ArrExpr::ValueExpr { value }, ts)))
 }
 
+fn parse_parenthesized(tokens: &[Token]) -> Result<(Expr, &[Token]), String> {
+    let tokens = expect_token(Token::OpenParen, tokens)?;
+    let (expr, tokens) = parse_expr(tokens)?;
+    let tokens = expect_token(Token::CloseParen, tokens)?;
+    Ok((expr, tokens))
+}
+
+fn parse_map(tokens: &[Token]) -> Result<(Vec<MapEntry>, &[Token]), String> {
+    let mut tokens = expect_token(Token::OpenBrace, tokens)?;
+    let mut entries = vec![];
+    while expect_token(Token::CloseBrace, tokens).is_err() {
+        let (key, ts) = parse_expr(tokens)?;
+        let ts = expect_token(Token::Colon, ts)?;
+        let (value, ts) = parse_expr(ts)?;
+        entries.push(MapEntry { key, value });
+        tokens = match expect_token(Token::Comma, ts) {
+            Ok(ts) => ts,
+            Err(_) => {
+                expect_token(Token::CloseBrace, ts)?;
+                ts
+            }
+        };
+    }
+    let tokens = expect_token(Token::CloseBrace, tokens)?;
+    Ok((entries, tokens))
+}
+
 fn expect_token(token: Token, tokens: &[Token]) -> Result<&[Token], String> {
-    match tokens.get(0) {
+    match tokens.first() {
         Some(t) if *t == token => Ok(&tokens[1..]),
-        _ => Err(String::from(format!(
-            "Expected char '{:?}' not found",
-            token
-        ))),
+        _ => Err(format!("Expected char '{:?}' not found", token)),
+    }
+}
+
+fn expect_keyword<'a>(keyword: &str, tokens: &'a [Token]) -> Result<&'a [Token], String> {
+    match tokens.first() {
+        Some(Token::Alphanum(s)) if s == keyword => Ok(&tokens[1..]),
+        _ => Err(format!("Expected keyword '{}' not found", keyword)),
     }
 }
 
-fn parse_array_index(tokens: &[Token]) -> Result<(i32, &[Token]), String> {
+fn parse_array_index(tokens: &[Token]) -> Result<(Index, &[Token]), String> {
     let tokens = expect_token(Token::OpenBracket, tokens)?;
-    let (index, tokens) = match tokens.first() {
-        Some(Token::Alphanum(s)) => s
+    let (index, tokens) = match tokens {
+        [Token::Alphanum(s), Token::CloseBracket, ..] if s.chars().all(|c| c.is_ascii_digit()) => s
             .parse::<i32>()
             .map_err(|err| err.to_string())
-            .map(|r| (r, &tokens[1..])),
-        Some(other) => Err(format!("Unexpected token")),
-        None => Err("Missing index".to_string()),
+            .map(|r| (Index::Position(r), &tokens[1..])),
+        [] => Err("Missing index".to_string()),
+        _ => parse_expr(tokens).map(|(expr, ts)| (Index::Expr(Box::new(expr)), ts)),
     }?;
     let tokens = expect_token(Token::CloseBracket, tokens)?;
     Ok((index, tokens))
 }
 
 fn parse_value(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
-    parse_item(tokens).or(parse_identifier(tokens).map(|(i, ts)| (Value::Identifier(i), ts)))
+    parse_item(tokens)
+        .or(parse_string(tokens))
+        .or(parse_regex(tokens))
+        .or(parse_bool(tokens))
+        .or(parse_number(tokens).map(|(n, ts)| (Value::Number(n), ts)))
+        .or(parse_identifier(tokens).map(|(i, ts)| (Value::Identifier(i), ts)))
+}
+
+fn parse_string(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
+    match tokens.first() {
+        Some(Token::String(s)) => Ok((Value::String(s.clone()), &tokens[1..])),
+        Some(_) => Err(String::from("Not a string")),
+        None => Err(String::from("No token left")),
+    }
+}
+
+fn parse_regex(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
+    match tokens.first() {
+        Some(Token::Regex(s)) => Ok((Value::Regex(s.clone()), &tokens[1..])),
+        Some(_) => Err(String::from("Not a regex")),
+        None => Err(String::from("No token left")),
+    }
+}
+
+fn parse_bool(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
+    match tokens.first() {
+        Some(Token::Alphanum(s)) if s == "true" => Ok((Value::Bool(true), &tokens[1..])),
+        Some(Token::Alphanum(s)) if s == "false" => Ok((Value::Bool(false), &tokens[1..])),
+        Some(_) => Err(String::from("Not a bool")),
+        None => Err(String::from("No token left")),
+    }
 }
 
 fn parse_number(tokens: &[Token]) -> Result<(String, &[Token]), String> {
     match tokens.first() {
-        Some(Token::Alphanum(s)) if s.chars().all(|c| char::is_digit(c, 10)) => {
+        Some(Token::Alphanum(s)) if s.starts_with(|c: char| c.is_ascii_digit()) => {
             Ok((s.clone(), &tokens[1..]))
         }
         Some(_) => Err(String::from("Not a number")),
@@ -150,8 +578,15 @@ fn parse_number(tokens: &[Token]) -> Result<(String, &[Token]), String> {
 
 fn parse_identifier(tokens: &[Token]) -> Result<(String, &[Token]), String> {
     match tokens.first() {
+        Some(Token::Alphanum(s)) if KEYWORDS.contains(&s.as_str()) => {
+            Err(format!("{} is a keyword", s))
+        }
+        Some(Token::Alphanum(s)) if s.contains('.') => Err(format!(
+            "Unexpected '.' in {}, only functions have qualified names",
+            s
+        )),
         Some(Token::Alphanum(s))
-            if s.chars().nth(0).map(|c| c.is_alphabetic()).unwrap_or(false) =>
+            if s.chars().next().map(|c| c.is_alphabetic()).unwrap_or(false) =>
         {
             Ok((s.clone(), &tokens[1..]))
         }
@@ -160,6 +595,21 @@ fn parse_identifier(tokens: &[Token]) -> Result<(String, &[Token]), String> {
     }
 }
 
+/// Function names may be qualified, e.g. `agg.sum`
+fn parse_function_name(tokens: &[Token]) -> Result<(String, &[Token]), String> {
+    match tokens.first() {
+        Some(Token::Alphanum(s)) if s.contains('.') => {
+            let (namespace, _) = s.split_once('.').unwrap();
+            parse_identifier(&[Token::Alphanum(namespace.to_string())])?;
+            if s.split('.').any(|part| part.is_empty()) {
+                return Err(format!("Invalid function name {}", s));
+            }
+            Ok((s.clone(), &tokens[1..]))
+        }
+        _ => parse_identifier(tokens),
+    }
+}
+
 fn parse_item(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
     match tokens.first() {
         Some(Token::Underscore) => Ok((Value::Item, &tokens[1..])),
@@ -170,6 +620,7 @@ fn parse_item(tokens: &[Token]) -> Result<(Value, &[Token]), String> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::tokens;
 
     #[test]
     fn parse_function_expr() {
@@ -180,7 +631,7 @@ mod tests {
             Token::Underscore,
             Token::CloseParen,
         ];
-        let expected = AST {
+        let expected = Ast {
             statements: vec![Statement::FunctionCall {
                 function_call: FunctionCall {
                     function_name: String::from("foo"),
@@ -207,13 +658,13 @@ mod tests {
             Token::CloseBracket,
             Token::CloseParen,
         ];
-        let expected = AST {
+        let expected = Ast {
             statements: vec![Statement::FunctionCall {
                 function_call: FunctionCall {
                     function_name: String::from("foo"),
                     args: vec![Expr {
                         arr_expr: ArrExpr::ValueExpr { value: Value::Item },
-                        index: Some(1),
+                        index: Some(Index::Position(1)),
                     }],
                 },
             }],
@@ -221,4 +672,329 @@ mod tests {
         let actual = parse(tokens).unwrap();
         assert_eq!(actual, expected);
     }
+
+    #[test]
+    fn parse_multiple_args() {
+        // foo(_, "bar")
+        let tokens = vec![
+            Token::Alphanum(String::from("foo")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::Comma,
+            Token::String(String::from("bar")),
+            Token::CloseParen,
+        ];
+        let expected = Ast {
+            statements: vec![Statement::FunctionCall {
+                function_call: FunctionCall {
+                    function_name: String::from("foo"),
+                    args: vec![
+                        Expr {
+                            arr_expr: ArrExpr::ValueExpr { value: Value::Item },
+                            index: None,
+                        },
+                        Expr {
+                            arr_expr: ArrExpr::ValueExpr {
+                                value: Value::String(String::from("bar")),
+                            },
+                            index: None,
+                        },
+                    ],
+                },
+            }],
+        };
+        let actual = parse(tokens).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_missing_comma() {
+        // foo(_ _)
+        let tokens = vec![
+            Token::Alphanum(String::from("foo")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::Underscore,
+            Token::CloseParen,
+        ];
+        assert!(parse(tokens).is_err());
+    }
+
+    #[test]
+    fn parse_group_by() {
+        // group_by(_) { count(); }
+        let tokens = vec![
+            Token::Alphanum(String::from("group_by")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::CloseParen,
+            Token::OpenBrace,
+            Token::Alphanum(String::from("count")),
+            Token::OpenParen,
+            Token::CloseParen,
+            Token::Semicolon,
+            Token::CloseBrace,
+        ];
+        let expected = Ast {
+            statements: vec![Statement::GroupBy {
+                args: vec![Expr {
+                    arr_expr: ArrExpr::ValueExpr { value: Value::Item },
+                    index: None,
+                }],
+                statements: vec![Statement::FunctionCall {
+                    function_call: FunctionCall {
+                        function_name: String::from("count"),
+                        args: vec![],
+                    },
+                }],
+            }],
+        };
+        assert_eq!(parse(tokens), Ok(expected));
+    }
+
+    #[test]
+    fn parse_range() {
+        // range(_, _) {}
+        let tokens = vec![
+            Token::Alphanum(String::from("range")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::Comma,
+            Token::Underscore,
+            Token::CloseParen,
+            Token::OpenBrace,
+            Token::CloseBrace,
+        ];
+        let ast = parse(tokens).unwrap();
+        assert!(matches!(
+            &ast.statements[..],
+            [Statement::Range { args, statements }] if args.len() == 2 && statements.is_empty()
+        ));
+    }
+
+    #[test]
+    fn parse_next() {
+        // next; next(1); next = 1
+        let tokens = vec![
+            Token::Alphanum(String::from("next")),
+            Token::Semicolon,
+            Token::Alphanum(String::from("next")),
+            Token::OpenParen,
+            Token::Alphanum(String::from("1")),
+            Token::CloseParen,
+            Token::Semicolon,
+            Token::Alphanum(String::from("next")),
+            Token::Assign,
+            Token::Alphanum(String::from("1")),
+        ];
+        let ast = parse(tokens).unwrap();
+        assert!(matches!(
+            &ast.statements[..],
+            [
+                Statement::Next,
+                Statement::FunctionCall { .. },
+                Statement::Assignment { .. }
+            ]
+        ));
+    }
+
+    #[test]
+    fn parse_located_statements() {
+        // begin { x = 1 } print(x); }
+        let tokens = vec![
+            Token::Alphanum(String::from("begin")),
+            Token::OpenBrace,
+            Token::Alphanum(String::from("x")),
+            Token::Assign,
+            Token::Alphanum(String::from("1")),
+            Token::CloseBrace,
+            Token::Alphanum(String::from("print")),
+            Token::OpenParen,
+            Token::Alphanum(String::from("x")),
+            Token::CloseParen,
+            Token::Semicolon,
+            Token::CloseBrace,
+        ];
+        let (_, index) = parse_located(&tokens).unwrap_err();
+        assert_eq!(index, 11);
+        let (ast, starts) = parse_located(&tokens[..11]).unwrap();
+        assert_eq!(ast.statements.len(), 2);
+        assert_eq!(starts, [0, 6]);
+        let (_, index) = parse_located(&tokens[..9]).unwrap_err();
+        assert_eq!(index, 6);
+    }
+
+    #[test]
+    fn parse_qualified_name() {
+        // agg.sum(_)
+        let tokens = vec![
+            Token::Alphanum(String::from("agg.sum")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::CloseParen,
+        ];
+        let ast = parse(tokens).unwrap();
+        assert!(matches!(
+            &ast.statements[..],
+            [Statement::FunctionCall { function_call }] if function_call.function_name == "agg.sum"
+        ));
+        // Only functions have qualified names: a.b = _
+        let tokens = vec![
+            Token::Alphanum(String::from("a.b")),
+            Token::Assign,
+            Token::Underscore,
+        ];
+        assert!(parse(tokens).is_err());
+    }
+
+    #[test]
+    fn parse_with_key() {
+        // foo(bar(_)["baz"])
+        let tokens = vec![
+            Token::Alphanum(String::from("foo")),
+            Token::OpenParen,
+            Token::Alphanum(String::from("bar")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::CloseParen,
+            Token::OpenBracket,
+            Token::String(String::from("baz")),
+            Token::CloseBracket,
+            Token::CloseParen,
+        ];
+        let expected = Ast {
+            statements: vec![Statement::FunctionCall {
+                function_call: FunctionCall {
+                    function_name: String::from("foo"),
+                    args: vec![Expr {
+                        arr_expr: ArrExpr::FunctionExpr {
+                            function_call: FunctionCall {
+                                function_name: String::from("bar"),
+                                args: vec![Expr {
+                                    arr_expr: ArrExpr::ValueExpr { value: Value::Item },
+                                    index: None,
+                                }],
+                            },
+                        },
+                        index: Some(Index::Expr(Box::new(Expr {
+                            arr_expr: ArrExpr::ValueExpr {
+                                value: Value::String(String::from("baz")),
+                            },
+                            index: None,
+                        }))),
+                    }],
+                },
+            }],
+        };
+        let actual = parse(tokens).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_blocks() {
+        let source_code = r#"
+            begin { seen = {"a": true} }
+            if has(seen, _) { print(_) } else { seen[_] = true }
+            end { for k in seen { print(k) } }
+        "#;
+        let tokens = tokens::scan(source_code.to_string()).unwrap();
+        let ast = parse(tokens).unwrap();
+        assert!(matches!(
+            ast.statements.as_slice(),
+            [
+                Statement::Begin { .. },
+                Statement::If { .. },
+                Statement::End { .. }
+            ]
+        ));
+        let Statement::If {
+            else_statements, ..
+        } = &ast.statements[1]
+        else {
+            unreachable!()
+        };
+        assert!(matches!(
+            else_statements.as_slice(),
+            [Statement::Assignment { key: Some(_), .. }]
+        ));
+    }
+
+    #[test]
+    fn parse_precedence() {
+        // -a + b * c == d
+        let tokens = tokens::scan("-a + b * c == d".to_string()).unwrap();
+        let (actual, remainder) = parse_expr(&tokens).unwrap();
+        assert!(remainder.is_empty());
+        let var = |name: &str| {
+            Box::new(Expr::from(ArrExpr::ValueExpr {
+                value: Value::Identifier(name.to_string()),
+            }))
+        };
+        let expected = Expr::from(ArrExpr::BinaryExpr {
+            operator: BinaryOperator::Eq,
+            lhs: Box::new(Expr::from(ArrExpr::BinaryExpr {
+                operator: BinaryOperator::Add,
+                lhs: Box::new(Expr::from(ArrExpr::UnaryExpr {
+                    operator: UnaryOperator::Neg,
+                    operand: var("a"),
+                })),
+                rhs: Box::new(Expr::from(ArrExpr::BinaryExpr {
+                    operator: BinaryOperator::Mul,
+                    lhs: var("b"),
+                    rhs: var("c"),
+                })),
+            })),
+            rhs: var("d"),
+        });
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn parse_left_associative() {
+        // a - b - c is (a - b) - c
+        let tokens = tokens::scan("a - b - c".to_string()).unwrap();
+        let (actual, _) = parse_expr(&tokens).unwrap();
+        let ArrExpr::BinaryExpr { lhs, .. } = actual.arr_expr else {
+            panic!("Expected a binary expression");
+        };
+        assert!(matches!(lhs.arr_expr, ArrExpr::BinaryExpr { .. }));
+    }
+
+    #[test]
+    fn parse_chained_index() {
+        // m["a"][0]
+        let tokens = tokens::scan("m[\"a\"][0]".to_string()).unwrap();
+        let (actual, _) = parse_expr(&tokens).unwrap();
+        assert_eq!(actual.index, Some(Index::Position(0)));
+        let ArrExpr::NestedExpr { expr } = actual.arr_expr else {
+            panic!("Expected a nested expression");
+        };
+        assert!(matches!(expr.index, Some(Index::Expr(_))));
+    }
+
+    #[test]
+    fn parse_compound_assignment() {
+        let tokens = tokens::scan("count[_[0]] += 1".to_string()).unwrap();
+        let ast = parse(tokens).unwrap();
+        assert!(matches!(
+            ast.statements.as_slice(),
+            [Statement::Assignment {
+                key: Some(_),
+                operator: Some(BinaryOperator::Add),
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn parse_nested_begin() {
+        let tokens = tokens::scan("if x { begin { } }".to_string()).unwrap();
+        assert!(parse(tokens).is_err());
+    }
+
+    #[test]
+    fn parse_missing_semicolon() {
+        let tokens = tokens::scan("x = _ print(x)".to_string()).unwrap();
+        assert!(parse(tokens).is_err());
+    }
 }
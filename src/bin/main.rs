@@ -1,17 +1,24 @@
 use std::fmt::Debug;
 use std::fs;
 use std::io;
-use std::io::BufRead;
 use std::io::Write;
 
+use text_transformer::fixed_width;
+use text_transformer::grok;
 use text_transformer::program;
 use text_transformer::program_source;
 use text_transformer::program_source::ProgramSource;
+use text_transformer::records;
+use text_transformer::sort;
+use text_transformer::split;
 
+/// The arguments after the program source. Without files, the input is read
+/// from stdin.
 #[derive(Debug, PartialEq, Eq)]
-pub enum InputFile {
-    InStream,
-    Files(Vec<String>),
+pub enum Operand {
+    File(String),
+    /// `name=value` assigns to a variable before the following file is read
+    Assignment(String, String),
 }
 
 pub enum Verbosity {
@@ -21,9 +28,22 @@ pub enum Verbosity {
 }
 
 pub struct Config {
-    input_files: InputFile,
-    program: ProgramSource,
+    operands: Vec<Operand>,
+    programs: Vec<ProgramSource>,
     verbosity: Verbosity,
+    output_mode: program::OutputMode,
+    number_mode: program::NumberMode,
+    field_widths: Option<String>,
+    trim: fixed_width::Trim,
+    pattern_files: Vec<String>,
+    sort_by: Option<sort::LineKey>,
+    sort_memory: Option<usize>,
+    separator: Option<records::Separator>,
+    split_by: Option<String>,
+    out: Option<String>,
+    max_open: usize,
+    allow_exec: bool,
+    variables: Vec<(String, String)>,
 }
 
 fn main() {
@@ -33,8 +53,37 @@ fn main() {
             eprintln!("{}", e);
             eprintln!(
                 "Usage:\n\
-                tt -f program-file [ -- ] file ...\n\
-                tt [ -- ] program-source file ...\n\
+                tt [ options ] -f program-file [ -- ] file ...\n\
+                tt [ options ] [ -- ] program-source file ...\n\
+                \n\
+                -f program-file and -e program-source may be repeated, and the pieces are\n\
+                joined in order into a single program.\n\
+                \n\
+                Operands of the form name=value between files assign to variables before the\n\
+                following file is read.\n\
+                \n\
+                Options:\n\
+                -f file           read (part of) the program from a file\n\
+                -e source         add a piece of program source\n\
+                -v name=value     define a variable, which is a number if the value is one\n\
+                --json            print values as JSON Lines\n\
+                --decimal         use exact decimals instead of floats for fractional numbers\n\
+                --widths columns  split fields at fixed columns, e.g. 5,10,*\n\
+                --trim mode       trim fixed width fields: none, both (default), left, right\n\
+                --patterns file   load grok pattern definitions for parse() from a file\n\
+                --sort-by key     sort the output lines by a key: [-]field[:kind], where\n\
+                \x20                 field is 0, 1, ... or _ for the whole line, and kind is\n\
+                \x20                 string (default), numeric or natural; - sorts descending\n\
+                --sort-memory n   memory to use for sorting before spilling to temporary\n\
+                \x20                 files, e.g. 512M (default 64M)\n\
+                --rs separator    split the input into items at a string or /regex/, or by\n\
+                \x20                 line (default), paragraph, nul or whole for no splitting\n\
+                --split-by expr   write the output of each item to the file for the value of\n\
+                \x20                 an expression, e.g. _[0], instead of stdout\n\
+                --out path        the files for --split-by, with {{key}} for the value, e.g.\n\
+                \x20                 logs/{{key}}.log\n\
+                --max-open n      the number of files --split-by keeps open (default 256)\n\
+                --allow-exec      allow the program to run shell commands, e.g. with system()\n\
                 "
             );
             std::process::exit(1);
@@ -50,67 +99,242 @@ fn main() {
         })
         .init();
 
-    let code = match program_source::code(config.program) {
-        Result::Ok(code) => code,
+    let sources = match program_source::load(config.programs) {
+        Result::Ok(sources) => sources,
         Result::Err(e) => {
             log::error!("{}", e);
             std::process::exit(1);
         }
     };
-    let p = program::compile(code).unwrap_or_else(|e| {
+    let field_splitter = match config.field_widths {
+        None => program::FieldSplitter::Whitespace,
+        Some(spec) => match fixed_width::Columns::parse(&spec, config.trim) {
+            Ok(columns) => program::FieldSplitter::FixedWidth(columns),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let mut patterns = grok::Patterns::default();
+    for file_name in &config.pattern_files {
+        let loaded = fs::read_to_string(file_name)
+            .map_err(|e| e.to_string())
+            .and_then(|definitions| patterns.load(&definitions));
+        if let Err(e) = loaded {
+            log::error!("{}: {}", file_name, e);
+            std::process::exit(1);
+        }
+    }
+    let options = program::Options {
+        output_mode: config.output_mode,
+        field_splitter,
+        number_mode: config.number_mode,
+        patterns,
+        sort_memory: config.sort_memory,
+        split_by: config.split_by,
+        allow_exec: config.allow_exec,
+        variables: config.variables,
+        assigned_variables: config
+            .operands
+            .iter()
+            .filter_map(|operand| match operand {
+                Operand::Assignment(name, value) => Some((name.clone(), value.clone())),
+                Operand::File(_) => None,
+            })
+            .collect(),
+    };
+    let mut p = program::compile_sources(&sources, &options).unwrap_or_else(|e| {
         log::error!("{}", e);
         std::process::exit(1);
     });
-    let mut items = create_input_stream(config.input_files).unwrap();
-    items
-        .try_for_each(|input| p.run(&input).map(|out| println!("{}", out)))
-        .unwrap_or_else(|err| {
-            log::error!("{}", err);
+    // The separator passed on the command line overrides the program's
+    let separator = config
+        .separator
+        .or_else(|| p.separator().cloned())
+        .unwrap_or_default();
+    let split = config
+        .out
+        .map(|template| split::SplitFiles::new(&template, config.max_open))
+        .transpose()
+        .unwrap_or_else(|e| {
+            log::error!("{}", e);
             std::process::exit(1);
         });
+    let output = Output {
+        sorter: config.sort_by.map(|key| {
+            let budget = config.sort_memory.unwrap_or(sort::DEFAULT_MEMORY_BUDGET);
+            (key, sort::Sorter::new(key.descending, budget))
+        }),
+        split,
+    };
+    let input = Input {
+        operands: config.operands.into_iter(),
+        separator,
+    };
+    let code = run(&mut p, input, output).unwrap_or_else(|err| {
+        log::error!("{}", err);
+        std::process::exit(1);
+    });
+    std::process::exit(code);
 }
 
-struct Items {
-    reader: Box<dyn BufRead>,
+/// Runs the program on its input, and returns the exit code
+fn run(p: &mut program::Program, input: Input, output: Output) -> Result<i32, String> {
+    let result = run_items(p, input, output);
+    // The files and commands the program has printed to or read from are
+    // closed after errors as well, so that what was written reaches them
+    let closed = p.close().map_err(|e| e.to_string());
+    let code = result?;
+    closed?;
+    Ok(code)
 }
 
-impl Iterator for Items {
-    // TODO Using an iterator as abstraction for items means that we cannot
-    // handle errors that occur during reading the input stream, as
-    // Iterator::next returns an Option. I'm too lazy to change that now, but
-    // I should fix it in future
-    type Item = String;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let delimiter = '\n' as u8;
-        let mut buf = Vec::new();
-        match self.reader.read_until(delimiter, &mut buf) {
-            Err(_) => None,
-            Ok(0) => None,
-            Ok(_) => Some(String::from_utf8(buf).unwrap()),
+fn run_items(
+    p: &mut program::Program,
+    mut input: Input,
+    mut output: Output,
+) -> Result<i32, String> {
+    // The first file is opened before the begin blocks are run, so that they
+    // can read from it with getline
+    if !input.open_next(p)? {
+        p.set_input(
+            Box::new(io::BufReader::new(io::stdin())),
+            input.separator.clone(),
+        );
+    }
+    output.write(&p.begin().map_err(|e| e.to_string())?)?;
+    loop {
+        // Stops reading as soon as the program has had enough, e.g. after
+        // `exit`
+        while let Some(out) = p.step() {
+            let out = out.map_err(|e| e.to_string())?;
+            match p.split_key() {
+                Some(key) => output.write_split(key, &out)?,
+                None => output.write(&out)?,
+            }
         }
+        if p.status() != program::Status::Running || !input.open_next(p)? {
+            break;
+        }
+    }
+    if let Some(records) = p.sorted().map_err(|e| e.to_string())? {
+        for record in records {
+            let record = record.map_err(|e| format!("Cannot sort: {}", e))?;
+            output.write(&format!("{}\n", record))?;
+        }
+    }
+    output.write(&p.end().map_err(|e| e.to_string())?)?;
+    output.finish()?;
+    // Commands the program has printed to may write to stdout as well
+    io::stdout().flush().map_err(|e| e.to_string())?;
+    match p.status() {
+        program::Status::Exit(code) => Ok(code),
+        program::Status::Running | program::Status::Done => Ok(0),
     }
 }
 
-fn create_input_stream(inf: InputFile) -> Result<Items, io::Error> {
-    let reader: Box<dyn BufRead> = match inf {
-        InputFile::InStream => Box::new(io::BufReader::new(io::stdin())),
-        InputFile::Files(file_names) => {
-            Box::new(io::BufReader::new(fs::File::open(&file_names[0])?))
+/// Writes the output of the program to stdout, sorting the lines first if
+/// --sort-by is given, or the output of items to files with --split-by
+struct Output {
+    sorter: Option<(sort::LineKey, sort::Sorter)>,
+    split: Option<split::SplitFiles>,
+}
+
+impl Output {
+    fn write(&mut self, out: &str) -> Result<(), String> {
+        match &mut self.sorter {
+            None => print!("{}", out),
+            Some((key, sorter)) => {
+                for line in out.lines() {
+                    sorter
+                        .push(key.key(line), line.to_string())
+                        .map_err(|e| format!("Cannot sort: {}", e))?;
+                }
+            }
         }
-    };
+        Ok(())
+    }
+
+    fn write_split(&mut self, key: &str, out: &str) -> Result<(), String> {
+        match &mut self.split {
+            None => self.write(out),
+            Some(split) => split
+                .write(key, out)
+                .map_err(|e| format!("Cannot write to {}: {}", split.path(key), e)),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        if let Some(split) = self.split {
+            split.finish().map_err(|e| e.to_string())?;
+        }
+        if let Some((_, sorter)) = self.sorter {
+            let lines = sorter.finish().map_err(|e| format!("Cannot sort: {}", e))?;
+            for line in lines {
+                println!("{}", line.map_err(|e| format!("Cannot sort: {}", e))?);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The files the program reads one after another
+struct Input {
+    operands: std::vec::IntoIter<Operand>,
+    separator: records::Separator,
+}
 
-    Ok(Items { reader })
+impl Input {
+    /// Makes the next file the input of the program, after assigning the
+    /// variables before it. Returns false if there is no file left.
+    fn open_next(&mut self, p: &mut program::Program) -> Result<bool, String> {
+        for operand in self.operands.by_ref() {
+            match operand {
+                Operand::Assignment(name, value) => {
+                    p.assign(&name, &value).map_err(|e| e.to_string())?
+                }
+                Operand::File(path) => {
+                    let file = fs::File::open(&path).map_err(|e| format!("{}: {}", path, e))?;
+                    p.set_input(Box::new(io::BufReader::new(file)), self.separator.clone());
+                    return Ok(true);
+                }
+            }
+        }
+        Ok(false)
+    }
+}
+
+/// Splits `name=value` if name is a valid variable name
+fn parse_assignment(arg: &str) -> Option<(String, String)> {
+    let (name, value) = arg.split_once('=')?;
+    let mut chars = name.chars();
+    let valid = chars.next().is_some_and(|c| c.is_alphabetic() || c == '_')
+        && chars.all(|c| c.is_alphanumeric() || c == '_');
+    valid.then(|| (name.to_string(), value.to_string()))
 }
 
 fn parse_args(args: impl Iterator<Item = String>) -> Result<Config, String> {
     // TODO When adding more flags, consider using clap
-    // TODO add -v, -vv, -vvv for verbosity
+    // TODO add flags for verbosity; -v is taken by variables, like in awk
 
     let mut config = Config {
-        input_files: InputFile::InStream,
-        program: ProgramSource::Literal(String::new()),
+        operands: vec![],
+        programs: vec![],
         verbosity: Verbosity::Spam,
+        output_mode: program::OutputMode::Text,
+        number_mode: program::NumberMode::Float,
+        field_widths: None,
+        trim: fixed_width::Trim::default(),
+        pattern_files: vec![],
+        sort_by: None,
+        sort_memory: None,
+        separator: None,
+        split_by: None,
+        out: None,
+        max_open: split::DEFAULT_MAX_OPEN,
+        allow_exec: false,
+        variables: vec![],
     };
 
     // Assuming the first argument is the program name; this seems to be just
@@ -128,7 +352,89 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Config, String> {
                 let arg = arg.to_owned();
                 args.next();
                 let file_name = args.next().ok_or(format!("{} takes a parameter", arg))?;
-                config.program = ProgramSource::File(file_name);
+                config.programs.push(ProgramSource::File(file_name));
+            }
+            "-e" | "--source" => {
+                let arg = arg.to_owned();
+                args.next();
+                let source = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.programs.push(ProgramSource::Literal(source));
+            }
+            "--json" => {
+                args.next();
+                config.output_mode = program::OutputMode::Json;
+            }
+            "-v" => {
+                let arg = arg.to_owned();
+                args.next();
+                let assignment = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                let variable = parse_assignment(&assignment)
+                    .ok_or(format!("Invalid variable definition '{}'", assignment))?;
+                config.variables.push(variable);
+            }
+            "--allow-exec" => {
+                args.next();
+                config.allow_exec = true;
+            }
+            "--decimal" => {
+                args.next();
+                config.number_mode = program::NumberMode::Decimal;
+            }
+            "--widths" => {
+                let arg = arg.to_owned();
+                args.next();
+                let spec = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.field_widths = Some(spec);
+            }
+            "--trim" => {
+                let arg = arg.to_owned();
+                args.next();
+                let trim = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.trim = fixed_width::Trim::try_from(trim.as_str())?;
+            }
+            "--patterns" => {
+                let arg = arg.to_owned();
+                args.next();
+                let file_name = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.pattern_files.push(file_name);
+            }
+            "--sort-by" => {
+                let arg = arg.to_owned();
+                args.next();
+                let key = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.sort_by = Some(sort::LineKey::try_from(key.as_str())?);
+            }
+            "--sort-memory" => {
+                let arg = arg.to_owned();
+                args.next();
+                let size = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.sort_memory = Some(sort::parse_size(&size)?);
+            }
+            "--rs" => {
+                let arg = arg.to_owned();
+                args.next();
+                let spec = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.separator = Some(records::Separator::try_from(spec.as_str())?);
+            }
+            "--split-by" => {
+                let arg = arg.to_owned();
+                args.next();
+                let expr = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.split_by = Some(expr);
+            }
+            "--out" => {
+                let arg = arg.to_owned();
+                args.next();
+                let template = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.out = Some(template);
+            }
+            "--max-open" => {
+                let arg = arg.to_owned();
+                args.next();
+                let n = args.next().ok_or(format!("{} takes a parameter", arg))?;
+                config.max_open = n
+                    .parse()
+                    .map_err(|_| format!("Invalid number of files '{}'", n))?;
             }
             "--" => {
                 args.next();
@@ -138,24 +444,167 @@ fn parse_args(args: impl Iterator<Item = String>) -> Result<Config, String> {
         };
     }
 
-    // If -f hasn't been passed, the program source is the next argument
-    config.program = match config.program {
-        f @ ProgramSource::File(_) => f,
-        ProgramSource::Literal(_) => match args.next() {
-            None => Result::Err("Program source hasn't been specified")?,
-            Some(source) => ProgramSource::Literal(source),
-        },
-    };
+    if config.split_by.is_some() != config.out.is_some() {
+        return Err("--split-by and --out must be used together".to_string());
+    }
+    // The output of --split-by doesn't go to stdout, which --sort-by sorts
+    if config.split_by.is_some() && config.sort_by.is_some() {
+        return Err("--sort-by cannot be used with --split-by".to_string());
+    }
 
-    // everything after is files
-    match args.peek() {
-        None => {
-            config.input_files = InputFile::InStream;
-        }
-        Some(_) => {
-            config.input_files = InputFile::Files(args.collect());
+    // If neither -f nor -e has been passed, the program source is the next
+    // argument
+    if config.programs.is_empty() {
+        match args.next() {
+            None => Result::Err("Program source hasn't been specified")?,
+            Some(source) => config.programs.push(ProgramSource::Literal(source)),
         }
     }
 
+    // everything after is files and assignments
+    config.operands = args
+        .map(|arg| match parse_assignment(&arg) {
+            Some((name, value)) => Operand::Assignment(name, value),
+            None => Operand::File(arg),
+        })
+        .collect();
+
     Result::Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        let args = ["tt"].iter().chain(args).map(|arg| arg.to_string());
+        parse_args(args.collect::<Vec<_>>().into_iter())
+    }
+
+    #[test]
+    fn test_program_sources() {
+        let config = parse(&["-f", "a.tt", "-e", "print(_)", "--file", "b.tt", "x"]).unwrap();
+        assert_eq!(
+            config.programs,
+            [
+                ProgramSource::File("a.tt".to_string()),
+                ProgramSource::Literal("print(_)".to_string()),
+                ProgramSource::File("b.tt".to_string()),
+            ]
+        );
+        assert_eq!(config.operands, [Operand::File("x".to_string())]);
+        // Without -f or -e, the first operand is the program
+        let config = parse(&["--", "print(_)", "x"]).unwrap();
+        assert_eq!(
+            config.programs,
+            [ProgramSource::Literal("print(_)".to_string())]
+        );
+        assert_eq!(config.operands, [Operand::File("x".to_string())]);
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-e"]).is_err());
+    }
+
+    #[test]
+    fn test_run_closes_after_errors() {
+        let dir = std::env::temp_dir().join(format!("tt-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input_path = dir.join("input");
+        fs::write(&input_path, "a\nb\n").unwrap();
+        let source_code = format!(
+            r#"print_to("{}/out", _); if _ == "b" {{ print({{}}["x"]) }}"#,
+            dir.display()
+        );
+        let mut p = program::compile(source_code, &program::Options::default()).unwrap();
+        let input = Input {
+            operands: vec![Operand::File(input_path.display().to_string())].into_iter(),
+            separator: records::Separator::Line,
+        };
+        let output = Output {
+            sorter: None,
+            split: None,
+        };
+        let result = run(&mut p, input, output);
+        let printed = fs::read_to_string(dir.join("out")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_err());
+        assert_eq!(printed, "a\nb\n");
+    }
+
+    #[test]
+    fn test_variables() {
+        let config = parse(&["-v", "n=1", "-v", "s=a=b", "print(n + 1); print(s)"]).unwrap();
+        assert_eq!(
+            config.variables,
+            [
+                ("n".to_string(), "1".to_string()),
+                ("s".to_string(), "a=b".to_string())
+            ]
+        );
+        // Values that are numbers define numeric variables
+        let options = program::Options {
+            variables: config.variables,
+            ..Default::default()
+        };
+        let mut p = program::compile("print(n + 1); print(s)".to_string(), &options).unwrap();
+        assert_eq!(p.run("").unwrap(), "2\na=b\n");
+        assert!(parse(&["-v", "1n=1", "print(_)"]).is_err());
+        assert!(parse(&["-v", "n", "print(_)"]).is_err());
+    }
+
+    #[test]
+    fn test_operands() {
+        let config = parse(&["print(_)", "a", "level=2", "b", "1x=y", "c="]).unwrap();
+        assert_eq!(
+            config.operands,
+            [
+                Operand::File("a".to_string()),
+                Operand::Assignment("level".to_string(), "2".to_string()),
+                Operand::File("b".to_string()),
+                // Not a variable name, so a file
+                Operand::File("1x=y".to_string()),
+                Operand::Assignment("c".to_string(), "".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_record_separator() {
+        let config = parse(&["--rs", "paragraph", "print(_)"]).unwrap();
+        assert!(matches!(
+            config.separator,
+            Some(records::Separator::Paragraph)
+        ));
+        let config = parse(&["print(_)"]).unwrap();
+        assert!(config.separator.is_none());
+        assert!(parse(&["--rs", "", "print(_)"]).is_err());
+    }
+
+    #[test]
+    fn test_split_by() {
+        let args = ["--split-by", "_[0]", "--out", "{key}.log", "print(_)"];
+        let config = parse(&args).unwrap();
+        assert_eq!(config.split_by.as_deref(), Some("_[0]"));
+        assert_eq!(config.out.as_deref(), Some("{key}.log"));
+        assert_eq!(config.max_open, split::DEFAULT_MAX_OPEN);
+        let config = parse(&["--max-open", "4", "print(_)"]).unwrap();
+        assert_eq!(config.max_open, 4);
+        assert!(parse(&["--max-open", "x", "print(_)"]).is_err());
+        // Both or neither
+        assert!(parse(&["--split-by", "_[0]", "print(_)"]).is_err());
+        assert!(parse(&["--out", "{key}.log", "print(_)"]).is_err());
+        let args = [
+            "--sort-by",
+            "0",
+            "--split-by",
+            "_[0]",
+            "--out",
+            "{key}",
+            "print(_)",
+        ];
+        let result = parse(&args);
+        assert_eq!(
+            result.err().as_deref(),
+            Some("--sort-by cannot be used with --split-by")
+        );
+    }
+}
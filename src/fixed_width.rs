@@ -0,0 +1,146 @@
+use unicode_width::UnicodeWidthChar;
+
+// Column positions are measured in display columns rather than chars, so that
+// reports containing e.g. CJK characters, which occupy two columns on a
+// terminal, line up the same way as they do on screen.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Columns {
+    /// Start (inclusive) and end (exclusive) display column of each field,
+    /// counting from 0. A field without end extends to the end of the line.
+    ranges: Vec<(usize, Option<usize>)>,
+    trim: Trim,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Trim {
+    None,
+    #[default]
+    Both,
+    Left,
+    Right,
+}
+
+impl Trim {
+    pub fn apply(&self, s: &str) -> String {
+        match self {
+            Trim::None => s.to_string(),
+            Trim::Both => s.trim().to_string(),
+            Trim::Left => s.trim_start().to_string(),
+            Trim::Right => s.trim_end().to_string(),
+        }
+    }
+}
+
+impl TryFrom<&str> for Trim {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "none" => Ok(Trim::None),
+            "both" => Ok(Trim::Both),
+            "left" => Ok(Trim::Left),
+            "right" => Ok(Trim::Right),
+            other => Err(format!(
+                "Invalid trim option '{}', expected one of none, both, left, right",
+                other
+            )),
+        }
+    }
+}
+
+impl Columns {
+    /// Parses a comma separated list of columns. Each column is either a
+    /// width, e.g. `5`, starting where the previous column ended, a range of
+    /// 1-based display columns, e.g. `6-10`, or `*` for the rest of the line.
+    /// A range without end, e.g. `11-`, extends to the end of the line.
+    pub fn parse(spec: &str, trim: Trim) -> Result<Columns, String> {
+        let mut ranges = Vec::new();
+        let mut position = 0;
+        for column in spec.split(',').map(|c| c.trim()) {
+            let range = match column.split_once('-') {
+                _ if column == "*" => (position, None),
+                None => {
+                    let width = parse_column_number(column, spec)?;
+                    (position, Some(position + width))
+                }
+                Some((start, "")) => (parse_column_number(start, spec)? - 1, None),
+                Some((start, end)) => {
+                    let start = parse_column_number(start, spec)?;
+                    let end = parse_column_number(end, spec)?;
+                    if end < start {
+                        return Err(format!("Invalid column range {} in '{}'", column, spec));
+                    }
+                    (start - 1, Some(end))
+                }
+            };
+            if let (_, Some(end)) = range {
+                position = end;
+            }
+            ranges.push(range);
+        }
+        Ok(Columns { ranges, trim })
+    }
+
+    pub fn split(&self, line: &str) -> Vec<String> {
+        let mut chars = Vec::new();
+        let mut column = 0;
+        for ch in line.chars() {
+            chars.push((column, ch));
+            column += ch.width().unwrap_or(0);
+        }
+
+        self.ranges
+            .iter()
+            .map(|(start, end)| {
+                let field: String = chars
+                    .iter()
+                    .filter(|(c, _)| *c >= *start && end.map(|e| *c < e).unwrap_or(true))
+                    .map(|(_, ch)| ch)
+                    .collect();
+                self.trim.apply(&field)
+            })
+            .collect()
+    }
+}
+
+fn parse_column_number(s: &str, spec: &str) -> Result<usize, String> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => Err(format!("Invalid column '{}' in '{}'", s, spec)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_widths_keep_empty_columns() {
+        let columns = Columns::parse("5,6,*", Trim::Both).unwrap();
+        let actual = columns.split("root       1 /sbin/init");
+        assert_eq!(actual, vec!["root", "", "1 /sbin/init"]);
+    }
+
+    #[test]
+    fn test_ranges() {
+        let columns = Columns::parse("1-3,7-", Trim::None).unwrap();
+        let actual = columns.split("abcdefghij");
+        assert_eq!(actual, vec!["abc", "ghij"]);
+    }
+
+    #[test]
+    fn test_display_width() {
+        // '日' and '本' occupy two columns each
+        let columns = Columns::parse("4,3", Trim::Right).unwrap();
+        let actual = columns.split("日本abc");
+        assert_eq!(actual, vec!["日本", "abc"]);
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        assert!(Columns::parse("5,x", Trim::Both).is_err());
+        assert!(Columns::parse("5-3", Trim::Both).is_err());
+        assert!(Columns::parse("0", Trim::Both).is_err());
+    }
+}
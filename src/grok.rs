@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+// Grok patterns, as known from Logstash, are regexes built from named
+// sub-patterns, e.g. `%{IP:client} %{WORD:method}`. `%{NAME:field}` captures
+// the text matched by the sub-pattern NAME as `field`, `%{NAME}` matches it
+// without capturing it. Text outside of `%{...}` is matched literally, so that
+// e.g. `[` in a pattern doesn't need to be escaped. The definitions of
+// sub-patterns are regexes themselves and may refer to other sub-patterns.
+
+const BUILTIN_PATTERNS: &str = r#"
+USERNAME [a-zA-Z0-9._-]+
+USER %{USERNAME}
+INT [+-]?[0-9]+
+POSINT \b[1-9][0-9]*\b
+NONNEGINT \b[0-9]+\b
+NUMBER [+-]?(?:[0-9]+(?:\.[0-9]*)?|\.[0-9]+)
+BASE16NUM [+-]?(?:0x)?[0-9A-Fa-f]+
+WORD \b\w+\b
+NOTSPACE \S+
+SPACE \s*
+DATA .*?
+GREEDYDATA .*
+QUOTEDSTRING "(?:[^"\\]|\\.)*"
+QS %{QUOTEDSTRING}
+UUID [A-Fa-f0-9]{8}-(?:[A-Fa-f0-9]{4}-){3}[A-Fa-f0-9]{12}
+MAC (?:[A-Fa-f0-9]{2}:){5}[A-Fa-f0-9]{2}
+IPV4 (?:(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])\.){3}(?:25[0-5]|2[0-4][0-9]|1[0-9]{2}|[1-9]?[0-9])
+IPV6 (?:[0-9A-Fa-f]{0,4}:){2,7}[0-9A-Fa-f]{0,4}
+IP (?:%{IPV4}|%{IPV6})
+HOSTNAME \b[0-9A-Za-z][0-9A-Za-z-]{0,62}(?:\.[0-9A-Za-z][0-9A-Za-z-]{0,62})*\.?\b
+IPORHOST (?:%{IP}|%{HOSTNAME})
+HOSTPORT %{IPORHOST}:%{POSINT}
+PATH (?:/[^\s?#]*)+
+URIPARAM \?\S*
+URIPATHPARAM %{PATH}(?:%{URIPARAM})?
+EMAILADDRESS [a-zA-Z0-9._%+-]+@[a-zA-Z0-9.-]+
+LOGLEVEL (?i:trace|debug|info|notice|warn(?:ing)?|err(?:or)?|crit(?:ical)?|fatal|severe|emerg(?:ency)?|alert)
+MONTH (?:Jan|Feb|Mar|Apr|May|Jun|Jul|Aug|Sep|Oct|Nov|Dec)[a-z]*
+MONTHNUM (?:0?[1-9]|1[0-2])
+MONTHDAY (?:0?[1-9]|[12][0-9]|3[01])
+YEAR [0-9]{4}
+HOUR (?:[01]?[0-9]|2[0-3])
+MINUTE [0-5][0-9]
+SECOND (?:[0-5]?[0-9]|60)(?:[.,][0-9]+)?
+TIME %{HOUR}:%{MINUTE}:%{SECOND}
+ISO8601_TIMEZONE (?:Z|[+-]%{HOUR}:?%{MINUTE})
+TIMESTAMP_ISO8601 %{YEAR}-%{MONTHNUM}-%{MONTHDAY}[T ]%{HOUR}:?%{MINUTE}(?::?%{SECOND})?%{ISO8601_TIMEZONE}?
+HTTPDATE %{MONTHDAY}/%{MONTH}/%{YEAR}:%{TIME} %{INT}
+SYSLOGTIMESTAMP %{MONTH} +%{MONTHDAY} %{TIME}
+TIMESTAMP (?:%{TIMESTAMP_ISO8601}|%{HTTPDATE}|%{SYSLOGTIMESTAMP})
+"#;
+
+/// Sub-patterns referring to each other deeper than this are assumed to be
+/// recursive
+const MAX_DEPTH: usize = 32;
+
+/// The sub-patterns that can be used in grok patterns
+#[derive(Debug, Clone)]
+pub struct Patterns {
+    definitions: HashMap<String, String>,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        let mut patterns = Patterns {
+            definitions: HashMap::new(),
+        };
+        patterns
+            .load(BUILTIN_PATTERNS)
+            .expect("the built-in patterns are valid");
+        patterns
+    }
+}
+
+impl Patterns {
+    /// Loads definitions of sub-patterns, one per line, consisting of the name
+    /// and the regex separated by whitespace, e.g. `STATUS [1-5][0-9]{2}`.
+    /// Empty lines and lines starting with `#` are ignored. Definitions
+    /// replace existing definitions with the same name.
+    pub fn load(&mut self, definitions: &str) -> Result<(), String> {
+        for (i, line) in definitions.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, definition) = line.split_once(char::is_whitespace).ok_or(format!(
+                "Line {}: Pattern {} has no definition",
+                i + 1,
+                line
+            ))?;
+            if !is_name(name) {
+                return Err(format!("Line {}: Invalid pattern name '{}'", i + 1, name));
+            }
+            self.definitions
+                .insert(name.to_string(), definition.trim().to_string());
+        }
+        Ok(())
+    }
+
+    /// Translates a grok pattern to a regex with a named capture group per
+    /// field
+    pub fn compile(&self, pattern: &str) -> Result<Regex, String> {
+        let expanded = self.expand(pattern, true, 0)?;
+        Regex::new(&expanded).map_err(|err| format!("Invalid pattern '{}': {}", pattern, err))
+    }
+
+    fn expand(&self, pattern: &str, literal: bool, depth: usize) -> Result<String, String> {
+        if depth > MAX_DEPTH {
+            return Err(format!("Pattern '{}' is recursive", pattern));
+        }
+        let mut expanded = String::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find("%{") {
+            let text = &rest[..start];
+            expanded.push_str(&if literal {
+                regex::escape(text)
+            } else {
+                text.to_string()
+            });
+            let end = rest[start..]
+                .find('}')
+                .ok_or(format!("Unterminated '%{{' in pattern '{}'", pattern))?;
+            let reference = &rest[start + 2..start + end];
+            let (name, field) = match reference.split_once(':') {
+                Some((name, field)) => (name, Some(field)),
+                None => (reference, None),
+            };
+            let definition = self
+                .definitions
+                .get(name)
+                .ok_or(format!("Unknown pattern '{}'", name))?;
+            let sub_pattern = self.expand(definition, false, depth + 1)?;
+            match field {
+                Some(field) if is_name(field) => {
+                    expanded.push_str(&format!("(?P<{}>{})", field, sub_pattern))
+                }
+                Some(field) => return Err(format!("Invalid field name '{}'", field)),
+                None => expanded.push_str(&format!("(?:{})", sub_pattern)),
+            }
+            rest = &rest[start + end + 1..];
+        }
+        expanded.push_str(&if literal {
+            regex::escape(rest)
+        } else {
+            rest.to_string()
+        });
+        Ok(expanded)
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// The fields captured by a regex, in the order of the capture groups. Fields
+/// that didn't participate in the match are empty. Returns None if the regex
+/// doesn't match.
+pub fn captures(regex: &Regex, s: &str) -> Option<Vec<(String, String)>> {
+    let captures = regex.captures(s)?;
+    let fields = regex
+        .capture_names()
+        .flatten()
+        .map(|name| {
+            let value = captures.name(name).map(|m| m.as_str()).unwrap_or_default();
+            (name.to_string(), value.to_string())
+        })
+        .collect();
+    Some(fields)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(pattern: &str, s: &str) -> Option<Vec<(String, String)>> {
+        captures(&Patterns::default().compile(pattern).unwrap(), s)
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        Some(
+            pairs
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_nginx() {
+        let line =
+            r#"127.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /apache_pb.gif HTTP/1.0" 200 2326"#;
+        let pattern = r#"%{IP:client} - - [%{TIMESTAMP:ts}] "%{WORD:method} %{PATH:path}"#;
+        let expected = fields(&[
+            ("client", "127.0.0.1"),
+            ("ts", "10/Oct/2000:13:55:36 -0700"),
+            ("method", "GET"),
+            ("path", "/apache_pb.gif"),
+        ]);
+        assert_eq!(parse(pattern, line), expected);
+    }
+
+    #[test]
+    fn test_syslog() {
+        let line = "Mar  7 04:02:16 host1 sshd[1234]: Accepted publickey";
+        let pattern = "%{SYSLOGTIMESTAMP:ts} %{HOSTNAME:host} %{WORD:program}[%{POSINT:pid}]: \
+                       %{GREEDYDATA:message}";
+        let expected = fields(&[
+            ("ts", "Mar  7 04:02:16"),
+            ("host", "host1"),
+            ("program", "sshd"),
+            ("pid", "1234"),
+            ("message", "Accepted publickey"),
+        ]);
+        assert_eq!(parse(pattern, line), expected);
+    }
+
+    #[test]
+    fn test_no_match() {
+        assert_eq!(parse("%{INT:n}", "abc"), None);
+    }
+
+    #[test]
+    fn test_builtin_patterns_compile() {
+        let patterns = Patterns::default();
+        for name in patterns.definitions.keys() {
+            assert!(
+                patterns.compile(&format!("%{{{}}}", name)).is_ok(),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_load() {
+        let mut patterns = Patterns::default();
+        let definitions =
+            "# HTTP status\nSTATUS [1-5][0-9]{2}\n\nREQUEST %{WORD} %{STATUS:status}\n";
+        patterns.load(definitions).unwrap();
+        let regex = patterns.compile("%{REQUEST}").unwrap();
+        assert_eq!(captures(&regex, "GET 404"), fields(&[("status", "404")]));
+        assert!(patterns.load("NODEFINITION").is_err());
+        assert!(patterns.load("BAD-NAME x").is_err());
+    }
+
+    #[test]
+    fn test_invalid_patterns() {
+        let mut patterns = Patterns::default();
+        assert!(patterns.compile("%{UNKNOWN}").is_err());
+        assert!(patterns.compile("%{INT:a-b}").is_err());
+        assert!(patterns.compile("%{INT").is_err());
+        assert!(patterns.compile("%{INT:n} %{INT:n}").is_err());
+        patterns.load("LOOP a%{LOOP}").unwrap();
+        assert!(patterns.compile("%{LOOP}").is_err());
+    }
+}
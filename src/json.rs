@@ -0,0 +1,61 @@
+// Only serialization is needed for now, since JSON is an output format of tt.
+
+pub fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for ch in s.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            '\u{8}' => quoted.push_str("\\b"),
+            '\u{c}' => quoted.push_str("\\f"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+pub fn array(elements: impl Iterator<Item = String>) -> String {
+    let elements: Vec<String> = elements.collect();
+    format!("[{}]", elements.join(","))
+}
+
+pub fn object<'a>(members: impl Iterator<Item = (&'a str, String)>) -> String {
+    let members: Vec<String> = members
+        .map(|(k, v)| format!("{}:{}", quote(k), v))
+        .collect();
+    format!("{{{}}}", members.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote_escapes() {
+        let actual = quote("say \"hi\"\\\n\u{1}");
+        assert_eq!(actual, "\"say \\\"hi\\\"\\\\\\n\\u0001\"");
+    }
+
+    #[test]
+    fn test_quote_keeps_unicode() {
+        assert_eq!(quote("grüße"), "\"grüße\"");
+    }
+
+    #[test]
+    fn test_array() {
+        let actual = array(vec![quote("a"), quote("b")].into_iter());
+        assert_eq!(actual, "[\"a\",\"b\"]");
+    }
+
+    #[test]
+    fn test_object() {
+        let actual = object(vec![("a", quote("b")), ("c", array(std::iter::empty()))].into_iter());
+        assert_eq!(actual, "{\"a\":\"b\",\"c\":[]}");
+    }
+}
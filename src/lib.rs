@@ -1,5 +1,12 @@
+pub mod fixed_width;
+pub mod grok;
 pub mod program;
 pub mod program_source;
+pub mod records;
+pub mod sort;
+pub mod split;
 
 mod ast;
+mod json;
+mod logfmt;
 mod tokens;
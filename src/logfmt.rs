@@ -0,0 +1,138 @@
+// logfmt is a loosely specified format, see https://brandur.org/logfmt. A line
+// consists of key=value pairs separated by whitespace. Values containing
+// whitespace, '=' or '"' are double quoted. A key without a value is treated
+// like a key with an empty value.
+
+enum State {
+    Init,
+    InKey,
+    InValue,
+    InQuotedValue,
+    InQuotedValueEscape,
+}
+
+pub fn parse(line: &str) -> Result<Vec<(String, String)>, String> {
+    let mut pairs = Vec::new();
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut state = State::Init;
+    for ch in line.chars() {
+        match (&state, ch) {
+            (State::Init, _) if ch.is_whitespace() => continue,
+            (State::Init, '=' | '"') => {
+                return Err(format!("Invalid logfmt: Expected key, got '{}'", ch))
+            }
+            (State::Init, _) => {
+                key.push(ch);
+                state = State::InKey;
+            }
+            (State::InKey, _) if ch.is_whitespace() => {
+                pairs.push((std::mem::take(&mut key), String::new()));
+                state = State::Init;
+            }
+            (State::InKey, '=') => state = State::InValue,
+            (State::InKey, '"') => return Err(format!("Invalid logfmt: '\"' in key {}", key)),
+            (State::InKey, _) => key.push(ch),
+            (State::InValue, '"') if value.is_empty() => state = State::InQuotedValue,
+            (State::InValue, _) if ch.is_whitespace() => {
+                pairs.push((std::mem::take(&mut key), std::mem::take(&mut value)));
+                state = State::Init;
+            }
+            (State::InValue, _) => value.push(ch),
+            (State::InQuotedValue, '"') => {
+                pairs.push((std::mem::take(&mut key), std::mem::take(&mut value)));
+                state = State::Init;
+            }
+            (State::InQuotedValue, '\\') => state = State::InQuotedValueEscape,
+            (State::InQuotedValue, _) => value.push(ch),
+            (State::InQuotedValueEscape, _) => {
+                value.push(match ch {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    other => other,
+                });
+                state = State::InQuotedValue;
+            }
+        }
+    }
+    match state {
+        State::Init => {}
+        State::InKey | State::InValue => pairs.push((key, value)),
+        State::InQuotedValue | State::InQuotedValueEscape => {
+            return Err(format!("Invalid logfmt: Unterminated value of key {}", key))
+        }
+    }
+    Ok(pairs)
+}
+
+pub fn format<'a>(pairs: impl Iterator<Item = (&'a str, String)>) -> String {
+    let formatted: Vec<String> = pairs
+        .map(|(key, value)| format!("{}={}", key, format_value(&value)))
+        .collect();
+    formatted.join(" ")
+}
+
+fn format_value(value: &str) -> String {
+    let needs_quotes = value.is_empty()
+        || value
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control() || c == '=' || c == '"');
+    if !needs_quotes {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for ch in value.chars() {
+        match ch {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair(k: &str, v: &str) -> (String, String) {
+        (k.to_string(), v.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let actual = parse(r#"level=info msg="started \"app\"" dur=12ms debug"#).unwrap();
+        let expected = vec![
+            pair("level", "info"),
+            pair("msg", "started \"app\""),
+            pair("dur", "12ms"),
+            pair("debug", ""),
+        ];
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_empty_quoted_value() {
+        let actual = parse(r#"a="" b=1"#).unwrap();
+        assert_eq!(actual, vec![pair("a", ""), pair("b", "1")]);
+    }
+
+    #[test]
+    fn test_parse_unterminated_quote() {
+        assert!(parse(r#"msg="oops"#).is_err());
+    }
+
+    #[test]
+    fn test_format_roundtrip() {
+        let line = r#"level=info msg="started \"app\"" empty="" dur=12ms"#;
+        let pairs = parse(line).unwrap();
+        let actual = format(pairs.iter().map(|(k, v)| (k.as_str(), v.clone())));
+        assert_eq!(actual, line);
+    }
+}
@@ -1,100 +1,437 @@
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
+use std::io::BufRead;
 
 use crate::ast;
+use crate::fixed_width::{Columns, Trim};
+use crate::grok::Patterns;
+use crate::records::Separator;
+use crate::sort::{self, Order, Sorted, Sorter};
 use crate::tokens;
 
+use aggregate::{Aggregate, Aggregator, GroupOrder, Groups};
+use arithmetic::{arithmetic_type, comparable, Arithmetic, Comparison};
+use builtins::{builtin_functions, TypedFunction};
+use input::Input;
+use ops::{Environment, Op, Source};
+use output::Outputs;
+use value::{Type, Value};
+
+mod aggregate;
+mod arithmetic;
+mod builtins;
+mod input;
+mod ops;
+mod output;
+mod printf;
+mod strings;
+mod time;
+mod value;
+
 // Note: I've not implemented function definitions yet. If and when I do that,
 // I need to implement function calls instead of unwinding the operations,
 // since as of right now recursion will lead to OOM on compilation.
 
-pub fn compile(source_code: String) -> Result<Program, CompileError> {
+pub fn compile(source_code: String, options: &Options) -> Result<Program, CompileError> {
     let tokens = tokens::scan(source_code).map_err(|e| CompileError { msg: e })?;
     let ast = ast::parse(tokens).map_err(|e| CompileError { msg: e })?;
-    Program::from_ast(&ast)
+    Program::from_ast(&ast, &[], options)
 }
 
-pub struct Program {
-    ops: Vec<Op>,
+/// Compiles several pieces of source code, e.g. from `-f` and `-e`, as a single
+/// program, with the pieces in order. Each piece is named, e.g. after its file,
+/// and errors are prefixed with the name and line where they occur, like
+/// `lib.tt:3: `. Statements don't continue from one piece to the next.
+pub fn compile_sources(
+    sources: &[(String, String)],
+    options: &Options,
+) -> Result<Program, CompileError> {
+    let mut tokens = vec![];
+    // The name and line of each token
+    let mut locations: Vec<(&str, usize)> = vec![];
+    for (name, source_code) in sources {
+        let located = tokens::scan_located(source_code).map_err(|(msg, line)| CompileError {
+            msg: format!("{}:{}: {}", name, line, msg),
+        })?;
+        if located.is_empty() {
+            continue;
+        }
+        if !tokens.is_empty() && tokens.last() != Some(&tokens::Token::Semicolon) {
+            tokens.push(tokens::Token::Semicolon);
+            locations.push(*locations.last().expect("there is a token"));
+        }
+        for (token, line) in located {
+            tokens.push(token);
+            locations.push((name, line));
+        }
+    }
+    let locate = |index: usize| match locations.get(index).or(locations.last()) {
+        Some((name, line)) => format!("{}:{}", name, line),
+        None => String::new(),
+    };
+    let (ast, starts) = ast::parse_located(&tokens).map_err(|(msg, index)| CompileError {
+        msg: format!("{}: {}", locate(index), msg),
+    })?;
+    let statement_locations: Vec<String> = starts.into_iter().map(locate).collect();
+    Program::from_ast(&ast, &statement_locations, options)
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub output_mode: OutputMode,
+    pub field_splitter: FieldSplitter,
+    pub number_mode: NumberMode,
+    /// The sub-patterns available to `parse`
+    pub patterns: Patterns,
+    /// The memory `sort_by` may use before spilling to temporary files, or
+    /// None for `sort::DEFAULT_MEMORY_BUDGET`
+    pub sort_memory: Option<usize>,
+    /// An expression that is evaluated for each item, e.g. `_[0]`, whose
+    /// value is available as `Program::split_key`
+    pub split_by: Option<String>,
+    /// Whether the program may run shell commands
+    pub allow_exec: bool,
+    /// Global variables with initial values by name, e.g. from `-v
+    /// name=value`. Values that are numbers are Int, Float or Decimal
+    /// variables, and any other value is a String.
+    pub variables: Vec<(String, String)>,
+    /// Global variables that are assigned later with `Program::assign`, e.g.
+    /// by `name=value` operands between input files. They are declared with
+    /// the types of these values.
+    pub assigned_variables: Vec<(String, String)>,
+}
+
+/// Determines how printed values are written to the output. Each printed value
+/// results in one line of output.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputMode {
+    #[default]
+    Text,
+    /// Every printed value is written as one JSON document, i.e. the output
+    /// is in the JSON Lines format
+    Json,
+}
+
+/// Determines the type of numbers with a fractional part, both of literals
+/// like `0.1` and of numbers parsed by `tonumber`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NumberMode {
+    #[default]
+    Float,
+    /// Exact decimal arithmetic, e.g. for amounts of money
+    Decimal,
 }
 
-struct ProgramState {
-    output: String,
-    stack: Vec<Value>,
+/// Determines how an item is split into the fields accessed by `_[n]`
+#[derive(Debug, Default, Clone)]
+pub enum FieldSplitter {
+    #[default]
+    Whitespace,
+    FixedWidth(Columns),
+}
+
+/// Whether a program wants more items
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    /// A `head` limit has been reached
+    Done,
+    /// `exit` has been called with an exit code
+    Exit(i32),
+}
+
+/// A compiled program. Variables are global and keep their values across
+/// items, so that e.g. counts can be collected in `run` and printed in `end`.
+pub struct Program {
+    begin_ops: Vec<Op>,
+    ops: Vec<Op>,
+    end_ops: Vec<Op>,
+    env: Environment,
+    /// The number of previous items that are kept for `prev` and `window`
+    history_len: usize,
+    /// The number of items that are read ahead for `next`
+    lookahead: usize,
+    /// How the program wants the input to be split into items
+    separator: Option<Separator>,
+    /// The slots and types of `Options::assigned_variables`
+    assignable: HashMap<String, (usize, Type)>,
+    /// Evaluates the expression of `Options::split_by` to a string
+    split_ops: Vec<Op>,
+    split_key: Option<String>,
 }
 
 impl Program {
-    fn from_ast(ast: &ast::AST) -> Result<Program, CompileError> {
-        let functions = builtin_functions();
+    /// Errors in a top-level statement are prefixed with its location, if
+    /// there is one
+    fn from_ast(
+        ast: &ast::Ast,
+        locations: &[String],
+        options: &Options,
+    ) -> Result<Program, CompileError> {
+        let located = |i: usize| {
+            move |err: CompileError| match locations.get(i) {
+                Some(location) => CompileError {
+                    msg: format!("{}: {}", location, err.msg),
+                },
+                None => err,
+            }
+        };
+        let mut scope = Scope {
+            functions: builtin_functions(),
+            variables: RefCell::new(HashMap::new()),
+            number_mode: options.number_mode,
+            patterns: options.patterns.clone(),
+            aggregates: RefCell::new(vec![]),
+            groups: vec![],
+            sort_descending: Cell::new(None),
+            history_len: Cell::new(0),
+            lookahead: Cell::new(0),
+            ranges: 0,
+            heads: Cell::new(0),
+            allow_exec: options.allow_exec,
+            block: Block::Main,
+            separator: RefCell::new(None),
+        };
+        let mut program = Program {
+            begin_ops: vec![],
+            ops: vec![],
+            end_ops: vec![],
+            env: Environment {
+                globals: vec![],
+                output_mode: options.output_mode,
+                field_splitter: options.field_splitter.clone(),
+                number_mode: options.number_mode,
+                patterns: options.patterns.clone(),
+                aggregates: vec![],
+                groups: vec![],
+                sorter: None,
+                history: VecDeque::new(),
+                following: VecDeque::new(),
+                ranges: vec![],
+                heads: vec![],
+                input: Input::default(),
+                outputs: Outputs::default(),
+                status: Status::Running,
+            },
+            history_len: 0,
+            lookahead: 0,
+            separator: None,
+            assignable: HashMap::new(),
+            split_ops: vec![],
+            split_key: None,
+        };
+
+        let mut initial_values = vec![];
+        for (name, value) in &options.variables {
+            let value = variable_value(value, options.number_mode);
+            initial_values.push((scope.declare(name, &value.get_type())?, value));
+        }
+        for (name, value) in &options.assigned_variables {
+            let var_type = variable_value(value, options.number_mode).get_type();
+            let slot = scope.declare(name, &var_type)?;
+            program.assignable.insert(name.clone(), (slot, var_type));
+        }
+        for (i, stmt) in ast.statements.iter().enumerate() {
+            declare_maps(std::slice::from_ref(stmt), &scope).map_err(located(i))?;
+        }
+        for (i, stmt) in ast.statements.iter().enumerate() {
+            match stmt {
+                ast::Statement::Begin { statements } => {
+                    scope.block = Block::Begin;
+                    let ops = compile_statements(statements, &mut scope).map_err(located(i))?;
+                    scope.block = Block::Main;
+                    program.begin_ops.extend(ops);
+                }
+                ast::Statement::End { statements } => {
+                    scope.block = Block::End;
+                    let ops = compile_statements(statements, &mut scope).map_err(located(i))?;
+                    scope.block = Block::Main;
+                    program.end_ops.extend(ops);
+                }
+                other => {
+                    let ops = compile_statement(other, &mut scope).map_err(located(i))?;
+                    program.ops.extend(ops);
+                }
+            }
+        }
+
+        if let Some(split_by) = &options.split_by {
+            program.split_ops = compile_split_by(split_by, &scope)?;
+        }
 
-        ast.statements
+        let mut variables: Vec<Variable> = scope.variables.into_inner().into_values().collect();
+        variables.sort_by_key(|v| v.slot);
+        program.env.globals = variables
             .iter()
-            .try_fold(Program { ops: vec![] }, |mut acc, stmt| match stmt {
-                ast::Statement::FunctionCall { function_call } => {
-                    let function_ops = compile_function_call(function_call, &functions)?;
-                    acc.ops.extend(function_ops.ops);
-                    Ok(acc)
+            .map(|v| v.var_type.default_value())
+            .collect();
+        for (slot, value) in initial_values {
+            program.env.globals[slot] = value;
+        }
+        program.env.aggregates = scope
+            .aggregates
+            .into_inner()
+            .into_iter()
+            .map(Aggregator::new)
+            .collect();
+        // The rows of group_by statements are printed before the end blocks
+        // are run
+        let mut end_ops: Vec<Op> = (0..scope.groups.len())
+            .map(|slot| Op::PrintGroups { slot })
+            .collect();
+        end_ops.append(&mut program.end_ops);
+        program.end_ops = end_ops;
+        program.env.groups = scope.groups;
+        if let Some(descending) = scope.sort_descending.get() {
+            let budget = options.sort_memory.unwrap_or(sort::DEFAULT_MEMORY_BUDGET);
+            program.env.sorter = Some(Sorter::new(descending, budget));
+        }
+        program.env.ranges = vec![false; scope.ranges];
+        program.env.heads = vec![0; scope.heads.get()];
+        program.history_len = scope.history_len.get();
+        program.lookahead = scope.lookahead.get();
+        program.separator = scope.separator.into_inner();
+        Ok(program)
+    }
+
+    /// Runs the begin blocks of the program, before any item is read
+    pub fn begin(&mut self) -> Result<String, RuntimeError> {
+        ops::execute(&self.begin_ops, &mut self.env, "")
+    }
+
+    /// If the program looks ahead with `next`, items are held back until
+    /// enough following items have been read, so the output may belong to an
+    /// earlier item. `flush` runs the items that are held back at the end.
+    /// Items are ignored once the status isn't `Running` anymore.
+    pub fn run(&mut self, input: &str) -> Result<String, RuntimeError> {
+        if self.env.status != Status::Running {
+            return Ok(String::new());
+        }
+        self.env.following.push_back(input.to_string());
+        match self.env.following.len() > self.lookahead {
+            true => self.run_next(),
+            false => Ok(String::new()),
+        }
+    }
+
+    /// Sets the input that `step` and `getline` read items from
+    pub fn set_input(&mut self, reader: Box<dyn BufRead>, separator: Separator) {
+        self.env.input.set_items(reader, separator);
+    }
+
+    /// Reads the next item from the input and runs it, like `run`. Returns
+    /// None at the end of the input or once the status isn't `Running`.
+    pub fn step(&mut self) -> Option<Result<String, RuntimeError>> {
+        if self.env.status != Status::Running {
+            return None;
+        }
+        while self.env.following.len() <= self.lookahead {
+            match self.env.input.next_item() {
+                Ok(Some(item)) => self.env.following.push_back(item),
+                Ok(None) => break,
+                Err(err) => {
+                    let msg = format!("Cannot read input: {}", err);
+                    return Some(Err(RuntimeError { msg }));
                 }
-            })
+            }
+        }
+        match self.env.following.is_empty() {
+            true => None,
+            false => Some(self.run_next()),
+        }
     }
 
-    pub fn run(&self, input: &str) -> Result<String, RuntimeError> {
-        let init_state = ProgramState {
-            output: String::new(),
-            stack: Vec::new(),
-        };
-        let final_state =
-            self.ops
-                .iter()
-                .try_fold(init_state, |mut program_state, op| match op {
-                    Op::Print => match program_state.stack.pop() {
-                        None => Err(RuntimeError {
-                            msg: "Empty stack".to_string(),
-                        }),
-                        Some(val) => {
-                            program_state.output.push_str(&format!("{}", val));
-                            Ok(program_state)
-                        }
-                    },
-                    Op::LoadItem => {
-                        program_state.stack.push(Value::String(input.to_string()));
-                        Ok(program_state)
-                    }
-                    Op::LoadIndex { index } => {
-                        let list = program_state.stack.pop().ok_or(RuntimeError {
-                            msg: "Empty stack".to_string(),
-                        })?;
-                        let elem = match list {
-                            Value::List(l) => l
-                                .get(*index as usize)
-                                .ok_or(RuntimeError {
-                                    msg: "out of bounds".to_string(),
-                                })
-                                .cloned(),
-                            _ => Err(RuntimeError {
-                                msg: "not a list".to_string(),
-                            }),
-                        }?;
-                        program_state.stack.push(Value::String(elem));
-                        Ok(program_state)
-                    }
-                    Op::SplitStr => {
-                        let s = program_state.stack.pop().ok_or(RuntimeError {
-                            msg: "Empty stack".to_string(),
-                        })?;
-                        let s = match s {
-                            Value::List(_) => Err(RuntimeError {
-                                msg: "Expected String".to_string(),
-                            }),
-                            Value::String(s) => Ok(s),
-                        }?;
-                        program_state.stack.push(Value::List(
-                            s.split_whitespace().map(|s| s.to_string()).collect(),
-                        ));
-                        Ok(program_state)
-                    }
-                })?;
-        Ok(final_state.output)
+    /// Runs the items that have been held back by `run` for looking ahead
+    pub fn flush(&mut self) -> Result<String, RuntimeError> {
+        let mut output = String::new();
+        while !self.env.following.is_empty() && self.env.status == Status::Running {
+            output.push_str(&self.run_next()?);
+        }
+        Ok(output)
+    }
+
+    fn run_next(&mut self) -> Result<String, RuntimeError> {
+        let input = self.env.following.pop_front().unwrap_or_default();
+        let output = ops::execute(&self.ops, &mut self.env, &input)?;
+        if !self.split_ops.is_empty() {
+            self.split_key = Some(ops::execute(&self.split_ops, &mut self.env, &input)?);
+        }
+        if self.history_len > 0 {
+            if self.env.history.len() == self.history_len {
+                self.env.history.pop_front();
+            }
+            self.env.history.push_back(input);
+        }
+        Ok(output)
+    }
+
+    /// The record separator chosen by the program with `records`, if any
+    pub fn separator(&self) -> Option<&Separator> {
+        self.separator.as_ref()
+    }
+
+    /// The value of the `split_by` expression for the item that has been run
+    /// last, after running it, so that it may refer to variables that the
+    /// program has set for the item
+    pub fn split_key(&self) -> Option<&str> {
+        self.split_key.as_deref()
+    }
+
+    /// Assigns a value to one of `Options::assigned_variables`, with the
+    /// same type as there
+    pub fn assign(&mut self, name: &str, value: &str) -> Result<(), RuntimeError> {
+        let (slot, var_type) = self.assignable.get(name).ok_or(RuntimeError {
+            msg: format!("Variable {} cannot be assigned", name),
+        })?;
+        let value = variable_value(value, self.env.number_mode);
+        if !var_type.accepts(&value.get_type()) {
+            let msg = format!(
+                "Cannot assign {} to variable {} of type {}",
+                value.get_type(),
+                name,
+                var_type
+            );
+            return Err(RuntimeError { msg });
+        }
+        self.env.globals[*slot] = value;
+        Ok(())
+    }
+
+    /// Whether the program wants more items. Callers should stop reading
+    /// input once it isn't `Running`, and still call `flush` and `end`.
+    pub fn status(&self) -> Status {
+        self.env.status
+    }
+
+    /// The records passed to `sort_by`, in sorted order, as printed. Returns
+    /// None if the program doesn't call `sort_by`, and must be called before
+    /// `end`, which may print the records otherwise.
+    pub fn sorted(&mut self) -> Result<Option<Sorted>, RuntimeError> {
+        match self.env.sorter.take() {
+            None => Ok(None),
+            Some(sorter) => sorter.finish().map(Some).map_err(|err| RuntimeError {
+                msg: format!("Cannot sort: {}", err),
+            }),
+        }
+    }
+
+    /// Runs the end blocks of the program, after the last item has been read
+    pub fn end(&mut self) -> Result<String, RuntimeError> {
+        ops::execute(&self.end_ops, &mut self.env, "")
+    }
+
+    /// Flushes and closes the files and commands the program has printed to,
+    /// and waits for the commands it has read from. Should be called after
+    /// the output of `end` has been written, since commands may write to
+    /// stdout as well.
+    pub fn close(&mut self) -> Result<(), RuntimeError> {
+        self.env.outputs.close().map_err(|err| RuntimeError {
+            msg: format!("Cannot close output: {}", err),
+        })?;
+        self.env.input.close().map_err(|err| RuntimeError {
+            msg: format!("Cannot close input: {}", err),
+        })
     }
 
     // TODO build binary for disassembling the program, for debugging
@@ -110,64 +447,565 @@ impl Program {
     // }
 }
 
-fn builtin_functions() -> HashMap<String, TypedFunction> {
-    let mut map = HashMap::new();
+/// Everything a statement or expression can refer to during compilation
+struct Scope {
+    functions: HashMap<String, Vec<TypedFunction>>,
+    /// `getline` declares the variable it reads into while being compiled as
+    /// an expression, hence the RefCell
+    variables: RefCell<HashMap<String, Variable>>,
+    number_mode: NumberMode,
+    patterns: Patterns,
+    /// One per call of an aggregate function, in the order of the calls.
+    /// Expressions are compiled with a shared scope, hence the RefCell.
+    aggregates: RefCell<Vec<Aggregate>>,
+    /// One per group_by statement
+    groups: Vec<Groups>,
+    /// Whether `sort_by` sorts in descending order, if it is called
+    sort_descending: Cell<Option<bool>>,
+    /// The number of previous items used by `prev` and `window`
+    history_len: Cell<usize>,
+    /// The number of following items used by `next`
+    lookahead: Cell<usize>,
+    /// The number of range statements
+    ranges: usize,
+    /// The number of calls of `head`
+    heads: Cell<usize>,
+    allow_exec: bool,
+    /// The kind of block that is compiled
+    block: Block,
+    /// The record separator chosen with `records`
+    separator: RefCell<Option<Separator>>,
+}
 
-    map.insert(
-        "print".to_string(),
-        TypedFunction {
-            ops: vec![Op::Print],
-            return_type: Type::Unit,
-            arg_types: vec![Type::String],
-        },
-    );
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Block {
+    /// There is no item in begin and end blocks
+    Begin,
+    Main,
+    End,
+}
 
-    map.insert("split".to_string(), {
-        TypedFunction {
-            ops: vec![Op::SplitStr],
-            return_type: Type::List,
-            arg_types: vec![Type::String],
+struct Variable {
+    slot: usize,
+    var_type: Type,
+}
+
+impl Scope {
+    /// Returns the slot of a variable, declaring the variable if it doesn't
+    /// exist yet. The type of a variable is the type of the first value
+    /// assigned to it.
+    fn declare(&self, name: &str, var_type: &Type) -> Result<usize, CompileError> {
+        let mut variables = self.variables.borrow_mut();
+        let next_slot = variables.len();
+        let variable = variables
+            .entry(name.to_string())
+            .or_insert_with(|| Variable {
+                slot: next_slot,
+                var_type: var_type.clone(),
+            });
+        if !variable.var_type.accepts(var_type) {
+            let msg = format!(
+                "Cannot assign {} to variable {} of type {}",
+                var_type, name, variable.var_type
+            );
+            return Err(CompileError { msg });
         }
-    });
+        Ok(variable.slot)
+    }
+}
 
-    map
+/// Declares all variables that are assigned to by key as maps, so that they
+/// can be used before the first assignment, e.g. in
+/// `if has(seen, _) { ... } else { seen[_] = true }`
+fn declare_maps(statements: &[ast::Statement], scope: &Scope) -> Result<(), CompileError> {
+    for stmt in statements {
+        match stmt {
+            ast::Statement::Assignment {
+                variable,
+                key: Some(_),
+                ..
+            } => {
+                scope.declare(variable, &Type::Map)?;
+            }
+            ast::Statement::If {
+                statements,
+                else_statements,
+                ..
+            } => {
+                declare_maps(statements, scope)?;
+                declare_maps(else_statements, scope)?;
+            }
+            ast::Statement::For { statements, .. }
+            | ast::Statement::Range { statements, .. }
+            | ast::Statement::Begin { statements }
+            | ast::Statement::End { statements } => declare_maps(statements, scope)?,
+            ast::Statement::FunctionCall { .. }
+            | ast::Statement::Assignment { .. }
+            | ast::Statement::GroupBy { .. }
+            | ast::Statement::Next => {}
+        }
+    }
+    Ok(())
 }
 
-fn compile_expr(
-    expr: &ast::Expr,
-    functions: &HashMap<String, TypedFunction>,
-) -> Result<TypedComputation, CompileError> {
+/// The name of a variable, if the expression is nothing but one
+fn identifier(expr: &ast::Expr) -> Option<&str> {
+    match expr {
+        ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::Identifier(name),
+                },
+            index: None,
+        } => Some(name),
+        _ => None,
+    }
+}
+
+/// The string, if the expression is nothing but a string literal
+fn string_literal(expr: &ast::Expr) -> Option<&str> {
+    match expr {
+        ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::String(s),
+                },
+            index: None,
+        } => Some(s),
+        _ => None,
+    }
+}
+
+fn compile_statements(
+    statements: &[ast::Statement],
+    scope: &mut Scope,
+) -> Result<Vec<Op>, CompileError> {
+    let mut ops = vec![];
+    for stmt in statements {
+        ops.extend(compile_statement(stmt, scope)?);
+    }
+    Ok(ops)
+}
+
+fn compile_statement(stmt: &ast::Statement, scope: &mut Scope) -> Result<Vec<Op>, CompileError> {
+    match stmt {
+        ast::Statement::FunctionCall { function_call } => {
+            let mut comp = compile_function_call(function_call, scope)?;
+            // Discard the return value, so that it doesn't pile up on the stack
+            if comp.result_type != Type::Unit {
+                comp.ops.push(Op::Pop);
+            }
+            Ok(comp.ops)
+        }
+        ast::Statement::Assignment {
+            variable,
+            key: None,
+            operator: None,
+            value,
+        } => {
+            let mut comp = compile_expr(value, scope)?;
+            if comp.result_type == Type::Unit {
+                let msg = format!("Cannot assign {} to variable {}", Type::Unit, variable);
+                return Err(CompileError { msg });
+            }
+            let slot = scope.declare(variable, &comp.result_type)?;
+            comp.ops.push(Op::StoreVar { slot });
+            Ok(comp.ops)
+        }
+        ast::Statement::Assignment {
+            variable,
+            key: None,
+            operator: Some(operator),
+            value,
+        } => {
+            let operator = arithmetic_operator(*operator)?;
+            let comp = compile_expr(value, scope)?;
+            // A variable that hasn't been assigned yet starts with the default
+            // value of the operand's type, e.g. 0 for `count += 1`. If the type
+            // is only known at runtime, it starts with 0.0, since any number can
+            // be added to a float without changing its type (or to a decimal in
+            // decimal mode).
+            let declared_type = scope
+                .variables
+                .borrow()
+                .get(variable)
+                .map(|v| v.var_type.clone());
+            let var_type = match (declared_type, &comp.result_type) {
+                (Some(var_type), _) => var_type,
+                (None, Type::Any) if scope.number_mode == NumberMode::Decimal => Type::Decimal,
+                (None, Type::Any) => Type::Float,
+                (None, other) => other.clone(),
+            };
+            let result_type = arithmetic_result(operator, &var_type, &comp.result_type)?;
+            let slot = scope.declare(variable, &var_type)?;
+            scope.declare(variable, &result_type)?;
+            let mut ops = vec![Op::LoadVar { slot }];
+            ops.extend(comp.ops);
+            ops.push(Op::Arithmetic { operator });
+            ops.push(Op::StoreVar { slot });
+            Ok(ops)
+        }
+        ast::Statement::Assignment {
+            variable,
+            key: Some(key),
+            operator,
+            value,
+        } => {
+            // Variables assigned to by key have been declared as maps before
+            let slot = scope.declare(variable, &Type::Map)?;
+            let key_comp = compile_expr(key, scope)?;
+            expect_type(&Type::String, &key_comp.result_type)?;
+            let value_comp = compile_expr(value, scope)?;
+            let store_op = match operator {
+                Some(operator) => {
+                    let operator = arithmetic_operator(*operator)?;
+                    let value_type = &value_comp.result_type;
+                    arithmetic_result(operator, value_type, value_type)?;
+                    Op::UpdateKey { operator }
+                }
+                None => Op::StoreKey,
+            };
+            let mut ops = vec![Op::LoadVar { slot }];
+            ops.extend(key_comp.ops);
+            ops.extend(value_comp.ops);
+            ops.push(store_op);
+            Ok(ops)
+        }
+        ast::Statement::If {
+            condition,
+            statements,
+            else_statements,
+        } => {
+            let condition_comp = compile_expr(condition, scope)?;
+            expect_type(&Type::Bool, &condition_comp.result_type)?;
+            let then_ops = compile_statements(statements, scope)?;
+            let else_ops = compile_statements(else_statements, scope)?;
+
+            let mut ops = condition_comp.ops;
+            if else_ops.is_empty() {
+                ops.push(Op::JumpIfFalse {
+                    offset: then_ops.len() as isize + 1,
+                });
+                ops.extend(then_ops);
+            } else {
+                ops.push(Op::JumpIfFalse {
+                    offset: then_ops.len() as isize + 2,
+                });
+                ops.extend(then_ops);
+                ops.push(Op::Jump {
+                    offset: else_ops.len() as isize + 1,
+                });
+                ops.extend(else_ops);
+            }
+            Ok(ops)
+        }
+        ast::Statement::For {
+            variable,
+            iterable,
+            statements,
+        } => {
+            let iterable_comp = compile_expr(iterable, scope)?;
+            let element_type = match iterable_comp.result_type {
+                Type::Map => Type::String,
+                Type::List | Type::Any => Type::Any,
+                other => {
+                    let msg = format!("Expected {} or {}, got {}", Type::List, Type::Map, other);
+                    return Err(CompileError { msg });
+                }
+            };
+            let slot = scope.declare(variable, &element_type)?;
+            let body_ops = compile_statements(statements, scope)?;
+
+            let mut ops = iterable_comp.ops;
+            ops.push(Op::IterStart);
+            ops.push(Op::IterNext {
+                slot,
+                offset: body_ops.len() as isize + 2,
+            });
+            let body_len = body_ops.len() as isize;
+            ops.extend(body_ops);
+            ops.push(Op::Jump {
+                offset: -(body_len + 1),
+            });
+            Ok(ops)
+        }
+        ast::Statement::GroupBy { args, statements } => compile_group_by(args, statements, scope),
+        ast::Statement::Range { args, statements } => compile_range(args, statements, scope),
+        ast::Statement::Next if scope.block != Block::Main => Err(CompileError {
+            msg: "next is not allowed in begin and end blocks".to_string(),
+        }),
+        ast::Statement::Next => Ok(vec![Op::Skip]),
+        ast::Statement::Begin { .. } | ast::Statement::End { .. } => Err(CompileError {
+            msg: "begin and end blocks are only allowed at the top level".to_string(),
+        }),
+    }
+}
+
+fn expect_type(expected: &Type, actual: &Type) -> Result<(), CompileError> {
+    match expected.accepts(actual) {
+        true => Ok(()),
+        false => Err(CompileError {
+            msg: format!("Expected {}, got {}", expected, actual),
+        }),
+    }
+}
+
+/// Strings are accepted as regexes as well, but they are compiled every time
+/// they are used
+fn expect_regex(actual: &Type) -> Result<(), CompileError> {
+    match actual {
+        Type::Regex | Type::String | Type::Any => Ok(()),
+        other => Err(CompileError {
+            msg: format!("Expected {}, got {}", Type::Regex, other),
+        }),
+    }
+}
+
+fn compile_expr(expr: &ast::Expr, scope: &Scope) -> Result<TypedComputation, CompileError> {
     let ast::Expr { arr_expr, index } = expr;
     let mut comp = match arr_expr {
         ast::ArrExpr::ValueExpr { value } => match value {
+            // Indexing the item accesses its fields
+            ast::Value::Item if index.is_some() => TypedComputation {
+                ops: vec![Op::LoadItem, Op::SplitFields],
+                result_type: Type::List,
+            },
             ast::Value::Item => TypedComputation {
                 ops: vec![Op::LoadItem],
                 result_type: Type::String,
             },
-            ast::Value::Identifier(_id) => todo!(),
+            ast::Value::Identifier(id) => {
+                let variables = scope.variables.borrow();
+                let variable = variables.get(id).ok_or(CompileError {
+                    msg: format!("Variable {} not found", id),
+                })?;
+                TypedComputation {
+                    ops: vec![Op::LoadVar {
+                        slot: variable.slot,
+                    }],
+                    result_type: variable.var_type.clone(),
+                }
+            }
+            ast::Value::String(s) => TypedComputation {
+                ops: vec![Op::LoadConst {
+                    value: Value::String(s.clone()),
+                }],
+                result_type: Type::String,
+            },
+            ast::Value::Bool(b) => TypedComputation {
+                ops: vec![Op::LoadConst {
+                    value: Value::Bool(*b),
+                }],
+                result_type: Type::Bool,
+            },
+            // Regex literals are compiled once, rather than for every item
+            ast::Value::Regex(r) => {
+                let regex = regex::Regex::new(r).map_err(|err| CompileError {
+                    msg: format!("Invalid regex /{}/: {}", r, err),
+                })?;
+                TypedComputation {
+                    ops: vec![Op::LoadConst {
+                        value: Value::Regex(regex),
+                    }],
+                    result_type: Type::Regex,
+                }
+            }
+            ast::Value::Number(n) => {
+                let value = arithmetic::parse_number(n, scope.number_mode)
+                    .map_err(|msg| CompileError { msg })?;
+                TypedComputation {
+                    result_type: value.get_type(),
+                    ops: vec![Op::LoadConst { value }],
+                }
+            }
         },
+        ast::ArrExpr::BinaryExpr { operator, lhs, rhs } => {
+            compile_binary_expr(*operator, lhs, rhs, scope)?
+        }
+        ast::ArrExpr::UnaryExpr { operator, operand } => {
+            let mut comp = compile_expr(operand, scope)?;
+            match operator {
+                ast::UnaryOperator::Neg => match comp.result_type {
+                    Type::Int | Type::Float | Type::Any => comp.ops.push(Op::Negate),
+                    ref other => {
+                        let msg = format!("Cannot negate {}", other);
+                        return Err(CompileError { msg });
+                    }
+                },
+                ast::UnaryOperator::Not => {
+                    expect_type(&Type::Bool, &comp.result_type)?;
+                    comp.ops.push(Op::Not);
+                    comp.result_type = Type::Bool;
+                }
+            }
+            comp
+        }
+        ast::ArrExpr::NestedExpr { expr } => compile_expr(expr, scope)?,
         ast::ArrExpr::FunctionExpr { function_call } => {
-            compile_function_call(function_call, functions)?
+            compile_function_call(function_call, scope)?
+        }
+        ast::ArrExpr::MapLiteral { entries } => {
+            let mut ops = vec![];
+            for ast::MapEntry { key, value } in entries {
+                let key_comp = compile_expr(key, scope)?;
+                expect_type(&Type::String, &key_comp.result_type)?;
+                ops.extend(key_comp.ops);
+                ops.extend(compile_expr(value, scope)?.ops);
+            }
+            ops.push(Op::MakeMap { len: entries.len() });
+            TypedComputation {
+                ops,
+                result_type: Type::Map,
+            }
         }
     };
 
-    // if index is not None => assert result is array, then index it
-    match (*index, &comp.result_type) {
-        (Some(index), Type::List) => {
-            comp.ops.push(Op::LoadIndex { index });
-            comp.result_type = Type::String;
+    // if index is not None => assert result is array or map, then index it
+    match (index, &comp.result_type) {
+        (Some(ast::Index::Position(index)), Type::List | Type::Any) => {
+            comp.ops.push(Op::LoadIndex { index: *index });
+            comp.result_type = Type::Any;
+            Ok(comp)
+        }
+        (Some(ast::Index::Expr(key)), container_type) => {
+            let key_comp = compile_expr(key, scope)?;
+            match (&key_comp.result_type, container_type) {
+                (Type::String, Type::Map | Type::Any)
+                | (Type::Int, Type::List | Type::Any)
+                | (Type::Any, Type::List | Type::Map | Type::Any) => {}
+                (key_type, container_type) => {
+                    let msg = format!("Cannot index {} with {}", container_type, key_type);
+                    return Err(CompileError { msg });
+                }
+            }
+            comp.ops.extend(key_comp.ops);
+            comp.ops.push(Op::LoadKey);
+            comp.result_type = Type::Any;
             Ok(comp)
         }
         (None, _) => Ok(comp),
-        (Some(_), other_type) => Err(CompileError {
+        (Some(ast::Index::Position(_)), other_type) => Err(CompileError {
             msg: format!("Expected {}, got {}", Type::List, other_type),
         }),
     }
 }
 
+fn compile_binary_expr(
+    operator: ast::BinaryOperator,
+    lhs: &ast::Expr,
+    rhs: &ast::Expr,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let lhs_comp = compile_expr(lhs, scope)?;
+    let rhs_comp = compile_expr(rhs, scope)?;
+    let mut ops = lhs_comp.ops;
+    let result_type = match operator {
+        // The right hand side is only evaluated if it determines the result
+        ast::BinaryOperator::And | ast::BinaryOperator::Or => {
+            expect_type(&Type::Bool, &lhs_comp.result_type)?;
+            expect_type(&Type::Bool, &rhs_comp.result_type)?;
+            let rhs_len = rhs_comp.ops.len() as isize;
+            if operator == ast::BinaryOperator::And {
+                ops.push(Op::JumpIfFalse {
+                    offset: rhs_len + 2,
+                });
+                ops.extend(rhs_comp.ops);
+                ops.push(Op::Jump { offset: 2 });
+                ops.push(Op::LoadConst {
+                    value: Value::Bool(false),
+                });
+            } else {
+                ops.push(Op::JumpIfFalse { offset: 3 });
+                ops.push(Op::LoadConst {
+                    value: Value::Bool(true),
+                });
+                ops.push(Op::Jump {
+                    offset: rhs_len + 1,
+                });
+                ops.extend(rhs_comp.ops);
+            }
+            Type::Bool
+        }
+        ast::BinaryOperator::Match | ast::BinaryOperator::NotMatch => {
+            expect_type(&Type::String, &lhs_comp.result_type)?;
+            expect_regex(&rhs_comp.result_type)?;
+            ops.extend(rhs_comp.ops);
+            ops.push(Op::Match {
+                negate: operator == ast::BinaryOperator::NotMatch,
+            });
+            Type::Bool
+        }
+        ast::BinaryOperator::Eq
+        | ast::BinaryOperator::NotEq
+        | ast::BinaryOperator::Lt
+        | ast::BinaryOperator::LtEq
+        | ast::BinaryOperator::Gt
+        | ast::BinaryOperator::GtEq => {
+            let operator = comparison_operator(operator);
+            if !comparable(operator, &lhs_comp.result_type, &rhs_comp.result_type) {
+                let msg = format!(
+                    "Cannot compare {} and {}",
+                    lhs_comp.result_type, rhs_comp.result_type
+                );
+                return Err(CompileError { msg });
+            }
+            ops.extend(rhs_comp.ops);
+            ops.push(Op::Compare { operator });
+            Type::Bool
+        }
+        ast::BinaryOperator::Add
+        | ast::BinaryOperator::Sub
+        | ast::BinaryOperator::Mul
+        | ast::BinaryOperator::Div
+        | ast::BinaryOperator::Rem => {
+            let operator = arithmetic_operator(operator)?;
+            let result_type =
+                arithmetic_result(operator, &lhs_comp.result_type, &rhs_comp.result_type)?;
+            ops.extend(rhs_comp.ops);
+            ops.push(Op::Arithmetic { operator });
+            result_type
+        }
+    };
+    Ok(TypedComputation { ops, result_type })
+}
+
+fn arithmetic_operator(operator: ast::BinaryOperator) -> Result<Arithmetic, CompileError> {
+    match operator {
+        ast::BinaryOperator::Add => Ok(Arithmetic::Add),
+        ast::BinaryOperator::Sub => Ok(Arithmetic::Sub),
+        ast::BinaryOperator::Mul => Ok(Arithmetic::Mul),
+        ast::BinaryOperator::Div => Ok(Arithmetic::Div),
+        ast::BinaryOperator::Rem => Ok(Arithmetic::Rem),
+        other => Err(CompileError {
+            msg: format!("{:?} is not an arithmetic operator", other),
+        }),
+    }
+}
+
+fn comparison_operator(operator: ast::BinaryOperator) -> Comparison {
+    match operator {
+        ast::BinaryOperator::NotEq => Comparison::NotEq,
+        ast::BinaryOperator::Lt => Comparison::Lt,
+        ast::BinaryOperator::LtEq => Comparison::LtEq,
+        ast::BinaryOperator::Gt => Comparison::Gt,
+        ast::BinaryOperator::GtEq => Comparison::GtEq,
+        _ => Comparison::Eq,
+    }
+}
+
+fn arithmetic_result(operator: Arithmetic, lhs: &Type, rhs: &Type) -> Result<Type, CompileError> {
+    arithmetic_type(lhs, rhs).ok_or_else(|| CompileError {
+        msg: format!("Cannot apply {:?} to {} and {}", operator, lhs, rhs),
+    })
+}
+
+/// The functions that run shell commands, which programs may only call if
+/// `Options::allow_exec` is set
+const EXEC_FUNCTIONS: &[&str] = &["system", "exec", "exec_lines", "getline_cmd", "print_pipe"];
+
 fn compile_function_call(
     function_call: &ast::FunctionCall,
-    functions: &HashMap<String, TypedFunction>,
+    scope: &Scope,
 ) -> Result<TypedComputation, CompileError> {
     let mut ops = Vec::new();
 
@@ -175,30 +1013,61 @@ fn compile_function_call(
         function_name,
         args,
     } = function_call;
+    if EXEC_FUNCTIONS.contains(&function_name.as_str()) && !scope.allow_exec {
+        let msg = format!(
+            "{} runs shell commands, which are only allowed with --allow-exec",
+            function_name
+        );
+        return Err(CompileError { msg });
+    }
+    match function_name.as_str() {
+        "format" | "printf" => return compile_format_call(function_call, scope),
+        "parse" => return compile_parse_call(function_call, scope),
+        "fixed" => return compile_fixed_call(function_call, scope),
+        "sort_by" => return compile_sort_by_call(function_call, scope),
+        "prev" | "window" | "next" => return compile_neighbor_call(function_call, scope),
+        "head" => return compile_head_call(function_call, scope),
+        "records" => return compile_records_call(function_call, scope),
+        "getline" | "getline_cmd" => return compile_getline_call(function_call, scope),
+        name if name.starts_with("agg.") => return compile_aggregate_call(function_call, scope),
+        _ => {}
+    }
 
-    let TypedFunction {
-        ops: function_ops,
-        return_type,
-        arg_types,
-    } = functions.get(function_name).ok_or(CompileError {
+    let overloads = scope.functions.get(function_name).ok_or(CompileError {
         msg: format!("Function {} not found", function_name),
     })?;
 
     let arg_computations: Vec<TypedComputation> = args
         .iter()
-        .map(|arg| compile_expr(arg, functions))
+        .map(|arg| compile_expr(arg, scope))
         .collect::<Result<Vec<_>, CompileError>>()?;
-    if args.len() != arg_types.len() {
-        let msg = format!(
-            "Function {} takes {} arguments, but {} were given",
-            function_name,
-            arg_types.len(),
-            arg_computations.len()
-        );
-        return Err(CompileError { msg });
-    }
+    let TypedFunction {
+        ops: function_ops,
+        return_type,
+        arg_types,
+    } = overloads
+        .iter()
+        .find(|f| f.arg_types.len() == args.len())
+        .ok_or_else(|| {
+            let arities: Vec<String> = overloads
+                .iter()
+                .map(|f| f.arg_types.len().to_string())
+                .collect();
+            let msg = format!(
+                "Function {} takes {} arguments, but {} were given",
+                function_name,
+                arities.join(" or "),
+                arg_computations.len()
+            );
+            CompileError { msg }
+        })?;
     for (i, (arg_c, exp_type)) in arg_computations.iter().zip(arg_types).enumerate() {
-        if arg_c.result_type != *exp_type {
+        // Regexes may also be given as strings, which are compiled at runtime
+        let accepted = match exp_type {
+            Type::Regex => expect_regex(&arg_c.result_type).is_ok(),
+            _ => exp_type.accepts(&arg_c.result_type),
+        };
+        if !accepted {
             let msg = format!(
                 "Argument {} to function {} is wrong: Expected {}, got {}",
                 i, function_name, exp_type, arg_c.result_type
@@ -220,6 +1089,618 @@ fn compile_function_call(
     })
 }
 
+/// `format` and `printf` take any number of arguments after the format. If the
+/// format is a literal, it is checked against the arguments at compile time.
+fn compile_format_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let ast::FunctionCall {
+        function_name,
+        args,
+    } = function_call;
+    let (format, args) = args.split_first().ok_or(CompileError {
+        msg: format!(
+            "Function {} takes a format as first argument",
+            function_name
+        ),
+    })?;
+    let format_comp = compile_expr(format, scope)?;
+    expect_type(&Type::String, &format_comp.result_type)?;
+    let arg_computations = args
+        .iter()
+        .map(|arg| compile_expr(arg, scope))
+        .collect::<Result<Vec<_>, CompileError>>()?;
+
+    if let ast::Expr {
+        arr_expr: ast::ArrExpr::ValueExpr {
+            value: ast::Value::String(s),
+        },
+        index: None,
+    } = format
+    {
+        let arg_types: Vec<Type> = arg_computations
+            .iter()
+            .map(|c| c.result_type.clone())
+            .collect();
+        printf::Format::parse(s)
+            .and_then(|f| f.check(&arg_types))
+            .map_err(|msg| CompileError {
+                msg: format!("Invalid format for {}: {}", function_name, msg),
+            })?;
+    }
+
+    let mut ops = format_comp.ops;
+    ops.extend(arg_computations.into_iter().flat_map(|c| c.ops));
+    ops.push(Op::Format { args: args.len() });
+    let result_type = match function_name.as_str() {
+        "printf" => {
+            ops.push(Op::Write);
+            Type::Unit
+        }
+        _ => Type::String,
+    };
+    Ok(TypedComputation { ops, result_type })
+}
+
+/// `parse(s, pattern)` matches a string against a grok pattern. Like regex
+/// literals, patterns that are literals are compiled once.
+fn compile_parse_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let [s, pattern] = function_call.args.as_slice() else {
+        let msg = format!(
+            "Function parse takes 2 arguments, but {} were given",
+            function_call.args.len()
+        );
+        return Err(CompileError { msg });
+    };
+    let s_comp = compile_expr(s, scope)?;
+    expect_type(&Type::String, &s_comp.result_type)?;
+    let mut ops = s_comp.ops;
+    match pattern {
+        ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::String(pattern),
+                },
+            index: None,
+        } => {
+            let regex = scope
+                .patterns
+                .compile(pattern)
+                .map_err(|msg| CompileError { msg })?;
+            ops.push(Op::LoadConst {
+                value: Value::Regex(regex),
+            });
+        }
+        pattern => {
+            let pattern_comp = compile_expr(pattern, scope)?;
+            expect_regex(&pattern_comp.result_type)?;
+            ops.extend(pattern_comp.ops);
+            ops.push(Op::Grok);
+        }
+    }
+    ops.push(Op::NamedCaptures);
+    Ok(TypedComputation {
+        ops,
+        result_type: Type::Map,
+    })
+}
+
+/// `fixed(s, columns)` and `fixed(s, columns, trim)`. Columns and trim mode
+/// are usually literals, which are parsed once here, so that mistakes in them
+/// are compile errors; otherwise they are parsed for every item.
+fn compile_fixed_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let (s, spec, trim) = match function_call.args.as_slice() {
+        [s, spec] => (s, spec, None),
+        [s, spec, trim] => (s, spec, Some(trim)),
+        args => {
+            let msg = format!(
+                "Function fixed takes 2 or 3 arguments, but {} were given",
+                args.len()
+            );
+            return Err(CompileError { msg });
+        }
+    };
+    let s_comp = compile_expr(s, scope)?;
+    expect_type(&Type::String, &s_comp.result_type)?;
+    let mut ops = s_comp.ops;
+    let literal_trim = match trim {
+        None => Some(Trim::default()),
+        Some(trim) => string_literal(trim)
+            .map(Trim::try_from)
+            .transpose()
+            .map_err(|msg| CompileError { msg })?,
+    };
+    match (string_literal(spec), literal_trim) {
+        (Some(spec), Some(trim)) => {
+            let columns = Columns::parse(spec, trim).map_err(|msg| CompileError { msg })?;
+            ops.push(Op::SplitColumns { columns });
+        }
+        _ => {
+            for arg in std::iter::once(spec).chain(trim) {
+                let comp = compile_expr(arg, scope)?;
+                expect_type(&Type::String, &comp.result_type)?;
+                ops.extend(comp.ops);
+            }
+            ops.push(Op::SplitFixed {
+                with_trim: trim.is_some(),
+            });
+        }
+    }
+    Ok(TypedComputation {
+        ops,
+        result_type: Type::List,
+    })
+}
+
+/// `sort_by(record, key, order)` emits a record like `print`, but the records
+/// are sorted by their keys at the end of the input. All calls must agree on
+/// the direction of the order, which is a literal like `-numeric`.
+fn compile_sort_by_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let (record, key, order) = match function_call.args.as_slice() {
+        [record, key] => (record, key, Order::default()),
+        [record, key, order] => {
+            let order = match order {
+                ast::Expr {
+                    arr_expr:
+                        ast::ArrExpr::ValueExpr {
+                            value: ast::Value::String(order),
+                        },
+                    index: None,
+                } => Order::try_from(order.as_str()).map_err(|msg| CompileError { msg })?,
+                _ => {
+                    let msg = "The order of sort_by must be a string literal".to_string();
+                    return Err(CompileError { msg });
+                }
+            };
+            (record, key, order)
+        }
+        args => {
+            let msg = format!(
+                "Function sort_by takes 2 or 3 arguments, but {} were given",
+                args.len()
+            );
+            return Err(CompileError { msg });
+        }
+    };
+    match scope.sort_descending.get() {
+        Some(descending) if descending != order.descending => {
+            let msg = "All calls of sort_by must sort in the same direction".to_string();
+            return Err(CompileError { msg });
+        }
+        _ => scope.sort_descending.set(Some(order.descending)),
+    }
+    let mut ops = vec![];
+    for arg in [record, key] {
+        let comp = compile_expr(arg, scope)?;
+        if comp.result_type == Type::Unit {
+            let msg = format!("Cannot sort {}", Type::Unit);
+            return Err(CompileError { msg });
+        }
+        ops.extend(comp.ops);
+    }
+    ops.push(Op::SortBy { kind: order.kind });
+    Ok(TypedComputation {
+        ops,
+        result_type: Type::Unit,
+    })
+}
+
+/// `prev(n)` is the nth item before the current one, `window(n)` the list of
+/// the last n items up to the current one, and `next(n)` the nth item after
+/// the current one. n defaults to 1 for `prev` and `next`, and must be a
+/// literal, so that the number of items to keep is known.
+fn compile_neighbor_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let name = function_call.function_name.as_str();
+    let n = match function_call.args.as_slice() {
+        [] if name != "window" => 1,
+        [ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::Number(n),
+                },
+            index: None,
+        }] => match n.parse::<usize>() {
+            Ok(n) if n > 0 => n,
+            _ => {
+                let msg = format!("The argument of {} must be a positive integer", name);
+                return Err(CompileError { msg });
+            }
+        },
+        _ => {
+            let msg = format!("The argument of {} must be an integer literal", name);
+            return Err(CompileError { msg });
+        }
+    };
+    let (op, result_type) = match name {
+        "prev" => {
+            scope.history_len.set(scope.history_len.get().max(n));
+            (Op::Prev { n }, Type::String)
+        }
+        "window" => {
+            scope.history_len.set(scope.history_len.get().max(n - 1));
+            (Op::Window { n }, Type::List)
+        }
+        _ => {
+            scope.lookahead.set(scope.lookahead.get().max(n));
+            (Op::Next { n }, Type::String)
+        }
+    };
+    Ok(TypedComputation {
+        ops: vec![op],
+        result_type,
+    })
+}
+
+/// The value of a variable that is defined outside of the program, e.g. by
+/// `-v name=value`: a number if it looks like one, and a string otherwise
+fn variable_value(value: &str, number_mode: NumberMode) -> Value {
+    arithmetic::parse_number(value, number_mode).unwrap_or(Value::String(value.to_string()))
+}
+
+/// The expression of `--split-by` is compiled like the program, and its value
+/// is written to the output as a string
+fn compile_split_by(source_code: &str, scope: &Scope) -> Result<Vec<Op>, CompileError> {
+    let tokens = tokens::scan(source_code.to_string()).map_err(|e| CompileError { msg: e })?;
+    let expr = ast::parse_expression(tokens).map_err(|e| CompileError { msg: e })?;
+    let mut comp = compile_expr(&expr, scope)?;
+    if comp.result_type == Type::Unit {
+        let msg = format!("Cannot split by {}", Type::Unit);
+        return Err(CompileError { msg });
+    }
+    comp.ops.push(Op::ToString);
+    comp.ops.push(Op::Write);
+    Ok(comp.ops)
+}
+
+/// `records(separator)` chooses how the input is split into items, see
+/// `Separator`. It takes effect before the first item is read, so it may only
+/// be called in begin blocks, with a string or regex literal.
+fn compile_records_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    if scope.block != Block::Begin {
+        let msg = "records may only be called in begin blocks".to_string();
+        return Err(CompileError { msg });
+    }
+    let separator = match function_call.args.as_slice() {
+        [ast::Expr {
+            arr_expr: ast::ArrExpr::ValueExpr { value },
+            index: None,
+        }] => match value {
+            ast::Value::String(s) => Separator::try_from(s.as_str()),
+            ast::Value::Regex(r) => Separator::regex(r),
+            _ => Err("The argument of records must be a string or regex literal".to_string()),
+        },
+        args => Err(format!(
+            "records takes 1 argument, but {} were given",
+            args.len()
+        )),
+    }
+    .map_err(|msg| CompileError { msg })?;
+    if scope.separator.borrow().is_some() {
+        let msg = "records may only be called once".to_string();
+        return Err(CompileError { msg });
+    }
+    scope.separator.replace(Some(separator));
+    Ok(TypedComputation {
+        ops: vec![],
+        result_type: Type::Unit,
+    })
+}
+
+/// `getline(var)` reads the next item of the input into a variable, skipping
+/// it for the program, `getline(var, path)` the next item of a file and
+/// `getline_cmd(var, command)` the next item of the output of a shell command.
+/// The variable is declared as a string if it hasn't been assigned before.
+fn compile_getline_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let name = function_call.function_name.as_str();
+    let (variable, source, path) = match (name, function_call.args.as_slice()) {
+        ("getline", [variable]) => (variable, Source::Input, None),
+        ("getline", [variable, path]) => (variable, Source::File, Some(path)),
+        ("getline_cmd", [variable, command]) => (variable, Source::Command, Some(command)),
+        (_, args) => {
+            let msg = format!("{} doesn't take {} arguments", name, args.len());
+            return Err(CompileError { msg });
+        }
+    };
+    let variable = identifier(variable).ok_or(CompileError {
+        msg: format!("The first argument of {} must be a variable", name),
+    })?;
+    let slot = scope.declare(variable, &Type::String)?;
+    let mut ops = vec![];
+    if let Some(path) = path {
+        let comp = compile_expr(path, scope)?;
+        expect_type(&Type::String, &comp.result_type)?;
+        ops.extend(comp.ops);
+    }
+    ops.push(Op::Getline { source, slot });
+    Ok(TypedComputation {
+        ops,
+        result_type: Type::Int,
+    })
+}
+
+/// `head(n)` stops reading items after the current one once this call has
+/// been executed n times, and skips the rest of the current item. It is
+/// counted per call, so that e.g. `if cond { print(_); head(10) }` prints the
+/// first ten items for which cond holds.
+fn compile_head_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let limit = match function_call.args.as_slice() {
+        [limit] => compile_expr(limit, scope)?,
+        args => {
+            let msg = format!("head takes 1 argument, but {} were given", args.len());
+            return Err(CompileError { msg });
+        }
+    };
+    expect_type(&Type::Int, &limit.result_type)?;
+    let mut ops = limit.ops;
+    ops.push(Op::Head {
+        slot: scope.heads.get(),
+    });
+    scope.heads.set(scope.heads.get() + 1);
+    Ok(TypedComputation {
+        ops,
+        result_type: Type::Unit,
+    })
+}
+
+/// Every call of an aggregate function, e.g. `agg.sum(_[2])`, accumulates the
+/// values it is called with across items and evaluates to their aggregate so
+/// far. Strings are converted to numbers like by `tonumber`.
+fn compile_aggregate_call(
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<TypedComputation, CompileError> {
+    let name = &function_call.function_name["agg.".len()..];
+    let (aggregate, mut comp) = compile_aggregate_arg(name, function_call, scope)?;
+    let mut aggregates = scope.aggregates.borrow_mut();
+    comp.ops.push(Op::Aggregate {
+        slot: aggregates.len(),
+        with_value: aggregate != Aggregate::Count,
+    });
+    aggregates.push(aggregate);
+    Ok(comp)
+}
+
+/// Returns the aggregate function and the computation of its argument, if it
+/// takes one. The result type is the type of the aggregate.
+fn compile_aggregate_arg(
+    name: &str,
+    function_call: &ast::FunctionCall,
+    scope: &Scope,
+) -> Result<(Aggregate, TypedComputation), CompileError> {
+    let aggregate = Aggregate::try_from(name).map_err(|msg| CompileError { msg })?;
+    let expected_args = match aggregate {
+        Aggregate::Count => 0,
+        _ => 1,
+    };
+    if function_call.args.len() != expected_args {
+        let msg = format!(
+            "Function {} takes {} arguments, but {} were given",
+            function_call.function_name,
+            expected_args,
+            function_call.args.len()
+        );
+        return Err(CompileError { msg });
+    }
+    let mut ops = vec![];
+    let mut arg_type = Type::Any;
+    if let Some(arg) = function_call.args.first() {
+        let comp = compile_expr(arg, scope)?;
+        arg_type = comp.result_type;
+        if !matches!(
+            arg_type,
+            Type::String | Type::Int | Type::Float | Type::Decimal | Type::Any
+        ) {
+            let msg = format!("Cannot aggregate {}", arg_type);
+            return Err(CompileError { msg });
+        }
+        ops.extend(comp.ops);
+    }
+    let result_type = match aggregate {
+        Aggregate::Count => Type::Int,
+        Aggregate::Sum | Aggregate::Min | Aggregate::Max => match arg_type {
+            Type::Int | Type::Float | Type::Decimal => arg_type,
+            _ => Type::Any,
+        },
+        Aggregate::Mean | Aggregate::Percentile(_) => Type::Float,
+    };
+    Ok((aggregate, TypedComputation { ops, result_type }))
+}
+
+/// `range(start, end, bounds) { ... }` runs the statements for the items
+/// from one for which the start condition holds to one for which the end
+/// condition holds. The conditions are bools, or regexes that the item must
+/// match. The bounds are `[]` (the default) to include both the start and the
+/// end item, `()` to exclude both, or `[)` or `(]` to include only one. The
+/// end condition is checked for the start item as well, so a range may
+/// consist of a single item, which is only included if both bounds are.
+fn compile_range(
+    args: &[ast::Expr],
+    statements: &[ast::Statement],
+    scope: &mut Scope,
+) -> Result<Vec<Op>, CompileError> {
+    let (start, end, bounds) = match args {
+        [start, end] => (start, end, "[]"),
+        [start, end, ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::String(bounds),
+                },
+            index: None,
+        }] => (start, end, bounds.as_str()),
+        [_, _, _] => {
+            let msg = "The bounds of range must be a string literal".to_string();
+            return Err(CompileError { msg });
+        }
+        _ => {
+            let msg = format!(
+                "range takes 2 or 3 arguments, but {} were given",
+                args.len()
+            );
+            return Err(CompileError { msg });
+        }
+    };
+    let (include_start, include_end) = match bounds {
+        "[]" => (true, true),
+        "[)" => (true, false),
+        "(]" => (false, true),
+        "()" => (false, false),
+        _ => {
+            let msg = format!("Invalid bounds '{}', expected [], [), (] or ()", bounds);
+            return Err(CompileError { msg });
+        }
+    };
+    let mut ops = vec![];
+    for condition in [start, end] {
+        let comp = compile_expr(condition, scope)?;
+        match comp.result_type {
+            Type::Regex => {
+                ops.push(Op::LoadItem);
+                ops.extend(comp.ops);
+                ops.push(Op::Match { negate: false });
+            }
+            other => {
+                expect_type(&Type::Bool, &other)?;
+                ops.extend(comp.ops);
+            }
+        }
+    }
+    ops.push(Op::InRange {
+        slot: scope.ranges,
+        include_start,
+        include_end,
+    });
+    scope.ranges += 1;
+    let body_ops = compile_statements(statements, scope)?;
+    ops.push(Op::JumpIfFalse {
+        offset: body_ops.len() as isize + 1,
+    });
+    ops.extend(body_ops);
+    Ok(ops)
+}
+
+/// `group_by(key, order) { ... }` aggregates items per key, and prints one row
+/// per key at the end. The block consists of aggregate functions, which are
+/// named after the function unless they are assigned to a name, e.g.
+/// `count(); bytes = sum(_[2]);`. The order names the column to sort by,
+/// which is `key` by default, prefixed with `-` for descending order.
+fn compile_group_by(
+    args: &[ast::Expr],
+    statements: &[ast::Statement],
+    scope: &mut Scope,
+) -> Result<Vec<Op>, CompileError> {
+    let (key, order) = match args {
+        [key] => (key, None),
+        [key, order] => (key, Some(order)),
+        _ => {
+            let msg = format!(
+                "group_by takes 1 or 2 arguments, but {} were given",
+                args.len()
+            );
+            return Err(CompileError { msg });
+        }
+    };
+    let key_comp = compile_expr(key, scope)?;
+    if key_comp.result_type == Type::Unit {
+        let msg = format!("Cannot group by {}", Type::Unit);
+        return Err(CompileError { msg });
+    }
+    let mut ops = key_comp.ops;
+    let mut columns: Vec<(String, Aggregate)> = vec![];
+    for stmt in statements {
+        let (name, function_call) = match stmt {
+            ast::Statement::FunctionCall { function_call } => {
+                (function_call.function_name.as_str(), function_call)
+            }
+            ast::Statement::Assignment {
+                variable,
+                key: None,
+                operator: None,
+                value:
+                    ast::Expr {
+                        arr_expr: ast::ArrExpr::FunctionExpr { function_call },
+                        index: None,
+                    },
+            } => (variable.as_str(), function_call),
+            _ => {
+                let msg = "group_by blocks may only contain aggregate functions, \
+                           e.g. count() or bytes = sum(_[2])"
+                    .to_string();
+                return Err(CompileError { msg });
+            }
+        };
+        let function_name = &function_call.function_name;
+        let function_name = function_name.strip_prefix("agg.").unwrap_or(function_name);
+        let (aggregate, comp) = compile_aggregate_arg(function_name, function_call, scope)?;
+        if name == "key" || columns.iter().any(|(column, _)| column == name) {
+            let msg = format!("Duplicate column {} in group_by", name);
+            return Err(CompileError { msg });
+        }
+        columns.push((name.to_string(), aggregate));
+        ops.extend(comp.ops);
+    }
+    let order = match order {
+        None => GroupOrder {
+            column: None,
+            descending: false,
+        },
+        Some(ast::Expr {
+            arr_expr:
+                ast::ArrExpr::ValueExpr {
+                    value: ast::Value::String(order),
+                },
+            index: None,
+        }) => {
+            let (name, descending) = match order.strip_prefix('-') {
+                Some(name) => (name, true),
+                None => (order.as_str(), false),
+            };
+            let column = match name {
+                "key" => None,
+                name => Some(
+                    columns
+                        .iter()
+                        .position(|(column, _)| column == name)
+                        .ok_or(CompileError {
+                            msg: format!("Cannot sort by {}, there is no such column", name),
+                        })?,
+                ),
+            };
+            GroupOrder { column, descending }
+        }
+        Some(_) => {
+            let msg = "The order of group_by must be a string literal".to_string();
+            return Err(CompileError { msg });
+        }
+    };
+    ops.push(Op::Group {
+        slot: scope.groups.len(),
+    });
+    scope.groups.push(Groups::new(columns, order));
+    Ok(ops)
+}
+
 #[derive(Debug)]
 pub struct RuntimeError {
     msg: String,
@@ -242,55 +1723,886 @@ impl Display for CompileError {
     }
 }
 
-#[derive(Debug, Clone)]
-enum Op {
-    Print,
-    LoadItem,
-    LoadIndex { index: i32 },
-    SplitStr,
+#[derive(Debug)]
+struct TypedComputation {
+    ops: Vec<Op>,
+    result_type: Type,
 }
 
-#[derive(Debug, Clone)]
-enum Value {
-    String(String),
-    List(Vec<String>),
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-impl Display for Value {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::String(s) => write!(f, "{}", s),
-            Value::List(ss) => ss.iter().try_for_each(|s| write!(f, "{}", s)),
+    fn run_all(source_code: &str, items: &[&str]) -> String {
+        let mut program = compile(source_code.to_string(), &Options::default()).unwrap();
+        let mut output = program.begin().unwrap();
+        for item in items {
+            if program.status() != Status::Running {
+                break;
+            }
+            output.push_str(&program.run(item).unwrap());
         }
+        output.push_str(&program.flush().unwrap());
+        for record in program.sorted().unwrap().into_iter().flatten() {
+            output.push_str(&record.unwrap());
+            output.push('\n');
+        }
+        output.push_str(&program.end().unwrap());
+        output
     }
-}
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Type {
-    Unit,
-    String,
-    List,
-}
+    #[test]
+    fn test_json_output() {
+        let options = Options {
+            output_mode: OutputMode::Json,
+            ..Options::default()
+        };
+        let source_code = r#"print(_); print(split(_)); print({"n": 1, "ok": true})"#;
+        let mut program = compile(source_code.to_string(), &options).unwrap();
+        let actual = program.run("a \"b\"").unwrap();
+        let expected = "\"a \\\"b\\\"\"\n[\"a\",\"\\\"b\\\"\"]\n{\"n\":1,\"ok\":true}\n";
+        assert_eq!(actual, expected);
+    }
 
-impl Display for Type {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Type::Unit => write!(f, "Unit"),
-            Type::String => write!(f, "String"),
-            Type::List => write!(f, "List"),
+    #[test]
+    fn test_fixed() {
+        let source_code = r#"
+            print(json(fixed(_, "2,*")));
+            print(json(fixed(_, "2,*", "none")));
+            spec = "1,1";
+            print(json(fixed(_, spec, "left")))
+        "#;
+        let actual = run_all(source_code, &["ab cd "]);
+        let expected = "[\"ab\",\"cd\"]\n[\"ab\",\" cd \"]\n[\"a\",\"b\"]\n";
+        assert_eq!(actual, expected);
+        // Literal columns and trim modes are checked when compiling
+        let compile_str = |s: &str| compile(s.to_string(), &Options::default());
+        assert!(compile_str(r#"print(fixed(_, "5,x"))"#).is_err());
+        assert!(compile_str(r#"print(fixed(_, "5", "bogus"))"#).is_err());
+        assert!(compile_str(r#"print(fixed(_))"#).is_err());
+        let mut program = compile_str(r#"spec = "5,x"; print(fixed(_, spec))"#).unwrap();
+        assert!(program.run("item").is_err());
+    }
+
+    #[test]
+    fn test_map_keeps_state_across_items() {
+        let source_code = r#"
+            if has(seen, _[0]) { print(_) } else { seen[_[0]] = _[1] }
+            end { for k in seen { print(k) } }
+        "#;
+        let actual = run_all(source_code, &["b 1", "a 2", "b 3"]);
+        assert_eq!(actual, "b 3\nb\na\n");
+    }
+
+    #[test]
+    fn test_map_literal_and_builtins() {
+        let source_code = r#"
+            m = {"x": "1", "y": {"z": "2"}};
+            delete(m, "x");
+            m["w"] = true;
+            print(json(keys(m)));
+            print(json(values(m)));
+            y = m["y"];
+            print(y["z"])
+        "#;
+        let actual = run_all(source_code, &["item"]);
+        assert_eq!(actual, "[\"y\",\"w\"]\n[{\"z\":\"2\"},true]\n2\n");
+    }
+
+    #[test]
+    fn test_map_containing_itself() {
+        let source_code = r#"
+            m = {"a": "1"};
+            m["self"] = m;
+            n = {"m": m};
+            m["n"] = n;
+            print(m);
+            print(format_kv(m));
+            print(json(m));
+            print(json({"x": n, "y": n}))
+        "#;
+        let actual = run_all(source_code, &["item"]);
+        let expected = [
+            "a=1 self=<cycle> n=\"m=<cycle>\"",
+            "a=1 self=<cycle> n=\"m=<cycle>\"",
+            r#"{"a":"1","self":null,"n":{"m":null}}"#,
+            r#"{"x":{"m":{"a":"1","self":null,"n":null}},"y":{"m":{"a":"1","self":null,"n":null}}}"#,
+        ];
+        assert_eq!(actual, expected.map(|line| format!("{}\n", line)).concat());
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let mut program = compile("print({}[\"a\"])".to_string(), &Options::default()).unwrap();
+        assert!(program.run("item").is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let source_code = r#"
+            print(1 + 2 * 3);
+            print((1 + 2) * 3);
+            print(7 / 2);
+            print(7 / 2.0);
+            print(-7 % 3);
+            print(0x10 + 1e1);
+            print(1 < 2 && !(2 < 1) || false)
+        "#;
+        let actual = run_all(source_code, &["item"]);
+        assert_eq!(actual, "7\n9\n3\n3.5\n-1\n26\ntrue\n");
+    }
+
+    #[test]
+    fn test_compound_assignment() {
+        let source_code = r#"
+            total += tonumber(_[1]);
+            count[_[0]] += 1;
+            end { print(total); print(count) }
+        "#;
+        let actual = run_all(source_code, &["a 1", "b 2.5", "a 3"]);
+        assert_eq!(actual, "6.5\na=2 b=1\n");
+    }
+
+    #[test]
+    fn test_tonumber() {
+        let source_code = "print(tonumber(_, -1) + 1)";
+        let actual = run_all(source_code, &["41", "x", "0x1f", "2.5"]);
+        assert_eq!(actual, "42\n0\n32\n3.5\n");
+        let mut program = compile("print(tonumber(_))".to_string(), &Options::default()).unwrap();
+        assert!(program.run("12abc").is_err());
+        let result = compile("print(tostring(1) + 1)".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_overflow_is_runtime_error() {
+        let source_code = "print(9223372036854775807 + tonumber(_))";
+        let mut program = compile(source_code.to_string(), &Options::default()).unwrap();
+        assert_eq!(program.run("0").unwrap(), "9223372036854775807\n");
+        assert!(program.run("1").is_err());
+        let source_code = "print(9223372036854775808)";
+        assert!(compile(source_code.to_string(), &Options::default()).is_err());
+    }
+
+    #[test]
+    fn test_chained_and_dynamic_index() {
+        let source_code = r#"
+            m = {"y": {"z": "2"}};
+            l = split("a b c");
+            i = 1;
+            print(m["y"]["z"]);
+            print(l[i + 1]);
+            print(_[i])
+        "#;
+        let actual = run_all(source_code, &["x y"]);
+        assert_eq!(actual, "2\nc\ny\n");
+    }
+
+    #[test]
+    fn test_decimal() {
+        let source_code = r#"
+            float_total += tonumber(_);
+            decimal_total += decimal(_);
+            end {
+                print(float_total);
+                print(decimal_total);
+                print(round(decimal_total / 7, 2, "half_up"));
+                print(decimal("x", decimal(0)) == 0)
+            }
+        "#;
+        let actual = run_all(source_code, &["0.1", "0.2"]);
+        assert_eq!(actual, "0.30000000000000004\n0.3\n0.04\ntrue\n");
+        let result = compile("print(decimal(_) + 0.1)".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        let options = Options {
+            number_mode: NumberMode::Decimal,
+            ..Options::default()
+        };
+        let source_code = "total += tonumber(_); end { print(total + 0.05) }";
+        let mut program = compile(source_code.to_string(), &options).unwrap();
+        for item in ["0.1", "0.2", "3"] {
+            program.run(item).unwrap();
         }
+        assert_eq!(program.end().unwrap(), "3.35\n");
     }
-}
 
-#[derive(Debug)]
-struct TypedFunction {
-    ops: Vec<Op>,
-    return_type: Type,
-    arg_types: Vec<Type>,
-}
+    #[test]
+    fn test_format() {
+        let source_code = r#"
+            printf("%-6s|%5.1f|%03d\n", _[0], tonumber(_[1]), 7);
+            s = format("%x", tonumber(_[2]));
+            print(s)
+        "#;
+        let actual = run_all(source_code, &["日本 2.25 255"]);
+        assert_eq!(actual, "日本  |  2.2|007\nff\n");
+    }
 
-#[derive(Debug)]
-struct TypedComputation {
-    ops: Vec<Op>,
-    result_type: Type,
+    #[test]
+    fn test_format_checked_at_compile_time() {
+        let compile_str = |s: &str| compile(s.to_string(), &Options::default());
+        assert!(compile_str(r#"printf("%d %d", 1)"#).is_err());
+        assert!(compile_str(r#"printf("%d", "a")"#).is_err());
+        assert!(compile_str(r#"printf("%q", 1)"#).is_err());
+        assert!(compile_str("printf()").is_err());
+        // Formats that aren't literals are checked at runtime
+        let mut program = compile_str("printf(_, 1.5)").unwrap();
+        assert_eq!(program.run("%.1f").unwrap(), "1.5");
+        assert!(program.run("%d").is_err());
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let source_code = r#"
+            name = trim(_);
+            print(upper(substr(name, 0, 1)));
+            print(pad_left(tostring(len(name)), 3, "0"));
+            print(join(reverse(split(replace(name, "ö", "oe"))), "-"));
+            print(index_of(name, "r") == 2 && contains(name, "ö") && starts_with(name, "Jö"))
+        "#;
+        let actual = run_all(source_code, &["  Jörg  Müller "]);
+        assert_eq!(actual, "J\n012\nMüller-Joerg\ntrue\n");
+    }
+
+    #[test]
+    fn test_regex() {
+        let source_code = r#"
+            if _ ~ /^\w+=/ { print(match(_, /(\w+)=(\d+)?/)) }
+            if _ !~ "=" { print(gsub(_, /(\w)(\w)/, "$2$1")) }
+            print(json(split(sub(_, /\s*,\s*/, "|"), /,\s*/)))
+        "#;
+        let actual = run_all(source_code, &["a=1, b", "ab,cd"]);
+        assert_eq!(actual, "a=1a1\n[\"a=1|b\"]\nba,dc\n[\"ab|cd\"]\n");
+        let result = compile("print(_ ~ /(/)".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("print(1 ~ /a/)".to_string(), &Options::default());
+        assert!(result.is_err());
+        for source_code in [
+            "print(match(_, 1))",
+            "print(sub(_, 1, \"x\"))",
+            "print(gsub(_, split(_), \"x\"))",
+            "print(split(_, {}))",
+        ] {
+            let result = compile(source_code.to_string(), &Options::default());
+            assert!(result.is_err(), "{} compiled", source_code);
+        }
+        // Regexes may be strings, and values of type Any are checked at runtime
+        let source_code = r#"m = {"re": "b+"}; print(sub(_, m["re"], "x")); print(split(_, ","))"#;
+        assert_eq!(run_all(source_code, &["abbc,d"]), "axc,d\nabbcd\n");
+        let mut program = compile(
+            r#"m = {"re": 1}; print(match(_, m["re"]))"#.to_string(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(program.run("a").is_err());
+    }
+
+    #[test]
+    fn test_regex_match_groups() {
+        let source_code = r#"print(json(match(_, /(\d+)-(x)?/)))"#;
+        let actual = run_all(source_code, &["id 12-", "none"]);
+        assert_eq!(actual, "[\"12-\",\"12\",\"\"]\n[]\n");
+    }
+
+    #[test]
+    fn test_time() {
+        let source_code = r#"
+            t = to_zone(parse_time(_), "Europe/Berlin");
+            printf("%s %s %d\n", format_time(t, "%d.%m.%Y %H:%M"), truncate_time(t, "hour"), epoch(t));
+            later = add_seconds(t, 90.5);
+            print(seconds_between(t, later));
+            print(t < later);
+        "#;
+        let actual = run_all(source_code, &["2024-07-01T10:15:30Z"]);
+        let expected = "01.07.2024 12:15 2024-07-01T12:00:00+02:00 1719828930\n90.5\ntrue\n";
+        assert_eq!(actual, expected);
+        let actual = run_all(
+            r#"print(parse_time(_, "%d/%b/%Y", "-05:00"))"#,
+            &["01/Mar/2024"],
+        );
+        assert_eq!(actual, "2024-03-01T00:00:00-05:00\n");
+        let result = compile(r#"print(epoch("2024"))"#.to_string(), &Options::default());
+        assert!(result.is_err());
+        let mut program = compile("print(parse_time(_))".to_string(), &Options::default()).unwrap();
+        assert!(program.run("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let source_code = r#"
+            requests = agg.count();
+            bytes = agg.sum(_[1]);
+            slowest = agg.max(tonumber(_[2]));
+            p50 = agg.median(_[2]);
+            end {
+                printf("%d %d %s %.1f\n", requests, bytes, slowest, p50);
+            }
+        "#;
+        let actual = run_all(source_code, &["/a 100 0.5", "/b 20 1.5", "/c 3 0.25"]);
+        assert_eq!(actual, "3 123 1.5 0.5\n");
+        let result = compile("print(agg.sum(true))".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("print(agg.count(_))".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("print(agg.p100(_))".to_string(), &Options::default());
+        assert!(result.is_err());
+        let mut program = compile("print(agg.sum(_))".to_string(), &Options::default()).unwrap();
+        assert!(program.run("abc").is_err());
+    }
+
+    #[test]
+    fn test_group_by() {
+        let source_code = r#"
+            group_by(_[0], "-requests") { requests = count(); bytes = sum(_[1]); max(_[1]); }
+            group_by(_[0]) { count(); }
+            end { print("done") }
+        "#;
+        let items = ["/a 10", "/b 5", "/a 20", "/c 1", "/b 7", "/a 1"];
+        let actual = run_all(source_code, &items);
+        let expected = "key=/a requests=3 bytes=31 max=20\n\
+                        key=/b requests=2 bytes=12 max=7\n\
+                        key=/c requests=1 bytes=1 max=1\n\
+                        key=/a count=3\nkey=/b count=2\nkey=/c count=1\ndone\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_group_by_errors() {
+        let compile_str = |s: &str| compile(s.to_string(), &Options::default());
+        assert!(compile_str("group_by(_) { print(_); }").is_err());
+        assert!(compile_str("group_by(_) { count(); count(); }").is_err());
+        assert!(compile_str("group_by(_) { key = count(); }").is_err());
+        assert!(compile_str(r#"group_by(_, "-total") { count(); }"#).is_err());
+        assert!(compile_str(r#"group_by(_, "-count") { count(); }"#).is_ok());
+    }
+
+    #[test]
+    fn test_sort_by() {
+        let source_code = r#"
+            sort_by(_, tonumber(_[1]), "-");
+            if _[0] == "b" { sort_by({"name": _[0]}, _[1], "-natural") }
+            end { print("done") }
+        "#;
+        let actual = run_all(source_code, &["a 9", "b 10", "c 9.5"]);
+        // Keys of different kinds are ordered by kind, numbers first, which
+        // descending order reverses
+        let expected = "name=b\nb 10\nc 9.5\na 9\ndone\n";
+        assert_eq!(actual, expected);
+        let options = Options {
+            sort_memory: Some(0),
+            ..Default::default()
+        };
+        let mut program = compile(r#"sort_by(_, _, "natural")"#.to_string(), &options).unwrap();
+        for item in ["x10", "x9", "x1"] {
+            program.run(item).unwrap();
+        }
+        let sorted: Vec<String> = program
+            .sorted()
+            .unwrap()
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(sorted, ["x1", "x9", "x10"]);
+        let result = compile(
+            r#"sort_by(_, _); sort_by(_, _, "-")"#.to_string(),
+            &Options::default(),
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_prev_and_window() {
+        let source_code = r#"
+            delta = tonumber(_) - tonumber(prev(), 0);
+            sum = 0.0;
+            for x in window(3) { sum += tonumber(x); }
+            printf("%s %s %.1f\n", delta, json(window(2)), sum / len(window(3)));
+            end { print(prev(2)) }
+        "#;
+        let actual = run_all(source_code, &["1", "4", "10", "12"]);
+        let expected = "1 [\"1\"] 1.0\n\
+                        3 [\"1\",\"4\"] 2.5\n\
+                        6 [\"4\",\"10\"] 5.0\n\
+                        2 [\"10\",\"12\"] 8.7\n\
+                        10\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_next() {
+        let source_code = r#"printf("%s>%s>%s\n", _, next(), next(2))"#;
+        let mut program = compile(source_code.to_string(), &Options::default()).unwrap();
+        assert_eq!(program.run("a").unwrap(), "");
+        assert_eq!(program.run("b").unwrap(), "");
+        assert_eq!(program.run("c").unwrap(), "a>b>c\n");
+        assert_eq!(program.flush().unwrap(), "b>c>\nc>>\n");
+        let result = compile("print(prev(0))".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("n = 2; print(window(n))".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_range() {
+        let items = [
+            "a",
+            "BEGIN",
+            "b",
+            "END",
+            "c",
+            "BEGIN END",
+            "d",
+            "BEGIN",
+            "e",
+        ];
+        let run_range = |bounds: &str| {
+            let source_code = format!(r#"range(/BEGIN/, _ ~ /END/, "{}") {{ print(_) }}"#, bounds);
+            run_all(&source_code, &items).replace('\n', "|")
+        };
+        assert_eq!(run_range("[]"), "BEGIN|b|END|BEGIN END|BEGIN|e|");
+        assert_eq!(run_range("()"), "b|e|");
+        assert_eq!(run_range("[)"), "BEGIN|b|BEGIN|e|");
+        assert_eq!(run_range("(]"), "b|END|e|");
+        // Each range keeps its own state
+        let source_code = r#"
+            range(/a/, /b/) { print(format("1:%s", _)) }
+            range(_ == "b", _ == "c") { print(format("2:%s", _)) }
+        "#;
+        let actual = run_all(source_code, &["a", "b", "c"]);
+        assert_eq!(actual, "1:a\n1:b\n2:b\n2:c\n");
+        let result = compile(
+            r#"range(/a/, /b/, "[[") {}"#.to_string(),
+            &Options::default(),
+        );
+        assert!(result.is_err());
+        let result = compile(r#"range(/a/, "b") {}"#.to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_next_statement() {
+        let source_code = r#"
+            if _ ~ /^#/ { next }
+            print(_);
+            end { print("end") }
+        "#;
+        let actual = run_all(source_code, &["a", "# comment", "b"]);
+        assert_eq!(actual, "a\nb\nend\n");
+        // next(n) is still the following item
+        let actual = run_all("print(next(1)); next;", &["a", "b"]);
+        assert_eq!(actual, "b\n\n");
+        let result = compile("begin { next }".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_exit() {
+        let source_code = r#"
+            if _ == "stop" { exit(3) }
+            print(_);
+            end { print("end") }
+        "#;
+        let mut program = compile(source_code.to_string(), &Options::default()).unwrap();
+        assert_eq!(program.run("a").unwrap(), "a\n");
+        assert_eq!(program.run("stop").unwrap(), "");
+        assert_eq!(program.status(), Status::Exit(3));
+        // Items after exit are ignored, but the end blocks are run
+        assert_eq!(program.run("b").unwrap(), "");
+        assert_eq!(program.end().unwrap(), "end\n");
+        // exit in an end block stops it
+        let source_code = "end { print(1); exit(); print(2) }";
+        assert_eq!(run_all(source_code, &["a"]), "1\n");
+        let actual = run_all("begin { exit(1) } print(_); end { print(\"end\") }", &["a"]);
+        assert_eq!(actual, "end\n");
+    }
+
+    #[test]
+    fn test_head() {
+        let source_code = "if _ ~ /x/ { print(_); head(2) }";
+        let mut program = compile(source_code.to_string(), &Options::default()).unwrap();
+        for item in ["a", "x1", "b", "x2"] {
+            assert_eq!(program.status(), Status::Running);
+            program.run(item).unwrap();
+        }
+        assert_eq!(program.status(), Status::Done);
+        assert_eq!(
+            run_all(source_code, &["a", "x1", "b", "x2", "x3"]),
+            "x1\nx2\n"
+        );
+        // Items that are held back for next are not run after the limit
+        let actual = run_all("print(next()); head(1)", &["a", "b", "c"]);
+        assert_eq!(actual, "b\n");
+        // With a limit of 0, not even the first item is printed
+        let actual = run_all("print(_); head(0); print(_)", &["a", "b"]);
+        assert_eq!(actual, "");
+        let actual = run_all(
+            "begin { print(1) } print(_); head(-1); end { print(2) }",
+            &["a"],
+        );
+        assert_eq!(actual, "1\n2\n");
+        // Within a loop, the item stops at the limit but keeps its output
+        let actual = run_all("for x in split(_) { print(x); head(2) }", &["a b c", "d"]);
+        assert_eq!(actual, "a\nb\n");
+        let actual = run_all("print(_); head(1); print(\"x\")", &["a", "b"]);
+        assert_eq!(actual, "a\n");
+        let result = compile("head(\"1\")".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        let compile_separator = |source_code: &str| {
+            compile(source_code.to_string(), &Options::default())
+                .map(|program| program.separator().cloned())
+        };
+        assert!(matches!(compile_separator("print(_)"), Ok(None)));
+        assert!(matches!(
+            compile_separator(r#"begin { records("paragraph") }"#),
+            Ok(Some(Separator::Paragraph))
+        ));
+        assert!(matches!(
+            compile_separator(r#"begin { records("\0") }"#),
+            Ok(Some(Separator::String(s))) if s == "\0"
+        ));
+        assert!(matches!(
+            compile_separator(r#"begin { records(/;\s*/) }"#),
+            Ok(Some(Separator::Regex(_)))
+        ));
+        assert!(compile_separator(r#"records("nul")"#).is_err());
+        assert!(compile_separator(r#"begin { records("nul"); records("nul") }"#).is_err());
+        assert!(compile_separator(r#"begin { records(_) }"#).is_err());
+        assert!(compile_separator(r#"begin { records(/a*/) }"#).is_err());
+    }
+
+    /// Runs a program on an input that it reads with `step`
+    fn step_all(source_code: &str, input: &str) -> String {
+        step_all_with(source_code, input, &Options::default())
+    }
+
+    fn step_all_with(source_code: &str, input: &str, options: &Options) -> String {
+        let mut program = compile(source_code.to_string(), options).unwrap();
+        let reader = Box::new(std::io::Cursor::new(input.to_string()));
+        program.set_input(reader, Separator::Line);
+        let mut output = program.begin().unwrap();
+        while let Some(out) = program.step() {
+            output.push_str(&out.unwrap());
+        }
+        output.push_str(&program.end().unwrap());
+        output
+    }
+
+    #[test]
+    fn test_step() {
+        assert_eq!(step_all("print(_)", "a\nb\n"), "a\nb\n");
+        assert_eq!(step_all("print(next())", "a\nb\n"), "b\n\n");
+        assert_eq!(step_all("print(_); head(1)", "a\nb\n"), "a\n");
+    }
+
+    #[test]
+    fn test_getline() {
+        // A header followed by two detail lines
+        let source_code = r#"
+            if _ == "H" {
+                getline(a);
+                if getline(b) == 1 { print(format("%s+%s", a, b)) }
+            } else {
+                print(_)
+            }
+            end { print(getline(c)) }
+        "#;
+        let actual = step_all(source_code, "x\nH\n1\n2\ny\nH\n3\n");
+        assert_eq!(actual, "x\n1+2\ny\n0\n");
+        // Items that have been read ahead are read first
+        let source_code = "if getline(line) == 1 { print(format(\"%s %s %s\", _, line, next())) }";
+        assert_eq!(step_all(source_code, "a\nb\nc\n"), "a b c\n");
+        let source_code = r#"begin { getline(first) } print(format("%s %s", first, _))"#;
+        assert_eq!(step_all(source_code, "a\nb\n"), "a b\n");
+
+        let path = std::env::temp_dir().join(format!("tt-getline-{}", std::process::id()));
+        std::fs::write(&path, "1\n2\n").unwrap();
+        let source_code = format!(
+            r#"n = getline(line, "{}"); print(format("%s %d %s", _, n, line))"#,
+            path.display()
+        );
+        let actual = step_all(&source_code, "a\nb\nc\n");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(actual, "a 1 1\nb 1 2\nc 0 2\n");
+        let actual = step_all(r#"print(getline(line, "/nonexistent"))"#, "a\n");
+        assert_eq!(actual, "-1\n");
+
+        let result = compile("getline(_)".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("x = 1; getline(x)".to_string(), &Options::default());
+        assert!(result.is_err());
+        let result = compile("getline_cmd(x)".to_string(), &Options::default());
+        assert!(result.is_err());
+
+        // The variable is declared wherever getline is used as an expression
+        let actual = step_all("group_by(getline(x)) { count() }", "a\nb\nc\n");
+        assert_eq!(actual, "key=0 count=1\nkey=1 count=1\n");
+        let options = Options {
+            split_by: Some("getline(y)".to_string()),
+            ..Default::default()
+        };
+        let mut program = compile("print(_)".to_string(), &options).unwrap();
+        let reader = Box::new(std::io::Cursor::new("a\nb\nc\n".to_string()));
+        program.set_input(reader, Separator::Line);
+        assert_eq!(program.step().unwrap().unwrap(), "a\n");
+        assert_eq!(program.split_key(), Some("1"));
+        assert_eq!(program.step().unwrap().unwrap(), "c\n");
+        assert_eq!(program.split_key(), Some("0"));
+
+        // A file read with getline continues across input files
+        let path = std::env::temp_dir().join(format!("tt-getline-files-{}", std::process::id()));
+        std::fs::write(&path, "1\n2\n3\n").unwrap();
+        let source_code = format!(
+            r#"getline(line, "{}"); print(format("%s%s", _, line))"#,
+            path.display()
+        );
+        let mut program = compile(source_code, &Options::default()).unwrap();
+        let mut actual = String::new();
+        for input in ["a\nb\n", "c\n"] {
+            let reader = Box::new(std::io::Cursor::new(input.to_string()));
+            program.set_input(reader, Separator::Line);
+            while let Some(output) = program.step() {
+                actual.push_str(&output.unwrap());
+            }
+        }
+        program.close().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(actual, "a1\nb2\nc3\n");
+    }
+
+    #[test]
+    fn test_exec() {
+        let options = Options {
+            allow_exec: true,
+            ..Default::default()
+        };
+        // The output of system keeps its place among printed values
+        let source_code = r#"print(1); print(system("echo 2; exit 3")); print(4)"#;
+        assert_eq!(step_all_with(source_code, "a\n", &options), "1\n2\n3\n4\n");
+        let source_code =
+            r#"print(exec("echo a; echo b")); print(exec_lines("printf 'c\nd\n'")[1])"#;
+        assert_eq!(step_all_with(source_code, "a\n", &options), "a\nb\nd\n");
+        let source_code = r#"getline_cmd(line, "printf 'x\ny\n'"); print(line)"#;
+        let actual = step_all_with(source_code, "a\nb\nc\n", &options);
+        assert_eq!(actual, "x\ny\ny\n");
+        for source_code in [
+            r#"system("true")"#,
+            r#"exec("true")"#,
+            r#"exec_lines("true")"#,
+            r#"getline_cmd(x, "true")"#,
+            r#"print_pipe("cat", _)"#,
+        ] {
+            let result = compile(source_code.to_string(), &Options::default());
+            assert!(result.is_err(), "{}", source_code);
+        }
+    }
+
+    /// A new directory for the files of a test, which is removed when it is
+    /// dropped, also if the test fails
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+            loop {
+                let n = NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                let name = format!("tt-{}-{}-{}", name, std::process::id(), n);
+                let path = std::env::temp_dir().join(name);
+                // Skips directories that an earlier run has left behind
+                match std::fs::create_dir(&path) {
+                    Ok(()) => return TempDir(path),
+                    Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                    Err(err) => panic!("Cannot create {}: {}", path.display(), err),
+                }
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_print_to() {
+        let temp_dir = TempDir::new("print-to");
+        let dir = &temp_dir.0;
+        let appended = dir.join("appended");
+        std::fs::write(&appended, "old\n").unwrap();
+        let source_code = format!(
+            r#"
+                print_to(format("{dir}/%s.log", _[0]), _[1]);
+                append_to("{dir}/appended", _[1]);
+                print_pipe("sort -r > {dir}/sorted", _[1]);
+            "#,
+            dir = dir.display()
+        );
+        let options = Options {
+            allow_exec: true,
+            ..Default::default()
+        };
+        let mut program = compile(source_code, &options).unwrap();
+        for item in ["info a", "error b", "info c"] {
+            assert_eq!(program.run(item).unwrap(), "");
+        }
+        program.close().unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("info.log"), "a\nc\n");
+        assert_eq!(read("error.log"), "b\n");
+        assert_eq!(read("appended"), "old\na\nb\nc\n");
+        assert_eq!(read("sorted"), "c\nb\na\n");
+
+        let mut program = compile(
+            r#"print_to("/nonexistent/file", _)"#.to_string(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(program.run("a").is_err());
+    }
+
+    #[test]
+    fn test_split_by() {
+        let options = Options {
+            split_by: Some("lower(level)".to_string()),
+            ..Default::default()
+        };
+        let mut program = compile("level = _[0]; print(_[1])".to_string(), &options).unwrap();
+        assert_eq!(program.split_key(), None);
+        assert_eq!(program.run("INFO a").unwrap(), "a\n");
+        assert_eq!(program.split_key(), Some("info"));
+        program.run("ERROR b").unwrap();
+        assert_eq!(program.split_key(), Some("error"));
+        let options = Options {
+            split_by: Some("_[0] _[1]".to_string()),
+            ..Default::default()
+        };
+        assert!(compile("print(_)".to_string(), &options).is_err());
+        let options = Options {
+            split_by: Some("print(_)".to_string()),
+            ..Default::default()
+        };
+        assert!(compile("print(_)".to_string(), &options).is_err());
+    }
+
+    #[test]
+    fn test_variables() {
+        let options = Options {
+            variables: vec![
+                ("limit".to_string(), "10".to_string()),
+                ("ratio".to_string(), "0.5".to_string()),
+                ("name".to_string(), "x".to_string()),
+            ],
+            assigned_variables: vec![("level".to_string(), "1".to_string())],
+            ..Default::default()
+        };
+        let source_code = r#"
+            begin { print(format("%d %.1f %s %d", limit, ratio, name, level)) }
+            if tonumber(_) > limit * ratio { print(format("%s %d", _, level)) }
+        "#;
+        let mut program = compile(source_code.to_string(), &options).unwrap();
+        assert_eq!(program.begin().unwrap(), "10 0.5 x 0\n");
+        assert_eq!(program.run("6").unwrap(), "6 0\n");
+        program.assign("level", "2").unwrap();
+        assert_eq!(program.run("7").unwrap(), "7 2\n");
+        assert_eq!(program.run("4").unwrap(), "");
+        assert!(program.assign("level", "x").is_err());
+        assert!(program.assign("limit", "1").is_err());
+        // The types of the variables are known to the compiler
+        let result = compile(r#"limit = "a""#.to_string(), &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_env() {
+        // PATH is set for the tests, unlike the other variable
+        let source_code = r#"
+            print(env("PATH") == env("PATH", "default"));
+            print(env("TT_TEST_UNSET_VARIABLE", "default"));
+            print(has(env(), "PATH"));
+        "#;
+        assert_eq!(run_all(source_code, &["a"]), "true\ndefault\ntrue\n");
+        let mut program = compile(
+            r#"print(env("TT_TEST_UNSET_VARIABLE"))"#.to_string(),
+            &Options::default(),
+        )
+        .unwrap();
+        assert!(program.run("a").is_err());
+    }
+
+    #[test]
+    fn test_parse() {
+        let source_code = r#"
+            m = parse(_, "%{IP:client} - - [%{TIMESTAMP:ts}] \"%{WORD:method} %{PATH:path}");
+            if len(m) > 0 { print(m) } else { print("no match") }
+        "#;
+        let line = r#"10.0.0.1 - - [10/Oct/2000:13:55:36 -0700] "GET /index.html HTTP/1.0" 200"#;
+        let actual = run_all(source_code, &[line, "garbage"]);
+        let expected = "client=10.0.0.1 ts=\"10/Oct/2000:13:55:36 -0700\" method=GET \
+                        path=/index.html\nno match\n";
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_parse_with_custom_patterns() {
+        let mut options = Options::default();
+        options.patterns.load("STATUS [1-5][0-9]{2}").unwrap();
+        let source_code = r#"p = "%{STATUS:status}"; print(parse(_, p)["status"])"#;
+        let mut program = compile(source_code.to_string(), &options).unwrap();
+        assert_eq!(program.run("HTTP 404").unwrap(), "404\n");
+        let result = compile(r#"parse(_, "%{NOPE}")"#.to_string(), &options);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_control_flow() {
+        let source_code = r#"
+            begin { first = true }
+            if first { print(_); first = false } else { last = _ }
+            end { for f in split(last) { print(f) } }
+        "#;
+        let actual = run_all(source_code, &["a", "b", "c d"]);
+        assert_eq!(actual, "a\nc\nd\n");
+    }
+
+    #[test]
+    fn test_variable_type_mismatch() {
+        let result = compile("x = _; x = split(_)".to_string(), &Options::default());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_compile_sources() {
+        let sources = |second: &str| {
+            vec![
+                ("lib.tt".to_string(), "begin { x = 1 }\n".to_string()),
+                ("-e".to_string(), second.to_string()),
+            ]
+        };
+        let mut program = compile_sources(&sources("print(x + 1)"), &Options::default()).unwrap();
+        program.begin().unwrap();
+        assert_eq!(program.run("a").unwrap(), "2\n");
+        // Statements don't continue into the next piece
+        let separate = [
+            ("a.tt".to_string(), "print(1)".to_string()),
+            ("b.tt".to_string(), "print(2)".to_string()),
+        ];
+        let mut program = compile_sources(&separate, &Options::default()).unwrap();
+        assert_eq!(program.run("a").unwrap(), "1\n2\n");
+
+        let error = |second: &str| match compile_sources(&sources(second), &Options::default()) {
+            Ok(_) => panic!("{:?} compiled", second),
+            Err(err) => err.to_string(),
+        };
+        assert!(error("\nprint(x, ").starts_with("-e:2: "));
+        assert!(error("print(1);\n\nprint(undefined)").starts_with("-e:3: "));
+        assert!(error("\"").starts_with("-e:1: "));
+        assert!(error("}").starts_with("-e:1: "));
+    }
 }
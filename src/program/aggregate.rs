@@ -0,0 +1,461 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use indexmap::IndexMap;
+
+use super::arithmetic::{self, Arithmetic, Comparison};
+use super::value::Value;
+use super::RuntimeError;
+
+// Aggregates accumulate the values of all items they have seen, e.g. the sum
+// of a field. Percentiles are exact up to EXACT_LIMIT values, after which the
+// values are moved to a sketch that takes a bounded amount of memory and has a
+// relative error of at most SKETCH_ACCURACY (a DDSketch, see
+// https://arxiv.org/abs/1908.10693).
+
+const EXACT_LIMIT: usize = 10_000;
+const SKETCH_ACCURACY: f64 = 0.01;
+const SKETCH_MAX_BINS: usize = 2048;
+/// Values closer to zero than this are counted as zero by the sketch
+const SKETCH_MIN_VALUE: f64 = 1e-9;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregate {
+    Count,
+    Sum,
+    Min,
+    Max,
+    Mean,
+    /// Between 0 and 100
+    Percentile(f64),
+}
+
+impl TryFrom<&str> for Aggregate {
+    type Error = String;
+
+    /// `median` is the 50th percentile, and e.g. `p95` or `p99.9` are
+    /// percentiles
+    fn try_from(name: &str) -> Result<Self, Self::Error> {
+        match name {
+            "count" => Ok(Aggregate::Count),
+            "sum" => Ok(Aggregate::Sum),
+            "min" => Ok(Aggregate::Min),
+            "max" => Ok(Aggregate::Max),
+            "mean" => Ok(Aggregate::Mean),
+            "median" => Ok(Aggregate::Percentile(50.0)),
+            _ => match name.strip_prefix('p').map(str::parse::<f64>) {
+                Some(Ok(p)) if p > 0.0 && p < 100.0 => Ok(Aggregate::Percentile(p)),
+                _ => Err(format!("Unknown aggregate function {}", name)),
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Aggregator {
+    Count(i64),
+    Sum(Value),
+    Min(Option<Value>),
+    Max(Option<Value>),
+    Mean { count: u64, mean: f64 },
+    Percentile { p: f64, values: Quantiles },
+}
+
+impl Aggregator {
+    pub fn new(aggregate: Aggregate) -> Aggregator {
+        match aggregate {
+            Aggregate::Count => Aggregator::Count(0),
+            Aggregate::Sum => Aggregator::Sum(Value::Int(0)),
+            Aggregate::Min => Aggregator::Min(None),
+            Aggregate::Max => Aggregator::Max(None),
+            Aggregate::Mean => Aggregator::Mean {
+                count: 0,
+                mean: 0.0,
+            },
+            Aggregate::Percentile(p) => Aggregator::Percentile {
+                p,
+                values: Quantiles::Exact(vec![]),
+            },
+        }
+    }
+
+    /// Values must be numbers, except for Count, which ignores them
+    pub fn add(&mut self, value: &Value) -> Result<(), RuntimeError> {
+        match self {
+            Aggregator::Count(count) => *count += 1,
+            Aggregator::Sum(sum) => *sum = arithmetic::apply(Arithmetic::Add, sum, value)?,
+            Aggregator::Min(min) => {
+                if min.is_none() || lower(value, min.as_ref().unwrap())? {
+                    *min = Some(value.clone());
+                }
+            }
+            Aggregator::Max(max) => {
+                if max.is_none() || lower(max.as_ref().unwrap(), value)? {
+                    *max = Some(value.clone());
+                }
+            }
+            Aggregator::Mean { count, mean } => {
+                // Updating the mean rather than dividing a sum by the count
+                // avoids large intermediate sums
+                *count += 1;
+                *mean += (arithmetic::to_f64(value) - *mean) / *count as f64;
+            }
+            Aggregator::Percentile { values, .. } => {
+                let x = arithmetic::to_f64(value);
+                if x.is_nan() {
+                    return Err(RuntimeError {
+                        msg: "Cannot compute a percentile of NaN".to_string(),
+                    });
+                }
+                values.add(x);
+            }
+        }
+        Ok(())
+    }
+
+    /// The aggregate of the values that have been added so far
+    pub fn value(&self) -> Value {
+        match self {
+            Aggregator::Count(count) => Value::Int(*count),
+            Aggregator::Sum(sum) => sum.clone(),
+            Aggregator::Min(v) | Aggregator::Max(v) => v.clone().unwrap_or(Value::Int(0)),
+            Aggregator::Mean { mean, .. } => Value::Float(*mean),
+            Aggregator::Percentile { p, values } => Value::Float(values.percentile(*p / 100.0)),
+        }
+    }
+}
+
+fn lower(a: &Value, b: &Value) -> Result<bool, RuntimeError> {
+    arithmetic::compare(Comparison::Lt, a, b)
+}
+
+/// The column that the rows of a group_by are sorted by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GroupOrder {
+    /// The key if None
+    pub column: Option<usize>,
+    pub descending: bool,
+}
+
+/// The aggregates of a group_by per key, in the order of the keys' first
+/// occurrence
+#[derive(Debug)]
+pub struct Groups {
+    columns: Vec<(String, Aggregate)>,
+    order: GroupOrder,
+    rows: IndexMap<String, Vec<Aggregator>>,
+}
+
+impl Groups {
+    pub fn new(columns: Vec<(String, Aggregate)>, order: GroupOrder) -> Groups {
+        Groups {
+            columns,
+            order,
+            rows: IndexMap::new(),
+        }
+    }
+
+    /// The number of values per item, i.e. the number of aggregates that
+    /// aren't counts
+    pub fn value_count(&self) -> usize {
+        self.columns
+            .iter()
+            .filter(|(_, aggregate)| *aggregate != Aggregate::Count)
+            .count()
+    }
+
+    pub fn add(&mut self, key: String, values: &[Value]) -> Result<(), RuntimeError> {
+        let aggregators = self.rows.entry(key).or_insert_with(|| {
+            self.columns
+                .iter()
+                .map(|(_, aggregate)| Aggregator::new(*aggregate))
+                .collect()
+        });
+        let mut values = values.iter();
+        for aggregator in aggregators {
+            match aggregator {
+                Aggregator::Count(_) => aggregator.add(&Value::Int(0))?,
+                _ => aggregator.add(values.next().unwrap())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// One map per key with the key as `key` and a value per column. Rows
+    /// that are equal in the sorted column are sorted by key.
+    pub fn rows(&self) -> Vec<IndexMap<String, Value>> {
+        let mut rows: Vec<(&String, Vec<Value>)> = self
+            .rows
+            .iter()
+            .map(|(key, aggregators)| (key, aggregators.iter().map(|a| a.value()).collect()))
+            .collect();
+        rows.sort_by(|(key_a, a), (key_b, b)| {
+            let ordering = match self.order.column {
+                None => key_a.cmp(key_b),
+                Some(column) => order(&a[column], &b[column]),
+            };
+            let ordering = match self.order.descending {
+                true => ordering.reverse(),
+                false => ordering,
+            };
+            ordering.then_with(|| key_a.cmp(key_b))
+        });
+        rows.into_iter()
+            .map(|(key, values)| {
+                let mut row = IndexMap::new();
+                row.insert("key".to_string(), Value::String(key.clone()));
+                for ((name, _), value) in self.columns.iter().zip(values) {
+                    row.insert(name.clone(), value);
+                }
+                row
+            })
+            .collect()
+    }
+}
+
+/// Values that can't be compared, e.g. NaN, are sorted as equal
+fn order(a: &Value, b: &Value) -> Ordering {
+    match (lower(a, b), lower(b, a)) {
+        (Ok(true), _) => Ordering::Less,
+        (_, Ok(true)) => Ordering::Greater,
+        _ => Ordering::Equal,
+    }
+}
+
+#[derive(Debug)]
+pub enum Quantiles {
+    /// Sorted
+    Exact(Vec<f64>),
+    Sketch(Sketch),
+}
+
+impl Quantiles {
+    fn add(&mut self, x: f64) {
+        match self {
+            Quantiles::Exact(values) if values.len() < EXACT_LIMIT => {
+                let i = values.partition_point(|v| *v <= x);
+                values.insert(i, x);
+            }
+            Quantiles::Exact(values) => {
+                let mut sketch = Sketch::new();
+                values.iter().for_each(|v| sketch.add(*v));
+                sketch.add(x);
+                *self = Quantiles::Sketch(sketch);
+            }
+            Quantiles::Sketch(sketch) => sketch.add(x),
+        }
+    }
+
+    /// Exact quantiles are interpolated linearly between the closest ranks.
+    /// `q` is between 0 and 1.
+    fn percentile(&self, q: f64) -> f64 {
+        match self {
+            Quantiles::Exact(values) if values.is_empty() => 0.0,
+            Quantiles::Exact(values) => {
+                let rank = q * (values.len() - 1) as f64;
+                let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+                values[lo] + (values[hi] - values[lo]) * (rank - lo as f64)
+            }
+            Quantiles::Sketch(sketch) => sketch.quantile(q),
+        }
+    }
+}
+
+/// Counts values in bins whose bounds grow exponentially, so that every value
+/// in a bin is within the relative accuracy of the bin's representative
+/// value. When there are too many bins, the bins closest to zero are merged.
+#[derive(Debug)]
+pub struct Sketch {
+    ln_gamma: f64,
+    positive: BTreeMap<i32, u64>,
+    /// Keyed by the magnitude of the values
+    negative: BTreeMap<i32, u64>,
+    zeros: u64,
+    count: u64,
+}
+
+impl Sketch {
+    fn new() -> Sketch {
+        let gamma = (1.0 + SKETCH_ACCURACY) / (1.0 - SKETCH_ACCURACY);
+        Sketch {
+            ln_gamma: gamma.ln(),
+            positive: BTreeMap::new(),
+            negative: BTreeMap::new(),
+            zeros: 0,
+            count: 0,
+        }
+    }
+
+    fn key(&self, magnitude: f64) -> i32 {
+        (magnitude.ln() / self.ln_gamma).ceil() as i32
+    }
+
+    fn bin_value(&self, key: i32) -> f64 {
+        let gamma = self.ln_gamma.exp();
+        2.0 * gamma.powi(key) / (gamma + 1.0)
+    }
+
+    fn add(&mut self, x: f64) {
+        self.count += 1;
+        if x.abs() < SKETCH_MIN_VALUE {
+            self.zeros += 1;
+            return;
+        }
+        let key = self.key(x.abs());
+        let bins = match x > 0.0 {
+            true => &mut self.positive,
+            false => &mut self.negative,
+        };
+        *bins.entry(key).or_default() += 1;
+        if self.positive.len() + self.negative.len() > SKETCH_MAX_BINS {
+            let bins = match self.positive.len() >= 2 {
+                true => &mut self.positive,
+                false => &mut self.negative,
+            };
+            let (_, lowest) = bins.pop_first().unwrap();
+            *bins.first_entry().unwrap().get_mut() += lowest;
+        }
+    }
+
+    fn quantile(&self, q: f64) -> f64 {
+        let rank = (q * (self.count - 1) as f64).round() as u64;
+        let mut seen = 0;
+        for (key, count) in self.negative.iter().rev() {
+            seen += count;
+            if seen > rank {
+                return -self.bin_value(*key);
+            }
+        }
+        seen += self.zeros;
+        if seen > rank {
+            return 0.0;
+        }
+        for (key, count) in &self.positive {
+            seen += count;
+            if seen > rank {
+                return self.bin_value(*key);
+            }
+        }
+        self.positive
+            .last_key_value()
+            .map(|(key, _)| self.bin_value(*key))
+            .unwrap_or(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::value::format_kv;
+    use super::*;
+
+    fn aggregate(name: &str, values: &[Value]) -> Value {
+        let mut aggregator = Aggregator::new(Aggregate::try_from(name).unwrap());
+        values.iter().for_each(|v| aggregator.add(v).unwrap());
+        aggregator.value()
+    }
+
+    fn ints(values: &[i64]) -> Vec<Value> {
+        values.iter().map(|i| Value::Int(*i)).collect()
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(Aggregate::try_from("p95"), Ok(Aggregate::Percentile(95.0)));
+        assert_eq!(
+            Aggregate::try_from("p99.9"),
+            Ok(Aggregate::Percentile(99.9))
+        );
+        assert_eq!(
+            Aggregate::try_from("median"),
+            Ok(Aggregate::Percentile(50.0))
+        );
+        assert!(Aggregate::try_from("p100").is_err());
+        assert!(Aggregate::try_from("px").is_err());
+        assert!(Aggregate::try_from("avg").is_err());
+    }
+
+    #[test]
+    fn test_aggregates() {
+        let values = ints(&[3, 1, 4, 1, 5]);
+        assert_eq!(aggregate("count", &values).to_string(), "5");
+        assert_eq!(aggregate("sum", &values).to_string(), "14");
+        assert_eq!(aggregate("min", &values).to_string(), "1");
+        assert_eq!(aggregate("max", &values).to_string(), "5");
+        assert_eq!(aggregate("mean", &values).to_string(), "2.8");
+        assert_eq!(aggregate("median", &values).to_string(), "3");
+        assert_eq!(aggregate("p25", &values).to_string(), "1");
+        assert_eq!(aggregate("p90", &values).to_string(), "4.6");
+        let mixed = vec![Value::Int(1), Value::Float(0.5)];
+        assert_eq!(aggregate("sum", &mixed).to_string(), "1.5");
+        assert_eq!(aggregate("min", &mixed).to_string(), "0.5");
+    }
+
+    #[test]
+    fn test_sum_overflow() {
+        let mut aggregator = Aggregator::new(Aggregate::Sum);
+        aggregator.add(&Value::Int(i64::MAX)).unwrap();
+        assert!(aggregator.add(&Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn test_groups() {
+        let columns = vec![
+            ("count".to_string(), Aggregate::Count),
+            ("bytes".to_string(), Aggregate::Sum),
+        ];
+        let order = GroupOrder {
+            column: Some(0),
+            descending: true,
+        };
+        let mut groups = Groups::new(columns, order);
+        assert_eq!(groups.value_count(), 1);
+        for (key, bytes) in [("b", 1), ("a", 2), ("c", 3), ("c", 4)] {
+            groups.add(key.to_string(), &[Value::Int(bytes)]).unwrap();
+        }
+        let rows: Vec<String> = groups.rows().iter().map(format_kv).collect();
+        assert_eq!(
+            rows,
+            [
+                "key=c count=2 bytes=7",
+                "key=a count=1 bytes=2",
+                "key=b count=1 bytes=1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sketch() {
+        let mut aggregator = Aggregator::new(Aggregate::Percentile(95.0));
+        for i in (-50_000..50_000).rev() {
+            aggregator.add(&Value::Int(i)).unwrap();
+        }
+        assert!(matches!(
+            aggregator,
+            Aggregator::Percentile {
+                values: Quantiles::Sketch(_),
+                ..
+            }
+        ));
+        let Value::Float(p95) = aggregator.value() else {
+            panic!("Expected Float");
+        };
+        assert!(
+            (p95 - 45_000.0).abs() <= 45_000.0 * SKETCH_ACCURACY,
+            "{}",
+            p95
+        );
+    }
+
+    #[test]
+    fn test_sketch_is_bounded() {
+        let mut sketch = Sketch::new();
+        for i in 0..100_000 {
+            sketch.add(1.001_f64.powi(i) * 1e-8);
+            sketch.add(-(i as f64));
+        }
+        assert!(sketch.positive.len() + sketch.negative.len() <= SKETCH_MAX_BINS);
+        let max = sketch.quantile(1.0);
+        let expected = 1.001_f64.powi(99_999) * 1e-8;
+        assert!((max - expected).abs() <= expected * SKETCH_ACCURACY);
+    }
+}
@@ -0,0 +1,470 @@
+use std::cmp::Ordering;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use super::value::{Type, Value};
+use super::{NumberMode, RuntimeError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arithmetic {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+/// How a number is rounded to a given number of decimal places
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    /// Rounds to the nearest number, and to the even one if both are equally
+    /// near, which avoids a bias when summing up rounded numbers
+    #[default]
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    /// Towards zero
+    Down,
+    /// Away from zero
+    Up,
+    Floor,
+    Ceiling,
+}
+
+impl TryFrom<&str> for Rounding {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "half_even" => Ok(Rounding::HalfEven),
+            "half_up" => Ok(Rounding::HalfUp),
+            "half_down" => Ok(Rounding::HalfDown),
+            "down" => Ok(Rounding::Down),
+            "up" => Ok(Rounding::Up),
+            "floor" => Ok(Rounding::Floor),
+            "ceiling" => Ok(Rounding::Ceiling),
+            other => Err(format!(
+                "Unknown rounding mode '{}', expected half_even, half_up, half_down, down, up, \
+                 floor or ceiling",
+                other
+            )),
+        }
+    }
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(rounding: Rounding) -> Self {
+        match rounding {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Floor => RoundingStrategy::ToNegativeInfinity,
+            Rounding::Ceiling => RoundingStrategy::ToPositiveInfinity,
+        }
+    }
+}
+
+fn is_number(t: &Type) -> bool {
+    matches!(t, Type::Int | Type::Float | Type::Decimal)
+}
+
+/// The type of an arithmetic expression, or None if the operands can't be
+/// used in arithmetic. Integers are converted to floats or decimals if the
+/// other operand is one. Floats and decimals can't be mixed, since that would
+/// lose the exactness of the decimal.
+pub fn arithmetic_type(lhs: &Type, rhs: &Type) -> Option<Type> {
+    match (lhs, rhs) {
+        (Type::Int, Type::Int) => Some(Type::Int),
+        (Type::Int | Type::Float, Type::Int | Type::Float) => Some(Type::Float),
+        (Type::Int | Type::Decimal, Type::Int | Type::Decimal) => Some(Type::Decimal),
+        (Type::Any, Type::Any) => Some(Type::Any),
+        (Type::Any, t) | (t, Type::Any) if is_number(t) => Some(Type::Any),
+        _ => None,
+    }
+}
+
+/// Whether values of the given types can be compared. Numbers are compared
+/// with numbers and strings with strings; bools can only be tested for
+/// equality.
+pub fn comparable(operator: Comparison, lhs: &Type, rhs: &Type) -> bool {
+    match (lhs, rhs) {
+        (Type::Any, _) | (_, Type::Any) => true,
+        (lhs, rhs) if is_number(lhs) && is_number(rhs) => true,
+        (Type::String, Type::String) | (Type::Time, Type::Time) => true,
+        (Type::Bool, Type::Bool) => matches!(operator, Comparison::Eq | Comparison::NotEq),
+        _ => false,
+    }
+}
+
+/// Integer arithmetic is checked, so that overflows and divisions by zero are
+/// reported instead of silently producing wrong results
+pub fn apply(operator: Arithmetic, lhs: &Value, rhs: &Value) -> Result<Value, RuntimeError> {
+    match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => apply_int(operator, *a, *b).map(Value::Int),
+        (Value::Int(_) | Value::Float(_), Value::Int(_) | Value::Float(_)) => {
+            let (a, b) = (to_f64(lhs), to_f64(rhs));
+            Ok(Value::Float(match operator {
+                Arithmetic::Add => a + b,
+                Arithmetic::Sub => a - b,
+                Arithmetic::Mul => a * b,
+                Arithmetic::Div => a / b,
+                Arithmetic::Rem => a % b,
+            }))
+        }
+        (Value::Int(_) | Value::Decimal(_), Value::Int(_) | Value::Decimal(_)) => {
+            let (a, b) = (to_decimal(lhs)?, to_decimal(rhs)?);
+            apply_decimal(operator, a, b).map(Value::Decimal)
+        }
+        _ => Err(RuntimeError {
+            msg: format!(
+                "Cannot apply {:?} to {} and {}",
+                operator,
+                lhs.get_type(),
+                rhs.get_type()
+            ),
+        }),
+    }
+}
+
+fn apply_int(operator: Arithmetic, a: i64, b: i64) -> Result<i64, RuntimeError> {
+    if b == 0 && matches!(operator, Arithmetic::Div | Arithmetic::Rem) {
+        return Err(RuntimeError {
+            msg: "Division by zero".to_string(),
+        });
+    }
+    let result = match operator {
+        Arithmetic::Add => a.checked_add(b),
+        Arithmetic::Sub => a.checked_sub(b),
+        Arithmetic::Mul => a.checked_mul(b),
+        Arithmetic::Div => a.checked_div(b),
+        Arithmetic::Rem => a.checked_rem(b),
+    };
+    result.ok_or(RuntimeError {
+        msg: format!("Integer overflow in {:?} of {} and {}", operator, a, b),
+    })
+}
+
+fn apply_decimal(operator: Arithmetic, a: Decimal, b: Decimal) -> Result<Decimal, RuntimeError> {
+    if b.is_zero() && matches!(operator, Arithmetic::Div | Arithmetic::Rem) {
+        return Err(RuntimeError {
+            msg: "Division by zero".to_string(),
+        });
+    }
+    // Division keeps as many digits as a decimal can hold, i.e. 28
+    let result = match operator {
+        Arithmetic::Add => a.checked_add(b),
+        Arithmetic::Sub => a.checked_sub(b),
+        Arithmetic::Mul => a.checked_mul(b),
+        Arithmetic::Div => a.checked_div(b),
+        Arithmetic::Rem => a.checked_rem(b),
+    };
+    result.ok_or(RuntimeError {
+        msg: format!("Decimal overflow in {:?} of {} and {}", operator, a, b),
+    })
+}
+
+pub fn negate(value: &Value) -> Result<Value, RuntimeError> {
+    match value {
+        Value::Int(i) => i.checked_neg().map(Value::Int).ok_or(RuntimeError {
+            msg: format!("Integer overflow in negation of {}", i),
+        }),
+        Value::Float(f) => Ok(Value::Float(-f)),
+        Value::Decimal(d) => Ok(Value::Decimal(-d)),
+        other => Err(RuntimeError {
+            msg: format!("Cannot negate {}", other.get_type()),
+        }),
+    }
+}
+
+pub fn compare(operator: Comparison, lhs: &Value, rhs: &Value) -> Result<bool, RuntimeError> {
+    let ordering = match (lhs, rhs) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Int(_) | Value::Decimal(_), Value::Int(_) | Value::Decimal(_)) => {
+            Some(to_decimal(lhs)?.cmp(&to_decimal(rhs)?))
+        }
+        (
+            Value::Int(_) | Value::Float(_) | Value::Decimal(_),
+            Value::Int(_) | Value::Float(_) | Value::Decimal(_),
+        ) => to_f64(lhs).partial_cmp(&to_f64(rhs)),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Time(a), Value::Time(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b))
+            if matches!(operator, Comparison::Eq | Comparison::NotEq) =>
+        {
+            Some(a.cmp(b))
+        }
+        _ => {
+            return Err(RuntimeError {
+                msg: format!("Cannot compare {} and {}", lhs.get_type(), rhs.get_type()),
+            })
+        }
+    };
+    // NaN is not equal to anything, not even itself
+    Ok(match operator {
+        Comparison::Eq => ordering == Some(Ordering::Equal),
+        Comparison::NotEq => ordering != Some(Ordering::Equal),
+        Comparison::Lt => ordering == Some(Ordering::Less),
+        Comparison::LtEq => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        Comparison::Gt => ordering == Some(Ordering::Greater),
+        Comparison::GtEq => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+    })
+}
+
+pub fn to_f64(value: &Value) -> f64 {
+    match value {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        Value::Decimal(d) => f64::try_from(*d).unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+/// Converts numbers and strings to decimals. Floats are converted to the
+/// decimal that is closest to them, e.g. 0.1 to 0.1 rather than
+/// 0.1000000000000000055511151231257827.
+pub fn to_decimal(value: &Value) -> Result<Decimal, RuntimeError> {
+    let result = match value {
+        Value::Int(i) => Ok(Decimal::from(*i)),
+        Value::Decimal(d) => Ok(*d),
+        Value::Float(f) => {
+            Decimal::try_from(*f).map_err(|_| format!("Cannot convert {} to a decimal", f))
+        }
+        Value::String(s) => parse_decimal(s.trim()),
+        other => Err(format!("Cannot convert {} to a decimal", other.get_type())),
+    };
+    result.map_err(|msg| RuntimeError { msg })
+}
+
+/// Rounds a number to the given number of decimal places. Decimals always
+/// have exactly that many places afterwards, e.g. 2.5 rounded to 2 places is
+/// 2.50, so that amounts of money are formatted consistently.
+pub fn round(value: &Value, places: i64, rounding: Rounding) -> Result<Value, RuntimeError> {
+    let places = u32::try_from(places)
+        .ok()
+        .filter(|p| *p <= Decimal::MAX_SCALE)
+        .ok_or(RuntimeError {
+            msg: format!(
+                "Decimal places must be between 0 and {}, got {}",
+                Decimal::MAX_SCALE,
+                places
+            ),
+        })?;
+    match value {
+        Value::Int(i) => Ok(Value::Int(*i)),
+        Value::Decimal(d) => {
+            let mut rounded = d.round_dp_with_strategy(places, rounding.into());
+            rounded.rescale(places);
+            Ok(Value::Decimal(rounded))
+        }
+        Value::Float(f) if !f.is_finite() => Ok(Value::Float(*f)),
+        Value::Float(_) => {
+            let rounded = to_decimal(value)?.round_dp_with_strategy(places, rounding.into());
+            Ok(Value::Float(f64::try_from(rounded).unwrap_or(f64::NAN)))
+        }
+        other => Err(RuntimeError {
+            msg: format!("Cannot round {}", other.get_type()),
+        }),
+    }
+}
+
+/// Parses decimal and hexadecimal integers, e.g. `-12` or `0x1F`, and floats,
+/// e.g. `1.5` or `2e-3`, which are decimals instead in decimal mode. Words like
+/// `inf` or `nan` are not numbers.
+pub fn parse_number(s: &str, mode: NumberMode) -> Result<Value, String> {
+    let (sign, digits) = match s.strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", s.strip_prefix('+').unwrap_or(s)),
+    };
+    let not_a_number = || format!("Not a number: '{}'", s);
+    if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        if hex.is_empty() || hex.starts_with(['+', '-']) {
+            return Err(not_a_number());
+        }
+        return i64::from_str_radix(&format!("{}{}", sign, hex), 16)
+            .map(Value::Int)
+            .map_err(|_| format!("Integer out of range: '{}'", s));
+    }
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return Err(not_a_number());
+    }
+    if digits.chars().all(|c| c.is_ascii_digit()) {
+        return format!("{}{}", sign, digits)
+            .parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| format!("Integer out of range: '{}'", s));
+    }
+    match mode {
+        NumberMode::Float => format!("{}{}", sign, digits)
+            .parse::<f64>()
+            .map(Value::Float)
+            .map_err(|_| not_a_number()),
+        NumberMode::Decimal => parse_decimal(s).map(Value::Decimal),
+    }
+}
+
+fn parse_decimal(s: &str) -> Result<Decimal, String> {
+    let digits = s.strip_prefix(['-', '+']).unwrap_or(s);
+    if !digits.starts_with(|c: char| c.is_ascii_digit() || c == '.') {
+        return Err(format!("Not a decimal: '{}'", s));
+    }
+    let result = match digits.contains(['e', 'E']) {
+        true => Decimal::from_scientific(s),
+        false => Decimal::from_str_exact(s),
+    };
+    result.map_err(|_| format!("Not a decimal: '{}'", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn int(v: Result<Value, impl std::fmt::Debug>) -> i64 {
+        match v.unwrap() {
+            Value::Int(i) => i,
+            other => panic!("Expected Int, got {:?}", other),
+        }
+    }
+
+    fn float(v: Result<Value, impl std::fmt::Debug>) -> f64 {
+        match v.unwrap() {
+            Value::Float(f) => f,
+            other => panic!("Expected Float, got {:?}", other),
+        }
+    }
+
+    fn parse_number_f(s: &str) -> Result<Value, String> {
+        parse_number(s, NumberMode::Float)
+    }
+
+    fn decimal(s: &str) -> Value {
+        Value::Decimal(parse_decimal(s).unwrap())
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(int(parse_number_f("42")), 42);
+        assert_eq!(int(parse_number_f("-42")), -42);
+        assert_eq!(int(parse_number_f("0x1F")), 31);
+        assert_eq!(int(parse_number_f("-0xff")), -255);
+        assert_eq!(float(parse_number_f("1.5")), 1.5);
+        assert_eq!(float(parse_number_f("2e3")), 2000.0);
+        assert_eq!(float(parse_number_f("1.5E-1")), 0.15);
+        assert_eq!(float(parse_number_f(".5")), 0.5);
+        assert!(parse_number_f("").is_err());
+        assert!(parse_number_f("abc").is_err());
+        assert!(parse_number_f("12abc").is_err());
+        assert!(parse_number_f("inf").is_err());
+        assert!(parse_number_f("0x").is_err());
+        assert!(parse_number_f("99999999999999999999").is_err());
+    }
+
+    #[test]
+    fn test_integer_arithmetic() {
+        let (a, b) = (Value::Int(7), Value::Int(2));
+        assert_eq!(int(apply(Arithmetic::Add, &a, &b)), 9);
+        assert_eq!(int(apply(Arithmetic::Sub, &a, &b)), 5);
+        assert_eq!(int(apply(Arithmetic::Mul, &a, &b)), 14);
+        assert_eq!(int(apply(Arithmetic::Div, &a, &b)), 3);
+        assert_eq!(int(apply(Arithmetic::Rem, &a, &b)), 1);
+    }
+
+    #[test]
+    fn test_mixed_arithmetic() {
+        let (a, b) = (Value::Int(7), Value::Float(2.0));
+        assert_eq!(float(apply(Arithmetic::Div, &a, &b)), 3.5);
+        assert!(float(apply(Arithmetic::Div, &b, &Value::Int(0))).is_infinite());
+    }
+
+    #[test]
+    fn test_overflow() {
+        let max = Value::Int(i64::MAX);
+        let min = Value::Int(i64::MIN);
+        assert!(apply(Arithmetic::Add, &max, &Value::Int(1)).is_err());
+        assert!(apply(Arithmetic::Mul, &max, &Value::Int(2)).is_err());
+        assert!(apply(Arithmetic::Div, &min, &Value::Int(-1)).is_err());
+        assert!(apply(Arithmetic::Rem, &Value::Int(1), &Value::Int(0)).is_err());
+        assert!(negate(&min).is_err());
+    }
+
+    #[test]
+    fn test_decimal_arithmetic() {
+        let sum = apply(Arithmetic::Add, &decimal("0.1"), &decimal("0.2")).unwrap();
+        assert_eq!(sum.to_string(), "0.3");
+        let product = apply(Arithmetic::Mul, &decimal("19.99"), &Value::Int(3)).unwrap();
+        assert_eq!(product.to_string(), "59.97");
+        let third = apply(Arithmetic::Div, &Value::Int(1), &decimal("3")).unwrap();
+        assert_eq!(third.to_string(), "0.3333333333333333333333333333");
+        assert!(apply(Arithmetic::Add, &decimal("1"), &Value::Float(1.0)).is_err());
+        assert!(apply(Arithmetic::Div, &decimal("1"), &decimal("0")).is_err());
+        let max = Value::Decimal(Decimal::MAX);
+        assert!(apply(Arithmetic::Add, &max, &Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn test_parse_decimal() {
+        let value = parse_number("2.50", NumberMode::Decimal).unwrap();
+        assert_eq!(value.to_string(), "2.50");
+        let value = parse_number("1.5e2", NumberMode::Decimal).unwrap();
+        assert_eq!(value.to_string(), "150");
+        assert!(matches!(
+            parse_number("2", NumberMode::Decimal),
+            Ok(Value::Int(2))
+        ));
+        assert!(parse_decimal("nan").is_err());
+        assert!(parse_decimal("1.2.3").is_err());
+        assert_eq!(to_decimal(&Value::Float(0.1)).unwrap().to_string(), "0.1");
+    }
+
+    #[test]
+    fn test_round() {
+        let round_str = |s: &str, places: i64, rounding: &str| {
+            let rounding = Rounding::try_from(rounding).unwrap();
+            round(&decimal(s), places, rounding).unwrap().to_string()
+        };
+        assert_eq!(round_str("2.345", 2, "half_even"), "2.34");
+        assert_eq!(round_str("2.345", 2, "half_up"), "2.35");
+        assert_eq!(round_str("2.345", 2, "half_down"), "2.34");
+        assert_eq!(round_str("-2.341", 2, "down"), "-2.34");
+        assert_eq!(round_str("-2.341", 2, "up"), "-2.35");
+        assert_eq!(round_str("-2.341", 2, "floor"), "-2.35");
+        assert_eq!(round_str("2.341", 2, "ceiling"), "2.35");
+        assert_eq!(round_str("2.5", 2, "half_even"), "2.50");
+        assert!(Rounding::try_from("nearest").is_err());
+        assert!(round(&decimal("1"), -1, Rounding::HalfEven).is_err());
+        let rounded = round(&Value::Float(2.675), 2, Rounding::HalfUp).unwrap();
+        assert_eq!(float(Ok::<_, RuntimeError>(rounded)), 2.68);
+    }
+
+    #[test]
+    fn test_compare() {
+        let one = Value::Int(1);
+        let one_and_half = Value::Float(1.5);
+        assert!(compare(Comparison::Lt, &one, &one_and_half).unwrap());
+        assert!(compare(Comparison::Eq, &one, &Value::Float(1.0)).unwrap());
+        let nan = Value::Float(f64::NAN);
+        assert!(!compare(Comparison::Eq, &nan, &nan).unwrap());
+        assert!(compare(Comparison::NotEq, &nan, &nan).unwrap());
+        let (a, b) = (Value::String("a".into()), Value::String("b".into()));
+        assert!(compare(Comparison::GtEq, &b, &a).unwrap());
+        assert!(compare(Comparison::Lt, &one, &a).is_err());
+        assert!(compare(Comparison::Lt, &Value::Bool(true), &Value::Bool(false)).is_err());
+        assert!(compare(Comparison::Eq, &decimal("1.50"), &decimal("1.5")).unwrap());
+        assert!(compare(Comparison::Lt, &decimal("1.5"), &Value::Int(2)).unwrap());
+        assert!(compare(Comparison::Gt, &decimal("1.5"), &Value::Float(1.25)).unwrap());
+    }
+}
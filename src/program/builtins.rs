@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+
+use super::ops::Op;
+use super::value::{Type, Value};
+use crate::fixed_width::Trim;
+
+/// A function may be overloaded with different numbers of arguments
+pub fn builtin_functions() -> HashMap<String, Vec<TypedFunction>> {
+    let mut map = HashMap::new();
+
+    map.insert(
+        "print".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Print],
+            return_type: Type::Unit,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "print_to".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::PrintTo { append: false }],
+            return_type: Type::Unit,
+            arg_types: vec![Type::String, Type::Any],
+        }],
+    );
+
+    map.insert(
+        "append_to".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::PrintTo { append: true }],
+            return_type: Type::Unit,
+            arg_types: vec![Type::String, Type::Any],
+        }],
+    );
+
+    map.insert(
+        "print_pipe".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::PrintPipe],
+            return_type: Type::Unit,
+            arg_types: vec![Type::String, Type::Any],
+        }],
+    );
+
+    map.insert(
+        "env".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::EnvVars],
+                return_type: Type::Map,
+                arg_types: vec![],
+            },
+            TypedFunction {
+                ops: vec![Op::Env {
+                    with_default: false,
+                }],
+                return_type: Type::String,
+                arg_types: vec![Type::String],
+            },
+            TypedFunction {
+                ops: vec![Op::Env { with_default: true }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::String],
+            },
+        ],
+    );
+
+    map.insert(
+        "system".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::System],
+            return_type: Type::Int,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "exec".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Exec { lines: false }],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "exec_lines".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Exec { lines: true }],
+            return_type: Type::List,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "exit".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![
+                    Op::LoadConst {
+                        value: Value::Int(0),
+                    },
+                    Op::Exit,
+                ],
+                return_type: Type::Unit,
+                arg_types: vec![],
+            },
+            TypedFunction {
+                ops: vec![Op::Exit],
+                return_type: Type::Unit,
+                arg_types: vec![Type::Int],
+            },
+        ],
+    );
+
+    map.insert(
+        "split".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::SplitStr],
+                return_type: Type::List,
+                arg_types: vec![Type::String],
+            },
+            TypedFunction {
+                ops: vec![Op::SplitRegex],
+                return_type: Type::List,
+                arg_types: vec![Type::String, Type::Regex],
+            },
+        ],
+    );
+
+    map.insert(
+        "match".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::MatchGroups],
+            return_type: Type::List,
+            arg_types: vec![Type::String, Type::Regex],
+        }],
+    );
+
+    map.insert(
+        "sub".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Substitute { all: false }],
+            return_type: Type::String,
+            arg_types: vec![Type::String, Type::Regex, Type::String],
+        }],
+    );
+
+    map.insert(
+        "gsub".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Substitute { all: true }],
+            return_type: Type::String,
+            arg_types: vec![Type::String, Type::Regex, Type::String],
+        }],
+    );
+
+    map.insert(
+        "parse_kv".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::ParseKv],
+            return_type: Type::Map,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "format_kv".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::FormatKv],
+            return_type: Type::String,
+            arg_types: vec![Type::Map],
+        }],
+    );
+
+    map.insert(
+        "fields".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::SplitFields],
+            return_type: Type::List,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "json".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::ToJson],
+            return_type: Type::String,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "tonumber".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::ToNumber {
+                    with_default: false,
+                }],
+                return_type: Type::Any,
+                arg_types: vec![Type::Any],
+            },
+            TypedFunction {
+                ops: vec![Op::ToNumber { with_default: true }],
+                return_type: Type::Any,
+                arg_types: vec![Type::Any, Type::Any],
+            },
+        ],
+    );
+
+    map.insert(
+        "decimal".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::ToDecimal {
+                    with_default: false,
+                }],
+                return_type: Type::Decimal,
+                arg_types: vec![Type::Any],
+            },
+            TypedFunction {
+                ops: vec![Op::ToDecimal { with_default: true }],
+                return_type: Type::Any,
+                arg_types: vec![Type::Any, Type::Any],
+            },
+        ],
+    );
+
+    map.insert(
+        "round".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::Round {
+                    with_rounding: false,
+                }],
+                return_type: Type::Any,
+                arg_types: vec![Type::Any, Type::Int],
+            },
+            TypedFunction {
+                ops: vec![Op::Round {
+                    with_rounding: true,
+                }],
+                return_type: Type::Any,
+                arg_types: vec![Type::Any, Type::Int, Type::String],
+            },
+        ],
+    );
+
+    map.insert(
+        "tostring".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::ToString],
+            return_type: Type::String,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "parse_time".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::ParseTime {
+                    with_format: false,
+                    with_zone: false,
+                }],
+                return_type: Type::Time,
+                arg_types: vec![Type::String],
+            },
+            TypedFunction {
+                ops: vec![Op::ParseTime {
+                    with_format: true,
+                    with_zone: false,
+                }],
+                return_type: Type::Time,
+                arg_types: vec![Type::String, Type::String],
+            },
+            TypedFunction {
+                ops: vec![Op::ParseTime {
+                    with_format: true,
+                    with_zone: true,
+                }],
+                return_type: Type::Time,
+                arg_types: vec![Type::String, Type::String, Type::String],
+            },
+        ],
+    );
+
+    map.insert(
+        "format_time".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::FormatTime],
+            return_type: Type::String,
+            arg_types: vec![Type::Time, Type::String],
+        }],
+    );
+
+    map.insert(
+        "from_epoch".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::FromEpoch],
+            return_type: Type::Time,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "epoch".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Epoch],
+            return_type: Type::Int,
+            arg_types: vec![Type::Time],
+        }],
+    );
+
+    map.insert(
+        "to_zone".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::ToZone],
+            return_type: Type::Time,
+            arg_types: vec![Type::Time, Type::String],
+        }],
+    );
+
+    map.insert(
+        "add_seconds".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::AddSeconds],
+            return_type: Type::Time,
+            arg_types: vec![Type::Time, Type::Any],
+        }],
+    );
+
+    map.insert(
+        "seconds_between".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::SecondsBetween],
+            return_type: Type::Float,
+            arg_types: vec![Type::Time, Type::Time],
+        }],
+    );
+
+    map.insert(
+        "truncate_time".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::TruncateTime],
+            return_type: Type::Time,
+            arg_types: vec![Type::Time, Type::String],
+        }],
+    );
+
+    map.insert(
+        "now".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Now],
+            return_type: Type::Time,
+            arg_types: vec![],
+        }],
+    );
+
+    map.insert(
+        "len".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Len],
+            return_type: Type::Int,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "upper".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Upper],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "lower".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Lower],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "trim".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Trim { trim: Trim::Both }],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "ltrim".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Trim { trim: Trim::Left }],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "rtrim".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Trim { trim: Trim::Right }],
+            return_type: Type::String,
+            arg_types: vec![Type::String],
+        }],
+    );
+
+    map.insert(
+        "substr".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::Substr { with_length: false }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int],
+            },
+            TypedFunction {
+                ops: vec![Op::Substr { with_length: true }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int, Type::Int],
+            },
+        ],
+    );
+
+    map.insert(
+        "replace".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Replace],
+            return_type: Type::String,
+            arg_types: vec![Type::String, Type::String, Type::String],
+        }],
+    );
+
+    map.insert(
+        "starts_with".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::StartsWith],
+            return_type: Type::Bool,
+            arg_types: vec![Type::String, Type::String],
+        }],
+    );
+
+    map.insert(
+        "ends_with".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::EndsWith],
+            return_type: Type::Bool,
+            arg_types: vec![Type::String, Type::String],
+        }],
+    );
+
+    map.insert(
+        "contains".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Contains],
+            return_type: Type::Bool,
+            arg_types: vec![Type::String, Type::String],
+        }],
+    );
+
+    map.insert(
+        "index_of".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::IndexOf],
+            return_type: Type::Int,
+            arg_types: vec![Type::String, Type::String],
+        }],
+    );
+
+    map.insert(
+        "repeat".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Repeat],
+            return_type: Type::String,
+            arg_types: vec![Type::String, Type::Int],
+        }],
+    );
+
+    map.insert(
+        "pad_left".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::Pad {
+                    left: true,
+                    with_fill: false,
+                }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int],
+            },
+            TypedFunction {
+                ops: vec![Op::Pad {
+                    left: true,
+                    with_fill: true,
+                }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int, Type::String],
+            },
+        ],
+    );
+
+    map.insert(
+        "pad_right".to_string(),
+        vec![
+            TypedFunction {
+                ops: vec![Op::Pad {
+                    left: false,
+                    with_fill: false,
+                }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int],
+            },
+            TypedFunction {
+                ops: vec![Op::Pad {
+                    left: false,
+                    with_fill: true,
+                }],
+                return_type: Type::String,
+                arg_types: vec![Type::String, Type::Int, Type::String],
+            },
+        ],
+    );
+
+    map.insert(
+        "join".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Join],
+            return_type: Type::String,
+            arg_types: vec![Type::List, Type::String],
+        }],
+    );
+
+    map.insert(
+        "reverse".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Reverse],
+            return_type: Type::Any,
+            arg_types: vec![Type::Any],
+        }],
+    );
+
+    map.insert(
+        "keys".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Keys],
+            return_type: Type::List,
+            arg_types: vec![Type::Map],
+        }],
+    );
+
+    map.insert(
+        "values".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Values],
+            return_type: Type::List,
+            arg_types: vec![Type::Map],
+        }],
+    );
+
+    map.insert(
+        "has".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Has],
+            return_type: Type::Bool,
+            arg_types: vec![Type::Map, Type::String],
+        }],
+    );
+
+    map.insert(
+        "delete".to_string(),
+        vec![TypedFunction {
+            ops: vec![Op::Delete],
+            return_type: Type::Unit,
+            arg_types: vec![Type::Map, Type::String],
+        }],
+    );
+
+    map
+}
+
+#[derive(Debug)]
+pub struct TypedFunction {
+    pub ops: Vec<Op>,
+    pub return_type: Type,
+    pub arg_types: Vec<Type>,
+}
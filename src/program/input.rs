@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, Command, Stdio};
+
+use crate::records::{Records, Separator};
+
+// The sources of items: the input of the program, and the files and commands
+// that `getline` reads from. Files and commands are opened on the first read
+// and kept open, so that each read continues where the previous one stopped.
+// All of them are split into items by the same separator.
+
+type Reader = Records<Box<dyn BufRead>>;
+
+#[derive(Default)]
+pub struct Input {
+    items: Option<Reader>,
+    separator: Separator,
+    files: HashMap<String, Reader>,
+    commands: HashMap<String, (Child, Reader)>,
+}
+
+impl Input {
+    /// Replaces the input, e.g. with the next input file. The files and
+    /// commands that getline reads from stay open.
+    pub fn set_items(&mut self, reader: Box<dyn BufRead>, separator: Separator) {
+        self.items = Some(Records::new(reader, separator.clone()));
+        self.separator = separator;
+    }
+
+    /// The next item of the input, or None at its end or if there is none
+    pub fn next_item(&mut self) -> io::Result<Option<String>> {
+        self.items.as_mut().and_then(Iterator::next).transpose()
+    }
+
+    /// The next item of a file
+    pub fn read_file(&mut self, path: &str) -> io::Result<Option<String>> {
+        if !self.files.contains_key(path) {
+            let file = fs::File::open(path)?;
+            let reader: Box<dyn BufRead> = Box::new(BufReader::new(file));
+            let records = Records::new(reader, self.separator.clone());
+            self.files.insert(path.to_string(), records);
+        }
+        let records = self.files.get_mut(path).expect("the file is open");
+        records.next().transpose()
+    }
+
+    /// The next item of the output of a shell command. The command is waited
+    /// for once its output has been read.
+    pub fn read_command(&mut self, command: &str) -> io::Result<Option<String>> {
+        if !self.commands.contains_key(command) {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                // The input of the program may be stdin as well
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .spawn()?;
+            let stdout = child.stdout.take().expect("stdout is piped");
+            let reader: Box<dyn BufRead> = Box::new(BufReader::new(stdout));
+            let records = Records::new(reader, self.separator.clone());
+            self.commands.insert(command.to_string(), (child, records));
+        }
+        let (child, records) = self.commands.get_mut(command).expect("the command runs");
+        let item = records.next().transpose()?;
+        if item.is_none() {
+            child.wait()?;
+        }
+        Ok(item)
+    }
+
+    /// Closes the files and waits for the commands that are still running.
+    /// Their output is closed first, so that they don't block writing it.
+    pub fn close(&mut self) -> io::Result<()> {
+        self.files.clear();
+        for (_, (mut child, records)) in self.commands.drain() {
+            drop(records);
+            child.wait()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1143 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+use regex::Regex;
+
+use super::aggregate::{Aggregator, Groups};
+use super::arithmetic::{self, Arithmetic, Comparison, Rounding};
+use super::input::Input;
+use super::output::Outputs;
+use super::printf;
+use super::strings;
+use super::time::{self, Time, Zone};
+use super::value::{format_kv, Value};
+use super::{FieldSplitter, NumberMode, OutputMode, RuntimeError, Status};
+use crate::fixed_width::{Columns, Trim};
+use crate::grok::{self, Patterns};
+use crate::logfmt;
+use crate::sort::{Key, KeyKind, Sorter};
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Print,
+    /// Pops a value and prints it to a file, whose path is below it on the
+    /// stack
+    PrintTo {
+        append: bool,
+    },
+    /// Pops a value and prints it to the input of a shell command, which is
+    /// below it on the stack
+    PrintPipe,
+    /// Writes a string to the output as is, i.e. without a newline
+    Write,
+    Pop,
+    LoadItem,
+    LoadIndex {
+        index: i32,
+    },
+    /// Loads the element of a list by position or the value of a map by key
+    LoadKey,
+    LoadConst {
+        value: Value,
+    },
+    LoadVar {
+        slot: usize,
+    },
+    StoreVar {
+        slot: usize,
+    },
+    StoreKey,
+    /// Applies an operator to the value of a key and stores the result. If the
+    /// key doesn't exist, the operand is stored as is.
+    UpdateKey {
+        operator: Arithmetic,
+    },
+    MakeMap {
+        len: usize,
+    },
+    /// Jumps are relative to the position of the jump
+    Jump {
+        offset: isize,
+    },
+    JumpIfFalse {
+        offset: isize,
+    },
+    /// Pushes an iterator over the keys of a map or the elements of a list
+    IterStart,
+    /// Stores the next value of the innermost iterator in a variable, or drops
+    /// the iterator and jumps if it is exhausted
+    IterNext {
+        slot: usize,
+        offset: isize,
+    },
+    Arithmetic {
+        operator: Arithmetic,
+    },
+    Compare {
+        operator: Comparison,
+    },
+    Negate,
+    Not,
+    Match {
+        negate: bool,
+    },
+    /// Pushes a list of the whole match and the capture groups, or an empty
+    /// list if the regex doesn't match
+    MatchGroups,
+    /// Replaces the first or all matches of a regex
+    Substitute {
+        all: bool,
+    },
+    SplitRegex,
+    /// Compiles a grok pattern to a regex
+    Grok,
+    /// Pushes a map of the named capture groups of a regex, which is empty if
+    /// the regex doesn't match
+    NamedCaptures,
+    ToNumber {
+        with_default: bool,
+    },
+    ToDecimal {
+        with_default: bool,
+    },
+    Round {
+        with_rounding: bool,
+    },
+    ToString,
+    /// Adds a value to the aggregator in the slot, unless it is a count, and
+    /// pushes the aggregate
+    Aggregate {
+        slot: usize,
+        with_value: bool,
+    },
+    /// Adds the values for the aggregates of a group_by, which are above the
+    /// key on the stack
+    Group {
+        slot: usize,
+    },
+    PrintGroups {
+        slot: usize,
+    },
+    /// Pops the start and end conditions of a range, and pushes whether the
+    /// item is in the range
+    InRange {
+        slot: usize,
+        include_start: bool,
+        include_end: bool,
+    },
+    /// The nth previous item
+    Prev {
+        n: usize,
+    },
+    /// The last n items up to the current one
+    Window {
+        n: usize,
+    },
+    /// The nth following item
+    Next {
+        n: usize,
+    },
+    /// Stops executing the ops for the current item
+    Skip,
+    /// Pops the exit code, and stops executing ops and reading items
+    Exit,
+    /// Pops a limit, and stops reading items after the current one if this op
+    /// has been executed that many times
+    Head {
+        slot: usize,
+    },
+    /// Pops the name of an environment variable and pushes its value, or pops
+    /// a default value first, which is pushed if the variable isn't set
+    Env {
+        with_default: bool,
+    },
+    /// Pushes a map of all environment variables
+    EnvVars,
+    /// Pops a shell command, runs it and pushes its exit status. Its stdout is
+    /// written to the output, so that it keeps its place among printed values.
+    System,
+    /// Pops a shell command, runs it and pushes its stdout without trailing
+    /// newlines, or a list of its lines
+    Exec {
+        lines: bool,
+    },
+    /// Reads an item into a variable and pushes 1, or 0 at the end of the
+    /// source or -1 on errors, which leave the variable unchanged. Pops the
+    /// path or command, unless reading from the input.
+    Getline {
+        source: Source,
+        slot: usize,
+    },
+    /// Passes a record below its key on the stack to the sorter
+    SortBy {
+        kind: Option<KeyKind>,
+    },
+    /// Without a format, the common formats are detected. The zone applies to
+    /// formats without an offset.
+    ParseTime {
+        with_format: bool,
+        with_zone: bool,
+    },
+    FormatTime,
+    FromEpoch,
+    Epoch,
+    ToZone,
+    AddSeconds,
+    SecondsBetween,
+    TruncateTime,
+    Now,
+    /// Formats the given number of arguments with a format below them on the
+    /// stack
+    Format {
+        args: usize,
+    },
+    Len,
+    Upper,
+    Lower,
+    Trim {
+        trim: Trim,
+    },
+    Substr {
+        with_length: bool,
+    },
+    Replace,
+    StartsWith,
+    EndsWith,
+    Contains,
+    IndexOf,
+    Repeat,
+    Pad {
+        left: bool,
+        with_fill: bool,
+    },
+    Join,
+    Reverse,
+    SplitStr,
+    SplitFields,
+    /// Splits at columns that are known at compile time
+    SplitColumns {
+        columns: Columns,
+    },
+    /// Splits at columns that are only known at runtime
+    SplitFixed {
+        with_trim: bool,
+    },
+    ParseKv,
+    FormatKv,
+    ToJson,
+    Keys,
+    Values,
+    Has,
+    Delete,
+}
+
+/// Where `getline` reads from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Source {
+    Input,
+    File,
+    Command,
+}
+
+/// Everything the ops of a program can access apart from the stack. The
+/// environment is kept across items.
+pub struct Environment {
+    pub globals: Vec<Value>,
+    pub output_mode: OutputMode,
+    pub field_splitter: FieldSplitter,
+    pub number_mode: NumberMode,
+    pub patterns: Patterns,
+    pub aggregates: Vec<Aggregator>,
+    pub groups: Vec<Groups>,
+    /// Only if the program calls `sort_by`
+    pub sorter: Option<Sorter>,
+    /// The previous items, the most recent last
+    pub history: VecDeque<String>,
+    /// The items after the current one that have been read ahead
+    pub following: VecDeque<String>,
+    /// Whether each range statement is within its range
+    pub ranges: Vec<bool>,
+    /// How often each call of `head` has been executed
+    pub heads: Vec<i64>,
+    pub input: Input,
+    pub outputs: Outputs,
+    pub status: Status,
+}
+
+struct ProgramState {
+    output: String,
+    stack: Vec<Value>,
+    iterators: Vec<std::vec::IntoIter<Value>>,
+}
+
+impl ProgramState {
+    fn pop(&mut self) -> Result<Value, RuntimeError> {
+        self.stack.pop().ok_or(RuntimeError {
+            msg: "Empty stack".to_string(),
+        })
+    }
+
+    fn pop_string(&mut self) -> Result<String, RuntimeError> {
+        match self.pop()? {
+            Value::String(s) => Ok(s),
+            _ => Err(RuntimeError {
+                msg: "Expected String".to_string(),
+            }),
+        }
+    }
+
+    fn pop_map(&mut self) -> Result<Rc<RefCell<IndexMap<String, Value>>>, RuntimeError> {
+        match self.pop()? {
+            Value::Map(m) => Ok(m),
+            _ => Err(RuntimeError {
+                msg: "Expected Map".to_string(),
+            }),
+        }
+    }
+
+    /// Strings are compiled to regexes
+    fn pop_regex(&mut self) -> Result<Regex, RuntimeError> {
+        match self.pop()? {
+            Value::Regex(r) => Ok(r),
+            Value::String(s) => Regex::new(&s).map_err(|err| RuntimeError {
+                msg: format!("Invalid regex /{}/: {}", s, err),
+            }),
+            _ => Err(RuntimeError {
+                msg: "Expected Regex".to_string(),
+            }),
+        }
+    }
+
+    fn pop_int(&mut self) -> Result<i64, RuntimeError> {
+        match self.pop()? {
+            Value::Int(i) => Ok(i),
+            _ => Err(RuntimeError {
+                msg: "Expected Int".to_string(),
+            }),
+        }
+    }
+
+    fn pop_time(&mut self) -> Result<Time, RuntimeError> {
+        match self.pop()? {
+            Value::Time(t) => Ok(t),
+            _ => Err(RuntimeError {
+                msg: "Expected Time".to_string(),
+            }),
+        }
+    }
+
+    fn pop_seconds(&mut self) -> Result<f64, RuntimeError> {
+        match self.pop()? {
+            n @ (Value::Int(_) | Value::Float(_) | Value::Decimal(_)) => Ok(arithmetic::to_f64(&n)),
+            other => Err(RuntimeError {
+                msg: format!("Expected a number of seconds, got {}", other.get_type()),
+            }),
+        }
+    }
+
+    fn pop_bool(&mut self) -> Result<bool, RuntimeError> {
+        match self.pop()? {
+            Value::Bool(b) => Ok(b),
+            _ => Err(RuntimeError {
+                msg: "Expected Bool".to_string(),
+            }),
+        }
+    }
+}
+
+fn print(value: &Value, output_mode: OutputMode, output: &mut String) {
+    let line = match output_mode {
+        OutputMode::Text => value.to_string(),
+        OutputMode::Json => value.to_json(),
+    };
+    output.push_str(&line);
+    output.push('\n');
+}
+
+/// Runs a shell command, and returns its exit status, or -1 if it has been
+/// killed by a signal, and its stdout
+fn run_command(command: &str) -> Result<(i64, String), RuntimeError> {
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        // The input of the program may be stdin as well
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|err| RuntimeError {
+            msg: format!("Cannot run '{}': {}", command, err),
+        })?;
+    let stdout = String::from_utf8(output.stdout).map_err(|_| RuntimeError {
+        msg: format!("The output of '{}' is not valid UTF-8", command),
+    })?;
+    Ok((output.status.code().map_or(-1, i64::from), stdout))
+}
+
+/// Without a kind, numbers and times are sorted numerically, and any other
+/// value as a string
+fn sort_key(value: &Value, kind: Option<KeyKind>) -> Key {
+    match (value, kind) {
+        (Value::Int(_) | Value::Float(_) | Value::Decimal(_), None | Some(KeyKind::Numeric)) => {
+            Key::Number(arithmetic::to_f64(value))
+        }
+        (Value::Time(t), None | Some(KeyKind::Numeric)) => {
+            Key::Number(t.timestamp() as f64 + t.timestamp_subsec_nanos() as f64 / 1e9)
+        }
+        (value, kind) => Key::new(kind.unwrap_or_default(), &value.to_string()),
+    }
+}
+
+/// Strings are converted to numbers like by `tonumber`
+fn aggregate_value(value: Value, number_mode: NumberMode) -> Result<Value, RuntimeError> {
+    match value {
+        Value::String(s) => {
+            arithmetic::parse_number(s.trim(), number_mode).map_err(|msg| RuntimeError { msg })
+        }
+        n @ (Value::Int(_) | Value::Float(_) | Value::Decimal(_)) => Ok(n),
+        other => Err(RuntimeError {
+            msg: format!("Cannot aggregate {}", other.get_type()),
+        }),
+    }
+}
+
+pub fn execute(ops: &[Op], env: &mut Environment, input: &str) -> Result<String, RuntimeError> {
+    let mut program_state = ProgramState {
+        output: String::new(),
+        stack: Vec::new(),
+        iterators: Vec::new(),
+    };
+    let mut pc = 0;
+    while let Some(op) = ops.get(pc) {
+        let mut next_pc = pc + 1;
+        match op {
+            Op::Print => {
+                let val = program_state.pop()?;
+                print(&val, env.output_mode, &mut program_state.output);
+            }
+            Op::PrintTo { append } => {
+                let val = program_state.pop()?;
+                let path = program_state.pop_string()?;
+                let mut text = String::new();
+                print(&val, env.output_mode, &mut text);
+                env.outputs
+                    .write_file(&path, *append, &text)
+                    .map_err(|err| RuntimeError {
+                        msg: format!("Cannot write to {}: {}", path, err),
+                    })?;
+            }
+            Op::PrintPipe => {
+                let val = program_state.pop()?;
+                let command = program_state.pop_string()?;
+                let mut text = String::new();
+                print(&val, env.output_mode, &mut text);
+                env.outputs
+                    .write_pipe(&command, &text)
+                    .map_err(|err| RuntimeError {
+                        msg: format!("Cannot write to '{}': {}", command, err),
+                    })?;
+            }
+            Op::Write => {
+                let s = program_state.pop_string()?;
+                program_state.output.push_str(&s);
+            }
+            Op::Pop => {
+                program_state.pop()?;
+            }
+            Op::LoadItem => {
+                program_state.stack.push(Value::String(input.to_string()));
+            }
+            Op::LoadIndex { index } => {
+                let list = program_state.pop()?;
+                let elem = match list {
+                    Value::List(mut l) if (*index as usize) < l.len() => {
+                        Ok(l.swap_remove(*index as usize))
+                    }
+                    Value::List(_) => Err(RuntimeError {
+                        msg: "out of bounds".to_string(),
+                    }),
+                    _ => Err(RuntimeError {
+                        msg: "not a list".to_string(),
+                    }),
+                }?;
+                program_state.stack.push(elem);
+            }
+            Op::LoadKey => {
+                let key = program_state.pop()?;
+                let container = program_state.pop()?;
+                let elem = match (container, key) {
+                    (Value::Map(m), Value::String(key)) => {
+                        m.borrow().get(&key).cloned().ok_or(RuntimeError {
+                            msg: format!("key {} not found", key),
+                        })
+                    }
+                    (Value::List(mut l), Value::Int(index))
+                        if index >= 0 && (index as usize) < l.len() =>
+                    {
+                        Ok(l.swap_remove(index as usize))
+                    }
+                    (Value::List(_), Value::Int(_)) => Err(RuntimeError {
+                        msg: "out of bounds".to_string(),
+                    }),
+                    (container, key) => Err(RuntimeError {
+                        msg: format!(
+                            "Cannot index {} with {}",
+                            container.get_type(),
+                            key.get_type()
+                        ),
+                    }),
+                }?;
+                program_state.stack.push(elem);
+            }
+            Op::LoadConst { value } => {
+                program_state.stack.push(value.clone());
+            }
+            Op::LoadVar { slot } => {
+                program_state.stack.push(env.globals[*slot].clone());
+            }
+            Op::StoreVar { slot } => {
+                env.globals[*slot] = program_state.pop()?;
+            }
+            Op::StoreKey => {
+                let value = program_state.pop()?;
+                let key = program_state.pop_string()?;
+                program_state.pop_map()?.borrow_mut().insert(key, value);
+            }
+            Op::UpdateKey { operator } => {
+                let operand = program_state.pop()?;
+                let key = program_state.pop_string()?;
+                let m = program_state.pop_map()?;
+                let mut m = m.borrow_mut();
+                let value = match m.get(&key) {
+                    Some(current) => arithmetic::apply(*operator, current, &operand)?,
+                    None => operand,
+                };
+                m.insert(key, value);
+            }
+            Op::MakeMap { len } => {
+                let mut map = IndexMap::with_capacity(*len);
+                let entries = program_state
+                    .stack
+                    .split_off(program_state.stack.len() - 2 * len);
+                let mut entries = entries.into_iter();
+                while let (Some(key), Some(value)) = (entries.next(), entries.next()) {
+                    match key {
+                        Value::String(key) => map.insert(key, value),
+                        _ => {
+                            return Err(RuntimeError {
+                                msg: "Map keys must be strings".to_string(),
+                            })
+                        }
+                    };
+                }
+                program_state.stack.push(Value::new_map(map));
+            }
+            Op::Jump { offset } => {
+                next_pc = pc.saturating_add_signed(*offset);
+            }
+            Op::JumpIfFalse { offset } => {
+                if !program_state.pop_bool()? {
+                    next_pc = pc.saturating_add_signed(*offset);
+                }
+            }
+            Op::IterStart => {
+                let values = match program_state.pop()? {
+                    Value::List(l) => l,
+                    Value::Map(m) => m.borrow().keys().cloned().map(Value::String).collect(),
+                    _ => {
+                        return Err(RuntimeError {
+                            msg: "Expected List or Map".to_string(),
+                        })
+                    }
+                };
+                program_state.iterators.push(values.into_iter());
+            }
+            Op::IterNext { slot, offset } => {
+                let next = program_state.iterators.last_mut().and_then(|it| it.next());
+                match next {
+                    Some(value) => env.globals[*slot] = value,
+                    None => {
+                        program_state.iterators.pop();
+                        next_pc = pc.saturating_add_signed(*offset);
+                    }
+                }
+            }
+            Op::Arithmetic { operator } => {
+                let rhs = program_state.pop()?;
+                let lhs = program_state.pop()?;
+                let result = arithmetic::apply(*operator, &lhs, &rhs)?;
+                program_state.stack.push(result);
+            }
+            Op::Compare { operator } => {
+                let rhs = program_state.pop()?;
+                let lhs = program_state.pop()?;
+                let result = arithmetic::compare(*operator, &lhs, &rhs)?;
+                program_state.stack.push(Value::Bool(result));
+            }
+            Op::Negate => {
+                let val = program_state.pop()?;
+                program_state.stack.push(arithmetic::negate(&val)?);
+            }
+            Op::Not => {
+                let b = program_state.pop_bool()?;
+                program_state.stack.push(Value::Bool(!b));
+            }
+            Op::Match { negate } => {
+                let regex = program_state.pop_regex()?;
+                let s = program_state.pop_string()?;
+                program_state
+                    .stack
+                    .push(Value::Bool(regex.is_match(&s) != *negate));
+            }
+            Op::MatchGroups => {
+                let regex = program_state.pop_regex()?;
+                let s = program_state.pop_string()?;
+                let groups = match regex.captures(&s) {
+                    Some(captures) => captures
+                        .iter()
+                        .map(|group| {
+                            let group = group.map(|m| m.as_str()).unwrap_or_default();
+                            Value::String(group.to_string())
+                        })
+                        .collect(),
+                    None => vec![],
+                };
+                program_state.stack.push(Value::List(groups));
+            }
+            Op::Substitute { all } => {
+                let replacement = program_state.pop_string()?;
+                let regex = program_state.pop_regex()?;
+                let s = program_state.pop_string()?;
+                let result = match all {
+                    true => regex.replace_all(&s, replacement.as_str()),
+                    false => regex.replace(&s, replacement.as_str()),
+                };
+                program_state.stack.push(Value::String(result.into_owned()));
+            }
+            Op::SplitRegex => {
+                let regex = program_state.pop_regex()?;
+                let s = program_state.pop_string()?;
+                program_state.stack.push(Value::List(
+                    regex
+                        .split(&s)
+                        .map(|s| Value::String(s.to_string()))
+                        .collect(),
+                ));
+            }
+            Op::Grok => {
+                let regex = match program_state.pop()? {
+                    Value::String(pattern) => env
+                        .patterns
+                        .compile(&pattern)
+                        .map_err(|msg| RuntimeError { msg })?,
+                    Value::Regex(regex) => regex,
+                    other => {
+                        return Err(RuntimeError {
+                            msg: format!("Expected a pattern, got {}", other.get_type()),
+                        })
+                    }
+                };
+                program_state.stack.push(Value::Regex(regex));
+            }
+            Op::NamedCaptures => {
+                let regex = program_state.pop_regex()?;
+                let s = program_state.pop_string()?;
+                let fields = grok::captures(&regex, &s).unwrap_or_default();
+                program_state.stack.push(Value::new_map(
+                    fields
+                        .into_iter()
+                        .map(|(k, v)| (k, Value::String(v)))
+                        .collect(),
+                ));
+            }
+            Op::ToNumber { with_default } => {
+                let default = match with_default {
+                    true => Some(program_state.pop()?),
+                    false => None,
+                };
+                let number = match program_state.pop()? {
+                    Value::String(s) => arithmetic::parse_number(s.trim(), env.number_mode),
+                    n @ (Value::Int(_) | Value::Float(_) | Value::Decimal(_)) => Ok(n),
+                    other => Err(format!("Cannot convert {} to a number", other.get_type())),
+                };
+                let number = match (number, default) {
+                    (Ok(n), _) => n,
+                    (Err(_), Some(default)) => default,
+                    (Err(msg), None) => return Err(RuntimeError { msg }),
+                };
+                program_state.stack.push(number);
+            }
+            Op::ToDecimal { with_default } => {
+                let default = match with_default {
+                    true => Some(program_state.pop()?),
+                    false => None,
+                };
+                let decimal = arithmetic::to_decimal(&program_state.pop()?);
+                let decimal = match (decimal, default) {
+                    (Ok(d), _) => Value::Decimal(d),
+                    (Err(_), Some(default)) => default,
+                    (Err(err), None) => return Err(err),
+                };
+                program_state.stack.push(decimal);
+            }
+            Op::Round { with_rounding } => {
+                let rounding = match with_rounding {
+                    true => Rounding::try_from(program_state.pop_string()?.as_str())
+                        .map_err(|msg| RuntimeError { msg })?,
+                    false => Rounding::default(),
+                };
+                let places = program_state.pop_int()?;
+                let value = program_state.pop()?;
+                let rounded = arithmetic::round(&value, places, rounding)?;
+                program_state.stack.push(rounded);
+            }
+            Op::Aggregate { slot, with_value } => {
+                let value = match with_value {
+                    false => Value::Int(0),
+                    true => aggregate_value(program_state.pop()?, env.number_mode)?,
+                };
+                let aggregator = &mut env.aggregates[*slot];
+                aggregator.add(&value)?;
+                program_state.stack.push(aggregator.value());
+            }
+            Op::Group { slot } => {
+                let groups = &mut env.groups[*slot];
+                let len = program_state.stack.len();
+                let count = groups.value_count();
+                if len < count + 1 {
+                    return Err(RuntimeError {
+                        msg: "Empty stack".to_string(),
+                    });
+                }
+                let values = program_state
+                    .stack
+                    .split_off(len - count)
+                    .into_iter()
+                    .map(|value| aggregate_value(value, env.number_mode))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let key = program_state.pop()?.to_string();
+                groups.add(key, &values)?;
+            }
+            Op::InRange {
+                slot,
+                include_start,
+                include_end,
+            } => {
+                let end = program_state.pop_bool()?;
+                let start = program_state.pop_bool()?;
+                let active = &mut env.ranges[*slot];
+                let in_range = match (*active, start, end) {
+                    (false, false, _) => false,
+                    // A range of a single item
+                    (false, true, true) => *include_start && *include_end,
+                    (false, true, false) => {
+                        *active = true;
+                        *include_start
+                    }
+                    (true, _, true) => {
+                        *active = false;
+                        *include_end
+                    }
+                    (true, _, false) => true,
+                };
+                program_state.stack.push(Value::Bool(in_range));
+            }
+            Op::Prev { n } => {
+                let history = &env.history;
+                let item = match history.len().checked_sub(*n) {
+                    Some(i) => history[i].clone(),
+                    None => String::new(),
+                };
+                program_state.stack.push(Value::String(item));
+            }
+            Op::Window { n } => {
+                let previous = env.history.len().min(n - 1);
+                let window = env
+                    .history
+                    .range(env.history.len() - previous..)
+                    .map(|item| Value::String(item.clone()))
+                    .chain([Value::String(input.to_string())])
+                    .collect();
+                program_state.stack.push(Value::List(window));
+            }
+            Op::Next { n } => {
+                let item = env.following.get(n - 1).cloned().unwrap_or_default();
+                program_state.stack.push(Value::String(item));
+            }
+            Op::Skip => next_pc = ops.len(),
+            Op::Exit => {
+                let code = program_state.pop_int()?;
+                let code = i32::try_from(code).map_err(|_| RuntimeError {
+                    msg: format!("Invalid exit code {}", code),
+                })?;
+                env.status = Status::Exit(code);
+                next_pc = ops.len();
+            }
+            Op::Head { slot } => {
+                let limit = program_state.pop_int()?;
+                if env.heads[*slot] < limit {
+                    env.heads[*slot] += 1;
+                } else if env.heads[*slot] == 0 {
+                    // A limit of 0 or less: the current item is beyond the
+                    // limit as well, so it is dropped
+                    program_state.output.clear();
+                }
+                if env.heads[*slot] >= limit {
+                    next_pc = ops.len();
+                    if env.status == Status::Running {
+                        env.status = Status::Done;
+                    }
+                }
+            }
+            Op::Env { with_default } => {
+                let default = match with_default {
+                    true => Some(program_state.pop_string()?),
+                    false => None,
+                };
+                let name = program_state.pop_string()?;
+                let value = match (std::env::var(&name), default) {
+                    (Ok(value), _) => value,
+                    (Err(_), Some(default)) => default,
+                    (Err(std::env::VarError::NotPresent), None) => {
+                        let msg = format!("Environment variable {} is not set", name);
+                        return Err(RuntimeError { msg });
+                    }
+                    (Err(std::env::VarError::NotUnicode(_)), None) => {
+                        let msg = format!("Environment variable {} is not valid UTF-8", name);
+                        return Err(RuntimeError { msg });
+                    }
+                };
+                program_state.stack.push(Value::String(value));
+            }
+            Op::EnvVars => {
+                let mut vars: Vec<(String, Value)> = std::env::vars_os()
+                    .filter_map(|(name, value)| {
+                        let value = value.into_string().ok()?;
+                        Some((name.into_string().ok()?, Value::String(value)))
+                    })
+                    .collect();
+                vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+                program_state
+                    .stack
+                    .push(Value::new_map(vars.into_iter().collect()));
+            }
+            Op::System => {
+                let command = program_state.pop_string()?;
+                let (status, stdout) = run_command(&command)?;
+                program_state.output.push_str(&stdout);
+                program_state.stack.push(Value::Int(status));
+            }
+            Op::Exec { lines } => {
+                let command = program_state.pop_string()?;
+                let (_, stdout) = run_command(&command)?;
+                let value = match lines {
+                    true => Value::List(
+                        stdout
+                            .lines()
+                            .map(|l| Value::String(l.to_string()))
+                            .collect(),
+                    ),
+                    false => Value::String(stdout.trim_end_matches('\n').to_string()),
+                };
+                program_state.stack.push(value);
+            }
+            Op::Getline { source, slot } => {
+                let item = match source {
+                    // Items that have been read ahead come first, and another
+                    // one is read ahead instead, so that `next` still looks
+                    // as far ahead
+                    Source::Input => match env.following.pop_front() {
+                        Some(item) => {
+                            let ahead = env.input.next_item().map_err(|err| RuntimeError {
+                                msg: format!("Cannot read input: {}", err),
+                            })?;
+                            env.following.extend(ahead);
+                            Ok(Some(item))
+                        }
+                        None => env.input.next_item(),
+                    },
+                    Source::File => env.input.read_file(&program_state.pop_string()?),
+                    Source::Command => env.input.read_command(&program_state.pop_string()?),
+                };
+                let status = match item {
+                    Ok(Some(item)) => {
+                        env.globals[*slot] = Value::String(item);
+                        1
+                    }
+                    Ok(None) => 0,
+                    Err(_) => -1,
+                };
+                program_state.stack.push(Value::Int(status));
+            }
+            Op::SortBy { kind } => {
+                let key = sort_key(&program_state.pop()?, *kind);
+                let record = program_state.pop()?;
+                let record = match env.output_mode {
+                    OutputMode::Text => record.to_string(),
+                    OutputMode::Json => record.to_json(),
+                };
+                if let Some(sorter) = env.sorter.as_mut() {
+                    sorter.push(key, record).map_err(|err| RuntimeError {
+                        msg: format!("Cannot sort: {}", err),
+                    })?;
+                }
+            }
+            Op::PrintGroups { slot } => {
+                for row in env.groups[*slot].rows() {
+                    print(
+                        &Value::new_map(row),
+                        env.output_mode,
+                        &mut program_state.output,
+                    );
+                }
+            }
+            Op::ParseTime {
+                with_format,
+                with_zone,
+            } => {
+                let zone = match with_zone {
+                    true => Zone::try_from(program_state.pop_string()?.as_str())
+                        .map_err(|msg| RuntimeError { msg })?,
+                    false => Zone::try_from("UTC").unwrap(),
+                };
+                let format = match with_format {
+                    true => Some(program_state.pop_string()?),
+                    false => None,
+                };
+                let s = program_state.pop_string()?;
+                let parsed = match format {
+                    Some(format) => time::parse_with_format(&s, &format, &zone),
+                    None => time::parse(&s),
+                };
+                let t = parsed.map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::Time(t));
+            }
+            Op::FormatTime => {
+                let format = program_state.pop_string()?;
+                let t = program_state.pop_time()?;
+                let formatted = time::format(&t, &format).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::String(formatted));
+            }
+            Op::FromEpoch => {
+                let seconds = program_state.pop_seconds()?;
+                let t = time::from_epoch(seconds).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::Time(t));
+            }
+            Op::Epoch => {
+                let t = program_state.pop_time()?;
+                program_state.stack.push(Value::Int(time::epoch(&t)));
+            }
+            Op::ToZone => {
+                let zone = Zone::try_from(program_state.pop_string()?.as_str())
+                    .map_err(|msg| RuntimeError { msg })?;
+                let t = program_state.pop_time()?;
+                program_state
+                    .stack
+                    .push(Value::Time(time::to_zone(&t, &zone)));
+            }
+            Op::AddSeconds => {
+                let seconds = program_state.pop_seconds()?;
+                let t = program_state.pop_time()?;
+                let t = time::add_seconds(&t, seconds).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::Time(t));
+            }
+            Op::SecondsBetween => {
+                let end = program_state.pop_time()?;
+                let start = program_state.pop_time()?;
+                let seconds = time::seconds_between(&start, &end);
+                program_state.stack.push(Value::Float(seconds));
+            }
+            Op::TruncateTime => {
+                let unit = program_state.pop_string()?;
+                let t = program_state.pop_time()?;
+                let t = time::truncate(&t, &unit).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::Time(t));
+            }
+            Op::Now => {
+                program_state.stack.push(Value::Time(time::now()));
+            }
+            Op::Format { args } => {
+                let len = program_state.stack.len();
+                if len < args + 1 {
+                    return Err(RuntimeError {
+                        msg: "Empty stack".to_string(),
+                    });
+                }
+                let args = program_state.stack.split_off(len - args);
+                let format = program_state.pop_string()?;
+                let formatted = printf::Format::parse(&format)
+                    .and_then(|f| f.apply(&args))
+                    .map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::String(formatted));
+            }
+            Op::ToString => {
+                let val = program_state.pop()?;
+                program_state.stack.push(Value::String(val.to_string()));
+            }
+            Op::ParseKv => {
+                let s = program_state.pop_string()?;
+                let pairs = logfmt::parse(&s).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::new_map(
+                    pairs
+                        .into_iter()
+                        .map(|(k, v)| (k, Value::String(v)))
+                        .collect(),
+                ));
+            }
+            Op::FormatKv => {
+                let m = program_state.pop_map()?;
+                let formatted = format_kv(&m.borrow());
+                program_state.stack.push(Value::String(formatted));
+            }
+            Op::ToJson => {
+                let val = program_state.pop()?;
+                program_state.stack.push(Value::String(val.to_json()));
+            }
+            Op::Len => {
+                let len =
+                    strings::len(&program_state.pop()?).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::Int(len));
+            }
+            Op::Upper | Op::Lower | Op::Trim { .. } => {
+                let s = program_state.pop_string()?;
+                let result = match op {
+                    Op::Upper => s.to_uppercase(),
+                    Op::Lower => s.to_lowercase(),
+                    Op::Trim { trim } => trim.apply(&s),
+                    _ => unreachable!(),
+                };
+                program_state.stack.push(Value::String(result));
+            }
+            Op::Substr { with_length } => {
+                let length = match with_length {
+                    true => Some(program_state.pop_int()?),
+                    false => None,
+                };
+                let start = program_state.pop_int()?;
+                let s = program_state.pop_string()?;
+                let result =
+                    strings::substr(&s, start, length).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::String(result));
+            }
+            Op::Replace => {
+                let to = program_state.pop_string()?;
+                let from = program_state.pop_string()?;
+                let s = program_state.pop_string()?;
+                program_state
+                    .stack
+                    .push(Value::String(s.replace(&from, &to)));
+            }
+            Op::StartsWith | Op::EndsWith | Op::Contains | Op::IndexOf => {
+                let sub = program_state.pop_string()?;
+                let s = program_state.pop_string()?;
+                let result = match op {
+                    Op::StartsWith => Value::Bool(s.starts_with(&sub)),
+                    Op::EndsWith => Value::Bool(s.ends_with(&sub)),
+                    Op::Contains => Value::Bool(s.contains(&sub)),
+                    _ => Value::Int(strings::index_of(&s, &sub)),
+                };
+                program_state.stack.push(result);
+            }
+            Op::Repeat => {
+                let n = program_state.pop_int()?;
+                let s = program_state.pop_string()?;
+                let result = strings::repeat(&s, n).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::String(result));
+            }
+            Op::Pad { left, with_fill } => {
+                let fill = match with_fill {
+                    true => program_state.pop_string()?,
+                    false => " ".to_string(),
+                };
+                let width = program_state.pop_int()?;
+                let s = program_state.pop_string()?;
+                let result =
+                    strings::pad(&s, width, &fill, *left).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::String(result));
+            }
+            Op::Join => {
+                let separator = program_state.pop_string()?;
+                let list = match program_state.pop()? {
+                    Value::List(l) => l,
+                    _ => {
+                        return Err(RuntimeError {
+                            msg: "Expected List".to_string(),
+                        })
+                    }
+                };
+                let joined = strings::join(&list, &separator);
+                program_state.stack.push(Value::String(joined));
+            }
+            Op::Reverse => {
+                let value = program_state.pop()?;
+                let reversed = strings::reverse(value).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(reversed);
+            }
+            Op::SplitFields => {
+                let s = program_state.pop_string()?;
+                let fields = match &env.field_splitter {
+                    FieldSplitter::Whitespace => {
+                        s.split_whitespace().map(|s| s.to_string()).collect()
+                    }
+                    FieldSplitter::FixedWidth(columns) => columns.split(&s),
+                };
+                program_state
+                    .stack
+                    .push(Value::List(fields.into_iter().map(Value::String).collect()));
+            }
+            Op::SplitColumns { columns } => {
+                let s = program_state.pop_string()?;
+                program_state.stack.push(Value::List(
+                    columns.split(&s).into_iter().map(Value::String).collect(),
+                ));
+            }
+            Op::SplitFixed { with_trim } => {
+                let trim = match with_trim {
+                    true => Trim::try_from(program_state.pop_string()?.as_str())
+                        .map_err(|msg| RuntimeError { msg })?,
+                    false => Trim::default(),
+                };
+                let spec = program_state.pop_string()?;
+                let s = program_state.pop_string()?;
+                let columns = Columns::parse(&spec, trim).map_err(|msg| RuntimeError { msg })?;
+                program_state.stack.push(Value::List(
+                    columns.split(&s).into_iter().map(Value::String).collect(),
+                ));
+            }
+            Op::SplitStr => {
+                let s = program_state.pop_string()?;
+                program_state.stack.push(Value::List(
+                    s.split_whitespace()
+                        .map(|s| Value::String(s.to_string()))
+                        .collect(),
+                ));
+            }
+            Op::Keys | Op::Values => {
+                let m = program_state.pop_map()?;
+                let m = m.borrow();
+                let list = match op {
+                    Op::Keys => m.keys().cloned().map(Value::String).collect(),
+                    _ => m.values().cloned().collect(),
+                };
+                program_state.stack.push(Value::List(list));
+            }
+            Op::Has | Op::Delete => {
+                let key = program_state.pop_string()?;
+                let m = program_state.pop_map()?;
+                match op {
+                    Op::Has => {
+                        let has = m.borrow().contains_key(&key);
+                        program_state.stack.push(Value::Bool(has));
+                    }
+                    _ => {
+                        m.borrow_mut().shift_remove(&key);
+                    }
+                };
+            }
+        }
+        pc = next_pc;
+    }
+    Ok(program_state.output)
+}
@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+// The files and commands that `print_to`, `append_to` and `print_pipe` write
+// to. They are opened on the first write and kept open until the program is
+// closed, so that all items printed to the same name end up in the same file
+// or are read by the same process.
+
+#[derive(Default)]
+pub struct Outputs {
+    files: HashMap<String, BufWriter<File>>,
+    pipes: HashMap<String, (Child, BufWriter<ChildStdin>)>,
+}
+
+impl Outputs {
+    /// Writes to a file, which is truncated when it is opened, unless
+    /// appending
+    pub fn write_file(&mut self, path: &str, append: bool, text: &str) -> io::Result<()> {
+        if !self.files.contains_key(path) {
+            let file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(append)
+                .truncate(!append)
+                .open(path)?;
+            self.files.insert(path.to_string(), BufWriter::new(file));
+        }
+        let file = self.files.get_mut(path).expect("the file is open");
+        file.write_all(text.as_bytes())
+    }
+
+    /// Writes to the input of a shell command, whose output goes to stdout.
+    /// A command may exit before reading all of its input, e.g. `head -1`,
+    /// which is not an error.
+    pub fn write_pipe(&mut self, command: &str, text: &str) -> io::Result<()> {
+        if !self.pipes.contains_key(command) {
+            let mut child = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(Stdio::piped())
+                .spawn()?;
+            let stdin = child.stdin.take().expect("stdin is piped");
+            self.pipes
+                .insert(command.to_string(), (child, BufWriter::new(stdin)));
+        }
+        let (_, stdin) = self.pipes.get_mut(command).expect("the command runs");
+        match stdin.write_all(text.as_bytes()) {
+            Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Flushes and closes all files, and closes the input of all commands and
+    /// waits for them to exit
+    pub fn close(&mut self) -> io::Result<()> {
+        for (_, mut file) in self.files.drain() {
+            file.flush()?;
+        }
+        for (_, (mut child, mut stdin)) in self.pipes.drain() {
+            match stdin.flush() {
+                Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+                _ => {}
+            }
+            drop(stdin);
+            child.wait()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,352 @@
+use rust_decimal::RoundingStrategy;
+use unicode_width::UnicodeWidthChar;
+use unicode_width::UnicodeWidthStr;
+
+use super::value::{Type, Value};
+
+/// A parsed printf-style format string, e.g. `"%-10s %8.2f"`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Format {
+    pieces: Vec<Piece>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Piece {
+    Literal(String),
+    Conversion(Conversion),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Conversion {
+    left_align: bool,
+    zero_pad: bool,
+    /// Prefix for positive numbers, either '+' or ' '
+    sign: Option<char>,
+    width: Option<usize>,
+    precision: Option<usize>,
+    kind: Kind,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Kind {
+    Int,
+    Hex {
+        upper: bool,
+    },
+    Float,
+    Exp {
+        upper: bool,
+    },
+    #[default]
+    Str,
+}
+
+impl Kind {
+    fn accepts(&self, t: &Type) -> bool {
+        match self {
+            Kind::Int | Kind::Hex { .. } => matches!(t, Type::Int | Type::Any),
+            Kind::Float | Kind::Exp { .. } => {
+                matches!(t, Type::Int | Type::Float | Type::Decimal | Type::Any)
+            }
+            Kind::Str => *t != Type::Unit,
+        }
+    }
+
+    fn letter(&self) -> char {
+        match self {
+            Kind::Int => 'd',
+            Kind::Hex { upper: false } => 'x',
+            Kind::Hex { upper: true } => 'X',
+            Kind::Float => 'f',
+            Kind::Exp { upper: false } => 'e',
+            Kind::Exp { upper: true } => 'E',
+            Kind::Str => 's',
+        }
+    }
+}
+
+impl Format {
+    /// Conversions are `%` followed by flags (`-` to align left, `0` to pad
+    /// numbers with zeros, `+` or space to prefix positive numbers), a width,
+    /// a precision after a `.` and one of `d`, `x`, `X`, `f`, `e`, `E` or `s`.
+    /// `%%` is a literal `%`.
+    pub fn parse(format: &str) -> Result<Format, String> {
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut chars = format.chars().peekable();
+        while let Some(ch) = chars.next() {
+            if ch != '%' {
+                literal.push(ch);
+                continue;
+            }
+            if chars.next_if_eq(&'%').is_some() {
+                literal.push('%');
+                continue;
+            }
+            let mut conversion = Conversion::default();
+            while let Some(flag) = chars.next_if(|c| matches!(c, '-' | '0' | '+' | ' ')) {
+                match flag {
+                    '-' => conversion.left_align = true,
+                    '0' => conversion.zero_pad = true,
+                    '+' => conversion.sign = Some('+'),
+                    // '+' takes precedence over ' ', as in C
+                    _ => conversion.sign = conversion.sign.or(Some(' ')),
+                }
+            }
+            conversion.width = parse_digits(&mut chars)?;
+            if chars.next_if_eq(&'.').is_some() {
+                conversion.precision = Some(parse_digits(&mut chars)?.unwrap_or(0));
+            }
+            conversion.kind = match chars.next() {
+                Some('d') => Kind::Int,
+                Some('x') => Kind::Hex { upper: false },
+                Some('X') => Kind::Hex { upper: true },
+                Some('f') => Kind::Float,
+                Some('e') => Kind::Exp { upper: false },
+                Some('E') => Kind::Exp { upper: true },
+                Some('s') => Kind::Str,
+                Some(other) => return Err(format!("Unknown conversion '%{}'", other)),
+                None => return Err("Incomplete conversion at the end of the format".to_string()),
+            };
+            if !literal.is_empty() {
+                pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+            }
+            pieces.push(Piece::Conversion(conversion));
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Format { pieces })
+    }
+
+    fn conversions(&self) -> impl Iterator<Item = &Conversion> {
+        self.pieces.iter().filter_map(|piece| match piece {
+            Piece::Conversion(c) => Some(c),
+            Piece::Literal(_) => None,
+        })
+    }
+
+    /// Checks that the format has one conversion per argument, and that each
+    /// conversion accepts the type of its argument
+    pub fn check(&self, arg_types: &[Type]) -> Result<(), String> {
+        let count = self.conversions().count();
+        if count != arg_types.len() {
+            return Err(format!(
+                "The format has {} conversions, but {} arguments were given",
+                count,
+                arg_types.len()
+            ));
+        }
+        for (i, (conversion, t)) in self.conversions().zip(arg_types).enumerate() {
+            if !conversion.kind.accepts(t) {
+                return Err(format!(
+                    "Conversion {} ('%{}') can't format {}",
+                    i,
+                    conversion.kind.letter(),
+                    t
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, args: &[Value]) -> Result<String, String> {
+        self.check(&args.iter().map(Value::get_type).collect::<Vec<_>>())?;
+        let mut args = args.iter();
+        let mut output = String::new();
+        for piece in &self.pieces {
+            match piece {
+                Piece::Literal(s) => output.push_str(s),
+                Piece::Conversion(c) => {
+                    if let Some(arg) = args.next() {
+                        output.push_str(&c.apply(arg));
+                    }
+                }
+            }
+        }
+        Ok(output)
+    }
+}
+
+fn parse_digits(chars: &mut std::iter::Peekable<std::str::Chars>) -> Result<Option<usize>, String> {
+    let mut digits = String::new();
+    while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+        digits.push(digit);
+    }
+    match digits.is_empty() {
+        true => Ok(None),
+        false => digits
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Width or precision too large: {}", digits)),
+    }
+}
+
+impl Conversion {
+    fn apply(&self, arg: &Value) -> String {
+        match (self.kind, arg) {
+            (Kind::Str, arg) => {
+                let s = arg.to_string();
+                let s = match self.precision {
+                    Some(precision) => truncate(&s, precision),
+                    None => s,
+                };
+                self.pad(String::new(), s, false)
+            }
+            (Kind::Int, Value::Int(i)) => self.pad_number(*i < 0, i.unsigned_abs().to_string()),
+            (Kind::Hex { upper }, Value::Int(i)) => match upper {
+                true => self.pad(String::new(), format!("{:X}", i), true),
+                false => self.pad(String::new(), format!("{:x}", i), true),
+            },
+            (Kind::Float, Value::Decimal(d)) => {
+                let precision = self.precision.unwrap_or(6) as u32;
+                let mut rounded =
+                    d.round_dp_with_strategy(precision, RoundingStrategy::MidpointNearestEven);
+                rounded.rescale(precision);
+                let s = rounded.abs().to_string();
+                self.pad_number(d.is_sign_negative() && !rounded.is_zero(), s)
+            }
+            (Kind::Float, arg) => {
+                let f = to_f64(arg);
+                let s = format!("{:.*}", self.precision.unwrap_or(6), f.abs());
+                self.pad_number(f.is_sign_negative(), s)
+            }
+            (Kind::Exp { upper }, arg) => {
+                let f = to_f64(arg);
+                let s = format!("{:.*e}", self.precision.unwrap_or(6), f.abs());
+                // Like C, the exponent has a sign and at least two digits
+                let s = match s.split_once('e') {
+                    Some((mantissa, exponent)) => {
+                        let (sign, digits) = match exponent.strip_prefix('-') {
+                            Some(digits) => ('-', digits),
+                            None => ('+', exponent),
+                        };
+                        format!("{}e{}{:0>2}", mantissa, sign, digits)
+                    }
+                    None => s,
+                };
+                let s = if upper { s.to_uppercase() } else { s };
+                self.pad_number(f.is_sign_negative(), s)
+            }
+            (Kind::Int | Kind::Hex { .. }, _) => unreachable!("checked by Format::apply"),
+        }
+    }
+
+    fn pad_number(&self, negative: bool, digits: String) -> String {
+        let sign = match (negative, self.sign) {
+            (true, _) => "-".to_string(),
+            (false, Some(sign)) => sign.to_string(),
+            (false, None) => String::new(),
+        };
+        self.pad(sign, digits, true)
+    }
+
+    /// Pads to the width in display columns, so that e.g. `日本` takes up four
+    /// columns. Zeros are inserted between the sign and the digits.
+    fn pad(&self, sign: String, s: String, numeric: bool) -> String {
+        let len = sign.width() + s.width();
+        let padding = self.width.unwrap_or(0).saturating_sub(len);
+        if self.left_align {
+            format!("{}{}{}", sign, s, " ".repeat(padding))
+        } else if self.zero_pad && numeric {
+            format!("{}{}{}", sign, "0".repeat(padding), s)
+        } else {
+            format!("{}{}{}", " ".repeat(padding), sign, s)
+        }
+    }
+}
+
+/// Truncates a string to at most the given number of display columns
+fn truncate(s: &str, columns: usize) -> String {
+    let mut width = 0;
+    s.chars()
+        .take_while(|c| {
+            width += c.width().unwrap_or(0);
+            width <= columns
+        })
+        .collect()
+}
+
+fn to_f64(value: &Value) -> f64 {
+    match value {
+        Value::Int(i) => *i as f64,
+        Value::Float(f) => *f,
+        Value::Decimal(d) => f64::try_from(*d).unwrap_or(f64::NAN),
+        _ => f64::NAN,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(format: &str, args: &[Value]) -> String {
+        Format::parse(format).unwrap().apply(args).unwrap()
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(format("%d", &[Value::Int(42)]), "42");
+        assert_eq!(format("[%5d]", &[Value::Int(42)]), "[   42]");
+        assert_eq!(format("[%-5d]", &[Value::Int(42)]), "[42   ]");
+        assert_eq!(format("[%05d]", &[Value::Int(-42)]), "[-0042]");
+        assert_eq!(format("[%+d]", &[Value::Int(42)]), "[+42]");
+        assert_eq!(format("[% d]", &[Value::Int(42)]), "[ 42]");
+        assert_eq!(
+            format(
+                "%x %X %04x",
+                &[Value::Int(255), Value::Int(255), Value::Int(42)]
+            ),
+            "ff FF 002a"
+        );
+    }
+
+    #[test]
+    fn test_floats() {
+        assert_eq!(format("%f", &[Value::Float(1.23456)]), "1.234560");
+        assert_eq!(format("%.2f", &[Value::Float(1.23456)]), "1.23");
+        assert_eq!(format("[%8.3f]", &[Value::Float(1.23456)]), "[   1.235]");
+        assert_eq!(format("[%08.3f]", &[Value::Float(-1.23456)]), "[-001.235]");
+        assert_eq!(format("%.0f", &[Value::Int(2)]), "2");
+        assert_eq!(format("%e", &[Value::Float(1234.5)]), "1.234500e+03");
+        assert_eq!(format("%.2E", &[Value::Float(0.00012)]), "1.20E-04");
+    }
+
+    #[test]
+    fn test_decimals() {
+        let d = |s: &str| Value::Decimal(s.parse().unwrap());
+        assert_eq!(format("%.2f", &[d("2.345")]), "2.34");
+        assert_eq!(format("%.4f", &[d("2.345")]), "2.3450");
+        assert_eq!(format("%.2f", &[d("-0.001")]), "0.00");
+    }
+
+    #[test]
+    fn test_strings() {
+        let s = || Value::String("abc".to_string());
+        assert_eq!(format("[%5s]", &[s()]), "[  abc]");
+        assert_eq!(format("[%-5s]", &[s()]), "[abc  ]");
+        assert_eq!(format("[%05s]", &[s()]), "[  abc]");
+        assert_eq!(format("[%.2s]", &[s()]), "[ab]");
+        assert_eq!(format("%s%%", &[Value::Int(50)]), "50%");
+    }
+
+    #[test]
+    fn test_unicode_width() {
+        let s = || Value::String("日本".to_string());
+        assert_eq!(format("[%6s]", &[s()]), "[  日本]");
+        assert_eq!(format("[%-6s]", &[s()]), "[日本  ]");
+        assert_eq!(format("[%.3s]", &[s()]), "[日]");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Format::parse("%q").is_err());
+        assert!(Format::parse("abc %").is_err());
+        assert!(Format::parse("%5").is_err());
+        let format = Format::parse("%d %s").unwrap();
+        assert!(format.check(&[Type::Int]).is_err());
+        assert!(format.check(&[Type::String, Type::String]).is_err());
+        assert!(format.check(&[Type::Any, Type::Bool]).is_ok());
+        assert!(format.apply(&[Value::Float(1.0), Value::Int(1)]).is_err());
+    }
+}
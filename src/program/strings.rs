@@ -0,0 +1,129 @@
+use super::value::Value;
+
+// Positions and lengths of strings are counted in chars rather than bytes, so
+// that e.g. `substr("日本語", 1, 1)` is "本" instead of an invalid string.
+
+/// The number of chars of a string, or the number of elements of a list or map
+pub fn len(value: &Value) -> Result<i64, String> {
+    let len = match value {
+        Value::String(s) => s.chars().count(),
+        Value::List(l) => l.len(),
+        Value::Map(m) => m.borrow().len(),
+        other => return Err(format!("Cannot get the length of {}", other.get_type())),
+    };
+    Ok(len as i64)
+}
+
+/// The chars from `start` to the end of the string, or at most `length` chars.
+/// Positions beyond the end of the string result in an empty string.
+pub fn substr(s: &str, start: i64, length: Option<i64>) -> Result<String, String> {
+    let start = non_negative(start, "start")?;
+    let chars = s.chars().skip(start);
+    match length {
+        Some(length) => Ok(chars.take(non_negative(length, "length")?).collect()),
+        None => Ok(chars.collect()),
+    }
+}
+
+/// The char position of the first occurrence of `sub`, or -1
+pub fn index_of(s: &str, sub: &str) -> i64 {
+    match s.find(sub) {
+        Some(byte_index) => s[..byte_index].chars().count() as i64,
+        None => -1,
+    }
+}
+
+pub fn repeat(s: &str, n: i64) -> Result<String, String> {
+    Ok(s.repeat(non_negative(n, "count")?))
+}
+
+/// Pads a string with a fill char to at least `width` chars
+pub fn pad(s: &str, width: i64, fill: &str, left: bool) -> Result<String, String> {
+    let mut fill_chars = fill.chars();
+    let fill = match (fill_chars.next(), fill_chars.next()) {
+        (Some(fill), None) => fill,
+        _ => return Err(format!("Padding must be a single char, got '{}'", fill)),
+    };
+    let padding: String = std::iter::repeat_n(fill, non_negative(width, "width")?)
+        .skip(s.chars().count())
+        .collect();
+    match left {
+        true => Ok(padding + s),
+        false => Ok(s.to_string() + &padding),
+    }
+}
+
+pub fn join(list: &[Value], separator: &str) -> String {
+    let strings: Vec<String> = list.iter().map(|v| v.to_string()).collect();
+    strings.join(separator)
+}
+
+/// Reverses the chars of a string or the elements of a list
+pub fn reverse(value: Value) -> Result<Value, String> {
+    match value {
+        Value::String(s) => Ok(Value::String(s.chars().rev().collect())),
+        Value::List(mut l) => {
+            l.reverse();
+            Ok(Value::List(l))
+        }
+        other => Err(format!("Cannot reverse {}", other.get_type())),
+    }
+}
+
+fn non_negative(n: i64, name: &str) -> Result<usize, String> {
+    usize::try_from(n).map_err(|_| format!("The {} must not be negative, got {}", name, n))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_len() {
+        assert_eq!(len(&Value::String("日本語".to_string())), Ok(3));
+        assert_eq!(len(&Value::List(vec![Value::Int(1)])), Ok(1));
+        assert!(len(&Value::Int(1)).is_err());
+    }
+
+    #[test]
+    fn test_substr() {
+        assert_eq!(substr("日本語", 1, Some(1)), Ok("本".to_string()));
+        assert_eq!(substr("日本語", 1, None), Ok("本語".to_string()));
+        assert_eq!(substr("abc", 1, Some(10)), Ok("bc".to_string()));
+        assert_eq!(substr("abc", 5, None), Ok(String::new()));
+        assert!(substr("abc", -1, None).is_err());
+        assert!(substr("abc", 0, Some(-1)).is_err());
+    }
+
+    #[test]
+    fn test_index_of() {
+        assert_eq!(index_of("日本語", "語"), 2);
+        assert_eq!(index_of("abcabc", "c"), 2);
+        assert_eq!(index_of("abc", "d"), -1);
+    }
+
+    #[test]
+    fn test_pad() {
+        assert_eq!(pad("7", 3, "0", true), Ok("007".to_string()));
+        assert_eq!(pad("日本", 4, ".", false), Ok("日本..".to_string()));
+        assert_eq!(pad("abcd", 2, " ", true), Ok("abcd".to_string()));
+        assert!(pad("a", 3, "ab", true).is_err());
+        assert!(pad("a", 3, "", true).is_err());
+    }
+
+    #[test]
+    fn test_reverse() {
+        let reversed = reverse(Value::String("añb".to_string())).unwrap();
+        assert_eq!(reversed.to_string(), "bña");
+        let list = Value::List(vec![Value::Int(1), Value::Int(2)]);
+        assert_eq!(reverse(list).unwrap().to_json(), "[2,1]");
+    }
+
+    #[test]
+    fn test_repeat_and_join() {
+        assert_eq!(repeat("ab", 3), Ok("ababab".to_string()));
+        assert!(repeat("ab", -1).is_err());
+        let list = vec![Value::String("a".to_string()), Value::Int(1)];
+        assert_eq!(join(&list, ", "), "a, 1");
+    }
+}
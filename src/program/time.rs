@@ -0,0 +1,342 @@
+use std::str::FromStr;
+
+use chrono::format::{Item, ParseErrorKind, StrftimeItems};
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone,
+    Timelike, Utc,
+};
+use chrono_tz::Tz;
+
+// Times are instants together with the UTC offset they are displayed in.
+// Named time zones are resolved to the offset that is in effect at the given
+// instant, so a time converted to e.g. Europe/Berlin keeps its offset when
+// it is moved across a daylight saving time change by `add_seconds`.
+
+pub type Time = DateTime<FixedOffset>;
+
+/// Formats with an offset that are tried by `parse`, in addition to RFC 3339
+/// and RFC 2822
+const OFFSET_FORMATS: [&str; 2] = ["%Y-%m-%dT%H:%M:%S%.f%#z", "%Y-%m-%d %H:%M:%S%.f%#z"];
+
+/// Formats without an offset that are tried by `parse`. They are taken to be
+/// in UTC.
+const NAIVE_FORMATS: [&str; 4] = [
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Zone {
+    Fixed(FixedOffset),
+    Named(Tz),
+}
+
+impl TryFrom<&str> for Zone {
+    type Error = String;
+
+    /// Accepts `UTC`, `Z`, offsets like `+02:00`, `+0200` or `-05`, and the
+    /// names of the tz database, e.g. `Europe/Berlin`
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if s == "UTC" || s == "Z" {
+            return Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap()));
+        }
+        if s.starts_with(['+', '-']) {
+            return parse_offset(s)
+                .map(Zone::Fixed)
+                .ok_or(format!("Invalid UTC offset '{}'", s));
+        }
+        Tz::from_str(s)
+            .map(Zone::Named)
+            .map_err(|_| format!("Unknown time zone '{}'", s))
+    }
+}
+
+fn parse_offset(s: &str) -> Option<FixedOffset> {
+    let (sign, digits) = match s.split_at(1) {
+        ("+", digits) => (1, digits),
+        ("-", digits) => (-1, digits),
+        _ => return None,
+    };
+    let digits = digits.replace(':', "");
+    if !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let (hours, minutes) = match digits.len() {
+        2 => (digits.parse::<i32>().ok()?, 0),
+        4 => (digits[..2].parse::<i32>().ok()?, digits[2..].parse().ok()?),
+        _ => return None,
+    };
+    if minutes >= 60 {
+        return None;
+    }
+    FixedOffset::east_opt(sign * (hours * 3600 + minutes * 60))
+}
+
+impl Zone {
+    fn convert(&self, time: &Time) -> Time {
+        match self {
+            Zone::Fixed(offset) => time.with_timezone(offset),
+            Zone::Named(tz) => time.with_timezone(tz).fixed_offset(),
+        }
+    }
+
+    /// Ambiguous local times, which occur when clocks are turned back, are
+    /// resolved to the earlier instant. Local times that are skipped when
+    /// clocks are turned forward don't exist.
+    fn localize(&self, naive: &NaiveDateTime) -> Result<Time, String> {
+        let time = match self {
+            Zone::Fixed(offset) => offset.from_local_datetime(naive).earliest(),
+            Zone::Named(tz) => tz
+                .from_local_datetime(naive)
+                .earliest()
+                .map(|t| t.fixed_offset()),
+        };
+        time.ok_or(format!("{} doesn't exist in the time zone", naive))
+    }
+}
+
+/// Parses ISO 8601 (including RFC 3339), RFC 2822 and seconds since the
+/// epoch. Times without an offset are taken to be in UTC.
+pub fn parse(s: &str) -> Result<Time, String> {
+    let s = s.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time);
+    }
+    for format in OFFSET_FORMATS {
+        if let Ok(time) = DateTime::parse_from_str(s, format) {
+            return Ok(time);
+        }
+    }
+    let utc = Zone::Fixed(FixedOffset::east_opt(0).unwrap());
+    for format in NAIVE_FORMATS {
+        if let Ok(naive) = NaiveDateTime::parse_from_str(s, format) {
+            return utc.localize(&naive);
+        }
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+        return utc.localize(&date.and_time(NaiveTime::MIN));
+    }
+    if let Ok(time) = DateTime::parse_from_rfc2822(s) {
+        return Ok(time);
+    }
+    match s.parse::<f64>() {
+        Ok(seconds) if !s.starts_with(['i', 'I', 'n', 'N', '+']) => from_epoch(seconds),
+        _ => Err(format!("Cannot parse '{}' as a time", s)),
+    }
+}
+
+/// Parses a time with a strftime-like format. If the format has no offset,
+/// the time is taken to be in the given zone, and if it has no time of day,
+/// the time is midnight.
+pub fn parse_with_format(s: &str, format: &str, zone: &Zone) -> Result<Time, String> {
+    let error = |err| format!("Cannot parse '{}' with format '{}': {}", s, format, err);
+    let not_enough = |err: &chrono::ParseError| err.kind() == ParseErrorKind::NotEnough;
+    match DateTime::parse_from_str(s, format) {
+        Ok(time) => return Ok(time),
+        Err(err) if !not_enough(&err) => return Err(error(err)),
+        Err(_) => {}
+    }
+    let naive = match NaiveDateTime::parse_from_str(s, format) {
+        Ok(naive) => naive,
+        Err(err) if not_enough(&err) => NaiveDate::parse_from_str(s, format)
+            .map_err(error)?
+            .and_time(NaiveTime::MIN),
+        Err(err) => return Err(error(err)),
+    };
+    zone.localize(&naive)
+}
+
+/// Fractions of seconds are kept up to nanoseconds
+pub fn from_epoch(seconds: f64) -> Result<Time, String> {
+    let invalid = || format!("{} seconds since the epoch is out of range", seconds);
+    if !seconds.is_finite() {
+        return Err(invalid());
+    }
+    let whole = seconds.floor();
+    let nanos = ((seconds - whole) * 1e9).round() as u32;
+    if whole < i64::MIN as f64 || whole > i64::MAX as f64 {
+        return Err(invalid());
+    }
+    DateTime::from_timestamp(whole as i64, nanos.min(999_999_999))
+        .map(|t| t.fixed_offset())
+        .ok_or_else(invalid)
+}
+
+/// Whole seconds since the epoch, rounded down
+pub fn epoch(time: &Time) -> i64 {
+    time.timestamp()
+}
+
+/// Invalid specifiers are reported rather than making formatting fail
+pub fn format(time: &Time, format: &str) -> Result<String, String> {
+    let items = StrftimeItems::new(format).collect::<Vec<_>>();
+    if items.iter().any(|item| matches!(item, Item::Error)) {
+        return Err(format!("Invalid time format '{}'", format));
+    }
+    Ok(time.format_with_items(items.iter()).to_string())
+}
+
+pub fn to_zone(time: &Time, zone: &Zone) -> Time {
+    zone.convert(time)
+}
+
+pub fn add_seconds(time: &Time, seconds: f64) -> Result<Time, String> {
+    let nanos = seconds * 1e9;
+    if !nanos.is_finite() || nanos.abs() >= i64::MAX as f64 {
+        return Err(format!("Cannot add {} seconds", seconds));
+    }
+    time.checked_add_signed(Duration::nanoseconds(nanos as i64))
+        .ok_or(format!(
+            "Adding {} seconds to {} is out of range",
+            seconds, time
+        ))
+}
+
+/// The seconds from `start` to `end`, which are negative if `end` is earlier
+pub fn seconds_between(start: &Time, end: &Time) -> f64 {
+    let duration = end.signed_duration_since(start);
+    duration.num_seconds() as f64 + duration.subsec_nanos() as f64 / 1e9
+}
+
+/// Truncates to the start of the second, minute, hour, day, week (starting
+/// on Monday), month or year in the offset of the time, e.g. 13:45:10+02:00
+/// truncated to the day is 00:00:00+02:00.
+pub fn truncate(time: &Time, unit: &str) -> Result<Time, String> {
+    let naive = time.naive_local();
+    let date = naive.date();
+    let truncated = match unit {
+        "second" => naive.with_nanosecond(0),
+        "minute" => date.and_hms_opt(naive.hour(), naive.minute(), 0),
+        "hour" => date.and_hms_opt(naive.hour(), 0, 0),
+        "day" => Some(date.and_time(NaiveTime::MIN)),
+        "week" => {
+            let monday = date - Duration::days(date.weekday().num_days_from_monday().into());
+            Some(monday.and_time(NaiveTime::MIN))
+        }
+        "month" => date.with_day(1).map(|d| d.and_time(NaiveTime::MIN)),
+        "year" => date.with_ordinal(1).map(|d| d.and_time(NaiveTime::MIN)),
+        _ => {
+            return Err(format!(
+                "Invalid unit '{}', expected second, minute, hour, day, week, month or year",
+                unit
+            ))
+        }
+    };
+    truncated
+        .and_then(|naive| time.offset().from_local_datetime(&naive).single())
+        .ok_or(format!("Cannot truncate {} to the {}", time, unit))
+}
+
+pub fn now() -> Time {
+    Utc::now().fixed_offset()
+}
+
+/// RFC 3339 with as many fractional digits as needed, and `Z` for UTC
+pub fn to_string(time: &Time) -> String {
+    time.to_rfc3339_opts(chrono::SecondsFormat::AutoSi, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zone(s: &str) -> Zone {
+        Zone::try_from(s).unwrap()
+    }
+
+    fn parse_str(s: &str) -> String {
+        to_string(&parse(s).unwrap())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_str("2024-03-01T12:30:00Z"), "2024-03-01T12:30:00Z");
+        assert_eq!(
+            parse_str("2024-03-01T12:30:00.250+02:00"),
+            "2024-03-01T12:30:00.250+02:00"
+        );
+        assert_eq!(
+            parse_str("2024-03-01 12:30:00+0200"),
+            "2024-03-01T12:30:00+02:00"
+        );
+        assert_eq!(parse_str("2024-03-01 12:30:00"), "2024-03-01T12:30:00Z");
+        assert_eq!(parse_str("2024-03-01"), "2024-03-01T00:00:00Z");
+        assert_eq!(
+            parse_str("Fri, 01 Mar 2024 12:30:00 -0500"),
+            "2024-03-01T12:30:00-05:00"
+        );
+        assert_eq!(parse_str("1709296200"), "2024-03-01T12:30:00Z");
+        assert_eq!(parse_str("1709296200.5"), "2024-03-01T12:30:00.500Z");
+        assert!(parse("yesterday").is_err());
+        assert!(parse("nan").is_err());
+    }
+
+    #[test]
+    fn test_parse_with_format() {
+        let utc = zone("UTC");
+        let time = parse_with_format("01/Mar/2024:12:30:00 +0100", "%d/%b/%Y:%H:%M:%S %z", &utc);
+        assert_eq!(to_string(&time.unwrap()), "2024-03-01T12:30:00+01:00");
+        let time = parse_with_format("2024-07-01 12:30", "%Y-%m-%d %H:%M", &zone("Europe/Berlin"));
+        assert_eq!(to_string(&time.unwrap()), "2024-07-01T12:30:00+02:00");
+        let time = parse_with_format("01.03.2024", "%d.%m.%Y", &zone("-05:00"));
+        assert_eq!(to_string(&time.unwrap()), "2024-03-01T00:00:00-05:00");
+        assert!(parse_with_format("2024-03-01", "%d.%m.%Y", &utc).is_err());
+        // Clocks were turned forward from 02:00 to 03:00
+        let gap = parse_with_format("2024-03-31 02:30", "%Y-%m-%d %H:%M", &zone("Europe/Berlin"));
+        assert!(gap.is_err());
+    }
+
+    #[test]
+    fn test_zone() {
+        assert_eq!(
+            zone("+0530"),
+            Zone::Fixed(FixedOffset::east_opt(19800).unwrap())
+        );
+        assert_eq!(
+            zone("-05"),
+            Zone::Fixed(FixedOffset::west_opt(18000).unwrap())
+        );
+        assert!(Zone::try_from("+25:00").is_err());
+        assert!(Zone::try_from("+02:75").is_err());
+        assert!(Zone::try_from("Mars/Olympus_Mons").is_err());
+        let time = parse("2024-01-15T12:00:00Z").unwrap();
+        let ny = to_zone(&time, &zone("America/New_York"));
+        assert_eq!(to_string(&ny), "2024-01-15T07:00:00-05:00");
+        assert_eq!(ny, time);
+    }
+
+    #[test]
+    fn test_format() {
+        let time = parse("2024-03-01T12:30:05+02:00").unwrap();
+        assert_eq!(format(&time, "%d.%m.%Y %H:%M").unwrap(), "01.03.2024 12:30");
+        assert_eq!(format(&time, "%s").unwrap(), "1709289005");
+        assert!(format(&time, "%Q").is_err());
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        let start = parse("2024-03-01T12:00:00Z").unwrap();
+        let end = add_seconds(&start, 90.5).unwrap();
+        assert_eq!(to_string(&end), "2024-03-01T12:01:30.500Z");
+        assert_eq!(seconds_between(&start, &end), 90.5);
+        assert_eq!(seconds_between(&end, &start), -90.5);
+        assert_eq!(epoch(&end), 1709294490);
+        assert!(add_seconds(&start, f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let time = parse("2024-03-07T13:45:10.5+02:00").unwrap();
+        let truncate_str = |unit| to_string(&truncate(&time, unit).unwrap());
+        assert_eq!(truncate_str("second"), "2024-03-07T13:45:10+02:00");
+        assert_eq!(truncate_str("minute"), "2024-03-07T13:45:00+02:00");
+        assert_eq!(truncate_str("hour"), "2024-03-07T13:00:00+02:00");
+        assert_eq!(truncate_str("day"), "2024-03-07T00:00:00+02:00");
+        assert_eq!(truncate_str("week"), "2024-03-04T00:00:00+02:00");
+        assert_eq!(truncate_str("month"), "2024-03-01T00:00:00+02:00");
+        assert_eq!(truncate_str("year"), "2024-01-01T00:00:00+02:00");
+        assert!(truncate(&time, "fortnight").is_err());
+    }
+}
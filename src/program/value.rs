@@ -0,0 +1,182 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::rc::Rc;
+
+use indexmap::IndexMap;
+use regex::Regex;
+use rust_decimal::Decimal;
+
+use super::time::{self, Time};
+use crate::json;
+use crate::logfmt;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    String(String),
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Decimal(Decimal),
+    Regex(Regex),
+    Time(Time),
+    List(Vec<Value>),
+    /// Maps are shared rather than copied when they are assigned or passed to
+    /// a function, so that they can be modified in place. Keys are kept in
+    /// insertion order.
+    Map(Rc<RefCell<IndexMap<String, Value>>>),
+}
+
+impl Value {
+    pub fn new_map(m: IndexMap<String, Value>) -> Value {
+        Value::Map(Rc::new(RefCell::new(m)))
+    }
+
+    pub fn get_type(&self) -> Type {
+        match self {
+            Value::String(_) => Type::String,
+            Value::Bool(_) => Type::Bool,
+            Value::Int(_) => Type::Int,
+            Value::Float(_) => Type::Float,
+            Value::Decimal(_) => Type::Decimal,
+            Value::Regex(_) => Type::Regex,
+            Value::Time(_) => Type::Time,
+            Value::List(_) => Type::List,
+            Value::Map(_) => Type::Map,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_json_within(&mut vec![])
+    }
+
+    fn to_json_within(&self, maps: &mut Enclosing) -> String {
+        match self {
+            Value::String(s) => json::quote(s),
+            Value::Bool(b) => b.to_string(),
+            Value::Int(i) => i.to_string(),
+            // JSON has no representation for NaN and infinity
+            Value::Float(f) if !f.is_finite() => "null".to_string(),
+            Value::Float(f) => f.to_string(),
+            Value::Decimal(d) => d.to_string(),
+            Value::Regex(r) => json::quote(r.as_str()),
+            Value::Time(t) => json::quote(&time::to_string(t)),
+            Value::List(l) => json::array(l.iter().map(|v| v.to_json_within(maps))),
+            Value::Map(m) if maps.contains(&RefCell::as_ptr(m).cast_const()) => "null".to_string(),
+            Value::Map(m) => {
+                maps.push(RefCell::as_ptr(m));
+                let object = json::object(
+                    m.borrow()
+                        .iter()
+                        .map(|(k, v)| (k.as_str(), v.to_json_within(maps))),
+                );
+                maps.pop();
+                object
+            }
+        }
+    }
+
+    fn to_string_within(&self, maps: &mut Enclosing) -> String {
+        match self {
+            Value::List(l) => l.iter().map(|v| v.to_string_within(maps)).collect(),
+            Value::Map(m) if maps.contains(&RefCell::as_ptr(m).cast_const()) => CYCLE.to_string(),
+            Value::Map(m) => {
+                maps.push(RefCell::as_ptr(m));
+                let formatted = format_kv_within(&m.borrow(), maps);
+                maps.pop();
+                formatted
+            }
+            _ => self.to_string(),
+        }
+    }
+}
+
+/// The maps that contain the value being formatted. Since maps are shared, a
+/// map may contain itself, which is printed as a placeholder instead.
+type Enclosing = Vec<*const IndexMap<String, Value>>;
+
+const CYCLE: &str = "<cycle>";
+
+pub fn format_kv(m: &IndexMap<String, Value>) -> String {
+    format_kv_within(m, &mut vec![m])
+}
+
+fn format_kv_within(m: &IndexMap<String, Value>, maps: &mut Enclosing) -> String {
+    logfmt::format(
+        m.iter()
+            .map(|(k, v)| (k.as_str(), v.to_string_within(maps))),
+    )
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::String(s) => write!(f, "{}", s),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Int(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{}", x),
+            Value::Decimal(d) => write!(f, "{}", d),
+            Value::Regex(r) => write!(f, "{}", r.as_str()),
+            Value::Time(t) => write!(f, "{}", time::to_string(t)),
+            Value::List(_) | Value::Map(_) => write!(f, "{}", self.to_string_within(&mut vec![])),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Unit,
+    String,
+    Bool,
+    Int,
+    Float,
+    Decimal,
+    Regex,
+    Time,
+    List,
+    Map,
+    /// The type of values that are only known at runtime, e.g. list elements.
+    /// Also used for parameters of functions that accept values of any type.
+    Any,
+}
+
+impl Type {
+    /// Values of type Any are accepted as well, since their type can only be
+    /// checked at runtime
+    pub fn accepts(&self, other: &Type) -> bool {
+        *self == Type::Any || *other == Type::Any || self == other
+    }
+
+    /// The value of a variable before it is assigned
+    pub fn default_value(&self) -> Value {
+        match self {
+            Type::Bool => Value::Bool(false),
+            Type::Int => Value::Int(0),
+            Type::Float => Value::Float(0.0),
+            Type::Decimal => Value::Decimal(Decimal::ZERO),
+            // The empty regex matches everything
+            Type::Regex => Value::Regex(Regex::new("").unwrap()),
+            Type::Time => Value::Time(chrono::DateTime::UNIX_EPOCH.fixed_offset()),
+            Type::List => Value::List(vec![]),
+            Type::Map => Value::new_map(IndexMap::new()),
+            Type::Unit | Type::String | Type::Any => Value::String(String::new()),
+        }
+    }
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Unit => write!(f, "Unit"),
+            Type::String => write!(f, "String"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Int => write!(f, "Int"),
+            Type::Float => write!(f, "Float"),
+            Type::Decimal => write!(f, "Decimal"),
+            Type::Regex => write!(f, "Regex"),
+            Type::Time => write!(f, "Time"),
+            Type::List => write!(f, "List"),
+            Type::Map => write!(f, "Map"),
+            Type::Any => write!(f, "Any"),
+        }
+    }
+}
@@ -22,3 +22,28 @@ pub fn code(source: ProgramSource) -> Result<String, String> {
             .map_err(|e| format!("Failed to read program source from {}: {}", fp, e)),
     }
 }
+
+/// Reads the code of the sources of a program, named for error messages after
+/// their file, or `command line` for literals, which are numbered if there are
+/// several
+pub fn load(sources: Vec<ProgramSource>) -> Result<Vec<(String, String)>, String> {
+    let literals = sources
+        .iter()
+        .filter(|s| matches!(s, ProgramSource::Literal(_)))
+        .count();
+    let mut literal = 0;
+    sources
+        .into_iter()
+        .map(|source| {
+            let name = match &source {
+                ProgramSource::File(fp) => fp.clone(),
+                ProgramSource::Literal(_) if literals == 1 => "command line".to_string(),
+                ProgramSource::Literal(_) => {
+                    literal += 1;
+                    format!("command line #{}", literal)
+                }
+            };
+            Ok((name, code(source)?))
+        })
+        .collect()
+}
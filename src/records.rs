@@ -0,0 +1,274 @@
+use std::io::{self, BufRead, Read};
+
+use regex::bytes::Regex;
+
+// Splitting of the input into items. By default an item is a line, but it may
+// also end with any string or a match of a regex, be a paragraph of lines
+// separated by blank lines, or be the whole input. Like a trailing newline,
+// a separator at the end of the input doesn't start another, empty item.
+
+/// The bytes read at once when looking for a separator
+const CHUNK_SIZE: usize = 64 << 10;
+
+/// How the input is split into items
+#[derive(Debug, Clone, Default)]
+pub enum Separator {
+    /// Lines ending with `\n` or `\r\n`
+    #[default]
+    Line,
+    /// NUL-terminated items, as printed by `find -print0`
+    Nul,
+    /// Lines separated by one or more blank lines. Leading and trailing blank
+    /// lines are ignored, the lines of an item are joined with `\n`.
+    Paragraph,
+    /// The whole input as a single item
+    Whole,
+    String(String),
+    Regex(Regex),
+}
+
+impl TryFrom<&str> for Separator {
+    type Error = String;
+
+    /// `line`, `nul`, `paragraph`, `whole`, a regex in slashes, e.g. `/;\s*/`,
+    /// or any other non-empty string
+    fn try_from(spec: &str) -> Result<Self, Self::Error> {
+        match spec {
+            "line" => Ok(Separator::Line),
+            "nul" => Ok(Separator::Nul),
+            "paragraph" => Ok(Separator::Paragraph),
+            "whole" => Ok(Separator::Whole),
+            "" => Err("The record separator must not be empty".to_string()),
+            s if s.len() > 2 && s.starts_with('/') && s.ends_with('/') => {
+                Separator::regex(&s[1..s.len() - 1])
+            }
+            s => Ok(Separator::String(s.to_string())),
+        }
+    }
+}
+
+impl Separator {
+    pub fn regex(regex: &str) -> Result<Self, String> {
+        match Regex::new(regex) {
+            Ok(r) if r.is_match(b"") => {
+                Err(format!("The regex /{}/ matches the empty string", regex))
+            }
+            Ok(r) => Ok(Separator::Regex(r)),
+            Err(err) => Err(format!("Invalid regex /{}/: {}", regex, err)),
+        }
+    }
+}
+
+/// The items of the input, split by a separator
+pub struct Records<R> {
+    reader: R,
+    separator: Separator,
+    /// Bytes that have been read but not returned as part of an item
+    buffer: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: BufRead> Records<R> {
+    pub fn new(reader: R, separator: Separator) -> Self {
+        Records {
+            reader,
+            separator,
+            buffer: Vec::new(),
+            eof: false,
+        }
+    }
+
+    fn read_until(&mut self, delimiter: u8) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        match self.reader.read_until(delimiter, &mut buf)? {
+            0 => Ok(None),
+            _ => {
+                if buf.last() == Some(&delimiter) {
+                    buf.pop();
+                }
+                Ok(Some(buf))
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut line = self.read_until(b'\n')?;
+        if let Some(line) = line.as_mut() {
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        Ok(line)
+    }
+
+    fn read_paragraph(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut paragraph: Option<Vec<u8>> = None;
+        while let Some(line) = self.read_line()? {
+            let blank = line.iter().all(u8::is_ascii_whitespace);
+            match paragraph.as_mut() {
+                None if blank => {}
+                None => paragraph = Some(line),
+                Some(_) if blank => break,
+                Some(paragraph) => {
+                    paragraph.push(b'\n');
+                    paragraph.extend(line);
+                }
+            }
+        }
+        Ok(paragraph)
+    }
+
+    fn read_whole(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut buf = Vec::new();
+        match self.reader.read_to_end(&mut buf)? {
+            0 => Ok(None),
+            _ => Ok(Some(buf)),
+        }
+    }
+
+    /// Splits off the buffer up to the first separator, reading more input
+    /// until one is found. A match of a regex that reaches the end of the
+    /// buffer could be longer, so more input is read in that case as well.
+    fn read_separated(&mut self) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let found = match &self.separator {
+                Separator::String(s) => {
+                    find(&self.buffer, s.as_bytes()).map(|start| (start, start + s.len()))
+                }
+                Separator::Regex(r) => r
+                    .find(&self.buffer)
+                    .map(|m| (m.start(), m.end()))
+                    .filter(|(_, end)| *end < self.buffer.len() || self.eof),
+                _ => unreachable!("not separated by a string or regex"),
+            };
+            if let Some((start, end)) = found {
+                let rest = self.buffer.split_off(end);
+                let mut item = std::mem::replace(&mut self.buffer, rest);
+                item.truncate(start);
+                return Ok(Some(item));
+            }
+            if self.eof {
+                return match self.buffer.is_empty() {
+                    true => Ok(None),
+                    false => Ok(Some(std::mem::take(&mut self.buffer))),
+                };
+            }
+            let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+            let n = (&mut self.reader)
+                .take(CHUNK_SIZE as u64)
+                .read_to_end(&mut chunk)?;
+            self.eof = n == 0;
+            self.buffer.extend(chunk);
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+impl<R: BufRead> Iterator for Records<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = match self.separator {
+            Separator::Line => self.read_line(),
+            Separator::Nul => self.read_until(b'\0'),
+            Separator::Paragraph => self.read_paragraph(),
+            Separator::Whole => self.read_whole(),
+            Separator::String(_) | Separator::Regex(_) => self.read_separated(),
+        };
+        match item {
+            Ok(None) => None,
+            Ok(Some(bytes)) => Some(
+                String::from_utf8(bytes)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+            ),
+            Err(err) => Some(Err(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(input: &str, spec: &str) -> Vec<String> {
+        let separator = Separator::try_from(spec).unwrap();
+        Records::new(input.as_bytes(), separator)
+            .collect::<io::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_lines() {
+        assert_eq!(split("a\r\nb\n\nc", "line"), ["a", "b", "", "c"]);
+        assert_eq!(split("a\n", "line"), ["a"]);
+        assert!(split("", "line").is_empty());
+    }
+
+    #[test]
+    fn test_nul() {
+        assert_eq!(split("a b\0c\nd\0", "nul"), ["a b", "c\nd"]);
+    }
+
+    #[test]
+    fn test_paragraphs() {
+        let input = "\n\na\nb\n\n  \n\nc\r\n\n";
+        assert_eq!(split(input, "paragraph"), ["a\nb", "c"]);
+    }
+
+    #[test]
+    fn test_whole() {
+        assert_eq!(split("a\nb\n", "whole"), ["a\nb\n"]);
+        assert!(split("", "whole").is_empty());
+    }
+
+    #[test]
+    fn test_string() {
+        assert_eq!(split("a;;b;;;c;;", ";;"), ["a", "b", ";c"]);
+        assert_eq!(split("a", ";;"), ["a"]);
+    }
+
+    #[test]
+    fn test_regex() {
+        assert_eq!(split("a, b,c ,\n", r"/\s*,\s*/"), ["a", "b", "c"]);
+        assert_eq!(split("a--b---c", "/-+/"), ["a", "b", "c"]);
+    }
+
+    #[test]
+    fn test_separators_across_chunks() {
+        let input = format!(
+            "{}--{}---",
+            "a".repeat(CHUNK_SIZE - 1),
+            "b".repeat(CHUNK_SIZE)
+        );
+        let items = split(&input, "/-+/");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[1].len(), CHUNK_SIZE);
+        let items = split(&input, "--");
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[2], "-");
+    }
+
+    #[test]
+    fn test_invalid_separators() {
+        assert!(Separator::try_from("").is_err());
+        assert!(Separator::try_from("/(/").is_err());
+        assert!(Separator::try_from("/x*/").is_err());
+        assert!(matches!(
+            Separator::try_from("//"),
+            Ok(Separator::String(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_utf8() {
+        let input: &[u8] = b"a\n\xff\n";
+        let mut records = Records::new(input, Separator::Line);
+        assert_eq!(records.next().unwrap().unwrap(), "a");
+        assert!(records.next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,626 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{self, AtomicUsize};
+
+// Sorting of records by typed keys. Records are buffered in memory up to a
+// budget. Beyond that, the buffer is sorted and spilled to a temporary file as
+// a run, and the runs are merged when the sorted records are read, a limited
+// number at a time. The sort is stable, i.e. records with equal keys keep their
+// order.
+
+pub const DEFAULT_MEMORY_BUDGET: usize = 64 << 20;
+
+/// The bytes a buffered record takes in addition to its key and text
+const RECORD_OVERHEAD: usize = 64;
+
+/// The most runs that are merged at once, and so the most temporary files
+/// that are open at once
+const MAX_FAN_IN: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyKind {
+    #[default]
+    String,
+    /// Keys that aren't numbers are sorted after all numbers
+    Numeric,
+    /// Runs of digits are compared by their numeric value, e.g. `file2` is
+    /// sorted before `file10`
+    Natural,
+}
+
+impl TryFrom<&str> for KeyKind {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s {
+            "string" => Ok(KeyKind::String),
+            "numeric" => Ok(KeyKind::Numeric),
+            "natural" => Ok(KeyKind::Natural),
+            _ => Err(format!(
+                "Invalid key kind '{}', expected string, numeric or natural",
+                s
+            )),
+        }
+    }
+}
+
+/// An optional `-` for descending order followed by an optional key kind,
+/// e.g. `-numeric`. Without a kind, the kind depends on the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Order {
+    pub kind: Option<KeyKind>,
+    pub descending: bool,
+}
+
+impl TryFrom<&str> for Order {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let (kind, descending) = match s.strip_prefix('-') {
+            Some(kind) => (kind, true),
+            None => (s, false),
+        };
+        let kind = match kind {
+            "" => None,
+            kind => Some(KeyKind::try_from(kind)?),
+        };
+        Ok(Order { kind, descending })
+    }
+}
+
+/// The key of a line of text, which is a whitespace separated field or the
+/// whole line, e.g. `-2:numeric` for the third field in descending numeric
+/// order, or `_` for the whole line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineKey {
+    /// The whole line if None
+    pub field: Option<usize>,
+    pub kind: KeyKind,
+    pub descending: bool,
+}
+
+impl TryFrom<&str> for LineKey {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let (field, descending) = match s.strip_prefix('-') {
+            Some(field) => (field, true),
+            None => (s, false),
+        };
+        let (field, kind) = match field.split_once(':') {
+            Some((field, kind)) => (field, KeyKind::try_from(kind)?),
+            None => (field, KeyKind::default()),
+        };
+        let field = match field {
+            "_" => None,
+            field => Some(
+                field
+                    .parse()
+                    .map_err(|_| format!("Invalid field '{}', expected a number or _", field))?,
+            ),
+        };
+        Ok(LineKey {
+            field,
+            kind,
+            descending,
+        })
+    }
+}
+
+impl LineKey {
+    /// Missing fields are empty
+    pub fn key(&self, line: &str) -> Key {
+        let text = match self.field {
+            None => line,
+            Some(i) => line.split_whitespace().nth(i).unwrap_or_default(),
+        };
+        Key::new(self.kind, text)
+    }
+}
+
+/// Parses a number of bytes with an optional suffix, e.g. `512K`, `64M` or
+/// `2G`
+pub fn parse_size(s: &str) -> Result<usize, String> {
+    let (digits, factor) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(factor))
+        .ok_or(format!("Invalid size '{}'", s))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Key {
+    Number(f64),
+    Text(String),
+    Natural(String),
+}
+
+impl Key {
+    pub fn new(kind: KeyKind, text: &str) -> Key {
+        match kind {
+            KeyKind::String => Key::Text(text.to_string()),
+            KeyKind::Numeric => Key::Number(text.trim().parse().unwrap_or(f64::NAN)),
+            KeyKind::Natural => Key::Natural(text.to_string()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Key::Number(_) => 8,
+            Key::Text(s) | Key::Natural(s) => s.len(),
+        }
+    }
+
+    /// Keys of different kinds are ordered by kind, numbers first
+    fn compare(&self, other: &Key) -> Ordering {
+        match (self, other) {
+            // NaN is sorted after all numbers
+            (Key::Number(a), Key::Number(b)) => match (a.is_nan(), b.is_nan()) {
+                (false, false) => a.total_cmp(b),
+                (a_nan, b_nan) => a_nan.cmp(&b_nan),
+            },
+            (Key::Text(a), Key::Text(b)) => a.cmp(b),
+            (Key::Natural(a), Key::Natural(b)) => natural_cmp(a, b),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+
+    fn rank(&self) -> u8 {
+        match self {
+            Key::Number(_) => 0,
+            Key::Text(_) => 1,
+            Key::Natural(_) => 2,
+        }
+    }
+}
+
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    loop {
+        match (a.chars().next(), b.chars().next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let (digits_a, rest_a) = split_digits(a);
+                let (digits_b, rest_b) = split_digits(b);
+                // Numbers of any length are compared without parsing them
+                let (trimmed_a, trimmed_b) = (
+                    digits_a.trim_start_matches('0'),
+                    digits_b.trim_start_matches('0'),
+                );
+                let ordering = trimmed_a
+                    .len()
+                    .cmp(&trimmed_b.len())
+                    .then_with(|| trimmed_a.cmp(trimmed_b))
+                    .then_with(|| digits_a.len().cmp(&digits_b.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                (a, b) = (rest_a, rest_b);
+            }
+            (Some(x), Some(y)) if x != y => return x.cmp(&y),
+            (Some(x), Some(_)) => {
+                (a, b) = (&a[x.len_utf8()..], &b[x.len_utf8()..]);
+            }
+        }
+    }
+}
+
+fn split_digits(s: &str) -> (&str, &str) {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    s.split_at(end)
+}
+
+pub struct Sorter {
+    descending: bool,
+    budget: usize,
+    buffer: Vec<(Key, String)>,
+    buffered: usize,
+    runs: Vec<Run>,
+    fan_in: usize,
+}
+
+impl Sorter {
+    pub fn new(descending: bool, budget: usize) -> Sorter {
+        Sorter {
+            descending,
+            budget,
+            buffer: vec![],
+            buffered: 0,
+            runs: vec![],
+            fan_in: MAX_FAN_IN,
+        }
+    }
+
+    pub fn push(&mut self, key: Key, record: String) -> io::Result<()> {
+        self.buffered += key.size() + record.len() + RECORD_OVERHEAD;
+        self.buffer.push((key, record));
+        if self.buffered > self.budget {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    fn sort_buffer(&mut self) {
+        let descending = self.descending;
+        self.buffer
+            .sort_by(|(a, _), (b, _)| ordered(a.compare(b), descending));
+    }
+
+    fn spill(&mut self) -> io::Result<()> {
+        self.sort_buffer();
+        let run = Run::create()?;
+        let mut writer = BufWriter::new(fs::File::create(&run.path)?);
+        for (key, record) in self.buffer.drain(..) {
+            write_entry(&mut writer, &key, &record)?;
+        }
+        writer.flush()?;
+        self.runs.push(run);
+        self.buffered = 0;
+        Ok(())
+    }
+
+    /// The records in sorted order
+    pub fn finish(mut self) -> io::Result<Sorted> {
+        self.sort_buffer();
+        // Merges groups of consecutive runs into bigger runs until the rest
+        // can be merged with the buffer at once. Consecutive runs keep
+        // records with equal keys in order.
+        while self.runs.len() >= self.fan_in {
+            let mut runs = std::mem::take(&mut self.runs).into_iter().peekable();
+            while runs.peek().is_some() {
+                let group: Vec<Run> = runs.by_ref().take(self.fan_in).collect();
+                self.runs.push(merge_runs(group, self.descending)?);
+            }
+        }
+        let mut sources: Vec<Source> = vec![];
+        for run in self.runs {
+            sources.push(Box::new(RunReader::open(run)?));
+        }
+        sources.push(Box::new(self.buffer.into_iter().map(Ok)));
+        Sorted::new(sources, self.descending)
+    }
+}
+
+/// Merges runs into a single run
+fn merge_runs(runs: Vec<Run>, descending: bool) -> io::Result<Run> {
+    let mut sources: Vec<Source> = vec![];
+    for run in runs {
+        sources.push(Box::new(RunReader::open(run)?));
+    }
+    let mut sorted = Sorted::new(sources, descending)?;
+    let merged = Run::create()?;
+    let mut writer = BufWriter::new(fs::File::create(&merged.path)?);
+    while let Some(entry) = sorted.next_entry() {
+        let (key, record) = entry?;
+        write_entry(&mut writer, &key, &record)?;
+    }
+    writer.flush()?;
+    Ok(merged)
+}
+
+fn ordered(ordering: Ordering, descending: bool) -> Ordering {
+    match descending {
+        true => ordering.reverse(),
+        false => ordering,
+    }
+}
+
+/// A sorted run, either spilled or in memory
+type Source = Box<dyn Iterator<Item = io::Result<(Key, String)>>>;
+
+/// Merges the sorted runs, taking the next record from the run with the
+/// lowest key. Runs are numbered in the order they were spilled, so that
+/// records with equal keys are taken from the earliest run first.
+pub struct Sorted {
+    descending: bool,
+    sources: Vec<Source>,
+    heap: BinaryHeap<Head>,
+}
+
+impl Sorted {
+    fn new(sources: Vec<Source>, descending: bool) -> io::Result<Sorted> {
+        let mut sorted = Sorted {
+            descending,
+            sources,
+            heap: BinaryHeap::new(),
+        };
+        for source in 0..sorted.sources.len() {
+            sorted.refill(source)?;
+        }
+        Ok(sorted)
+    }
+
+    fn next_entry(&mut self) -> Option<io::Result<(Key, String)>> {
+        let head = self.heap.pop()?;
+        match self.refill(head.source) {
+            Ok(()) => Some(Ok((head.key, head.record))),
+            Err(err) => Some(Err(err)),
+        }
+    }
+
+    fn refill(&mut self, source: usize) -> io::Result<()> {
+        if let Some(entry) = self.sources[source].next() {
+            let (key, record) = entry?;
+            self.heap.push(Head {
+                key,
+                record,
+                source,
+                descending: self.descending,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Sorted {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_entry()?.map(|(_, record)| record))
+    }
+}
+
+/// The next record of a run. BinaryHeap is a max-heap, so the order is
+/// reversed.
+struct Head {
+    key: Key,
+    record: String,
+    source: usize,
+    descending: bool,
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        ordered(self.key.compare(&other.key), self.descending)
+            .then_with(|| self.source.cmp(&other.source))
+            .reverse()
+    }
+}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+/// A temporary file, which is removed when the run is dropped
+struct Run {
+    path: PathBuf,
+}
+
+impl Run {
+    fn create() -> io::Result<Run> {
+        static NEXT_RUN: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT_RUN.fetch_add(1, atomic::Ordering::Relaxed);
+        let name = format!("tt-sort-{}-{}", std::process::id(), n);
+        let path = std::env::temp_dir().join(name);
+        // Fails rather than overwriting a file that happens to exist
+        fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Run { path })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+struct RunReader {
+    reader: BufReader<fs::File>,
+    // Keeps the file until it has been read
+    _run: Run,
+}
+
+impl RunReader {
+    fn open(run: Run) -> io::Result<RunReader> {
+        let reader = BufReader::new(fs::File::open(&run.path)?);
+        Ok(RunReader { reader, _run: run })
+    }
+}
+
+impl Iterator for RunReader {
+    type Item = io::Result<(Key, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        read_entry(&mut self.reader).transpose()
+    }
+}
+
+// Entries are a tag for the kind of key, the key, and the record. Numbers are
+// written as their bits, strings prefixed with their length.
+
+fn write_entry(writer: &mut impl Write, key: &Key, record: &str) -> io::Result<()> {
+    match key {
+        Key::Number(n) => {
+            writer.write_all(&[0])?;
+            writer.write_all(&n.to_bits().to_le_bytes())?;
+        }
+        Key::Text(s) => {
+            writer.write_all(&[1])?;
+            write_str(writer, s)?;
+        }
+        Key::Natural(s) => {
+            writer.write_all(&[2])?;
+            write_str(writer, s)?;
+        }
+    }
+    write_str(writer, record)
+}
+
+fn write_str(writer: &mut impl Write, s: &str) -> io::Result<()> {
+    writer.write_all(&(s.len() as u64).to_le_bytes())?;
+    writer.write_all(s.as_bytes())
+}
+
+fn read_entry(reader: &mut impl Read) -> io::Result<Option<(Key, String)>> {
+    let mut tag = [0];
+    if reader.read(&mut tag)? == 0 {
+        return Ok(None);
+    }
+    let key = match tag[0] {
+        0 => Key::Number(f64::from_bits(read_u64(reader)?)),
+        1 => Key::Text(read_str(reader)?),
+        2 => Key::Natural(read_str(reader)?),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Corrupt sort run",
+            ))
+        }
+    };
+    Ok(Some((key, read_str(reader)?)))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn read_str(reader: &mut impl Read) -> io::Result<String> {
+    let mut bytes = vec![0; read_u64(reader)? as usize];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sort(kind: KeyKind, descending: bool, budget: usize, keys: &[&str]) -> Vec<String> {
+        let mut sorter = Sorter::new(descending, budget);
+        for (i, key) in keys.iter().enumerate() {
+            let record = format!("{}#{}", key, i);
+            sorter.push(Key::new(kind, key), record).unwrap();
+        }
+        sorter.finish().unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn test_kinds() {
+        let keys = ["b10", "a", "b9", "B"];
+        let sorted = sort(KeyKind::String, false, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(sorted, ["B#3", "a#1", "b10#0", "b9#2"]);
+        let sorted = sort(KeyKind::Natural, false, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(sorted, ["B#3", "a#1", "b9#2", "b10#0"]);
+        let keys = ["10", "x", "9.5", "-1"];
+        let sorted = sort(KeyKind::Numeric, false, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(sorted, ["-1#3", "9.5#2", "10#0", "x#1"]);
+        let sorted = sort(KeyKind::Numeric, true, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(sorted, ["x#1", "10#0", "9.5#2", "-1#3"]);
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        assert_eq!(natural_cmp("file2.txt", "file10.txt"), Ordering::Less);
+        assert_eq!(natural_cmp("v1.10", "v1.9"), Ordering::Greater);
+        assert_eq!(natural_cmp("x01", "x1"), Ordering::Greater);
+        assert_eq!(
+            natural_cmp("99999999999999999999999", "100000000000000000000000"),
+            Ordering::Less
+        );
+        assert_eq!(natural_cmp("ä2", "ä2"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_stable() {
+        let keys = ["1", "0", "1", "0"];
+        let sorted = sort(KeyKind::Numeric, true, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(sorted, ["1#0", "1#2", "0#1", "0#3"]);
+    }
+
+    #[test]
+    fn test_spill() {
+        let keys: Vec<String> = (0..1000)
+            .map(|i| ((i * 7919) % 1000 / 2).to_string())
+            .collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let mut sorter = Sorter::new(false, 1000);
+        for (i, key) in keys.iter().enumerate() {
+            sorter
+                .push(Key::new(KeyKind::Numeric, key), format!("{}#{}", key, i))
+                .unwrap();
+        }
+        assert!(sorter.runs.len() > 10);
+        let paths: Vec<PathBuf> = sorter.runs.iter().map(|run| run.path.clone()).collect();
+        let spilled: Vec<String> = sorter.finish().unwrap().map(Result::unwrap).collect();
+        let in_memory = sort(KeyKind::Numeric, false, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(spilled, in_memory);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn test_merge_fan_in() {
+        let keys: Vec<String> = (0..1000).map(|i| ((i * 7919) % 100).to_string()).collect();
+        let keys: Vec<&str> = keys.iter().map(String::as_str).collect();
+        let mut sorter = Sorter::new(true, 500);
+        sorter.fan_in = 3;
+        for (i, key) in keys.iter().enumerate() {
+            sorter
+                .push(Key::new(KeyKind::Numeric, key), format!("{}#{}", key, i))
+                .unwrap();
+        }
+        // Merged in several rounds
+        assert!(sorter.runs.len() > 9);
+        let paths: Vec<PathBuf> = sorter.runs.iter().map(|run| run.path.clone()).collect();
+        let merged: Vec<String> = sorter.finish().unwrap().map(Result::unwrap).collect();
+        let in_memory = sort(KeyKind::Numeric, true, DEFAULT_MEMORY_BUDGET, &keys);
+        assert_eq!(merged, in_memory);
+        assert!(paths.iter().all(|path| !path.exists()));
+    }
+
+    #[test]
+    fn test_line_key() {
+        let key = LineKey::try_from("-1:numeric").unwrap();
+        assert_eq!(key.key("a 12 b"), Key::Number(12.0));
+        assert!(key.descending);
+        let key = LineKey::try_from("_").unwrap();
+        assert_eq!(key.key("a 12 b"), Key::Text("a 12 b".to_string()));
+        let key = LineKey::try_from("5:natural").unwrap();
+        assert_eq!(key.key("a"), Key::Natural(String::new()));
+        assert!(LineKey::try_from("x").is_err());
+        assert!(LineKey::try_from("1:fast").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1000"), Ok(1000));
+        assert_eq!(parse_size("64M"), Ok(64 << 20));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+
+    #[test]
+    fn test_order() {
+        let order = Order::try_from("-natural").unwrap();
+        assert_eq!(order.kind, Some(KeyKind::Natural));
+        assert!(order.descending);
+        assert_eq!(Order::try_from("").unwrap(), Order::default());
+        assert!(Order::try_from("-").unwrap().descending);
+        assert!(Order::try_from("numerical").is_err());
+    }
+}
@@ -0,0 +1,129 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+// Splitting of the output into files named after a key, e.g. `logs/{key}.log`.
+// Only a limited number of files is kept open, so that there may be many more
+// keys than file descriptors: when the limit is reached, the least recently
+// written file is closed, and it is reopened for appending when it is written
+// to again. A file is truncated when it is opened for the first time.
+
+pub const DEFAULT_MAX_OPEN: usize = 256;
+
+/// The placeholder for the key in the template of the paths
+const PLACEHOLDER: &str = "{key}";
+
+pub struct SplitFiles {
+    template: String,
+    max_open: usize,
+    /// The open files, from the least to the most recently written one
+    open: IndexMap<String, BufWriter<File>>,
+    /// The paths of all files that have been opened
+    opened: HashSet<String>,
+}
+
+impl SplitFiles {
+    pub fn new(template: &str, max_open: usize) -> Result<Self, String> {
+        if !template.contains(PLACEHOLDER) {
+            return Err(format!("The output path must contain {}", PLACEHOLDER));
+        }
+        if max_open == 0 {
+            return Err("At least one file must be kept open".to_string());
+        }
+        Ok(SplitFiles {
+            template: template.to_string(),
+            max_open,
+            open: IndexMap::new(),
+            opened: HashSet::new(),
+        })
+    }
+
+    /// The path of the file for a key. Slashes in the key are replaced, so
+    /// that it cannot refer to another directory.
+    pub fn path(&self, key: &str) -> String {
+        let key = key.replace(['/', '\0'], "_");
+        self.template.replace(PLACEHOLDER, &key)
+    }
+
+    /// Writes to the file for a key, which is only created once something is
+    /// written to it
+    pub fn write(&mut self, key: &str, text: &str) -> io::Result<()> {
+        if text.is_empty() {
+            return Ok(());
+        }
+        let path = self.path(key);
+        match self.open.get_index_of(&path) {
+            Some(i) => self.open.move_index(i, self.open.len() - 1),
+            None => {
+                if self.open.len() >= self.max_open {
+                    if let Some((_, mut file)) = self.open.shift_remove_index(0) {
+                        file.flush()?;
+                    }
+                }
+                let file = self.open_file(&path)?;
+                self.open.insert(path.clone(), BufWriter::new(file));
+            }
+        }
+        let file = self.open.get_mut(&path).expect("the file is open");
+        file.write_all(text.as_bytes())
+    }
+
+    fn open_file(&mut self, path: &str) -> io::Result<File> {
+        let append = !self.opened.insert(path.to_string());
+        if !append {
+            if let Some(dir) = Path::new(path).parent() {
+                fs::create_dir_all(dir)?;
+            }
+        }
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(append)
+            .truncate(!append)
+            .open(path)
+    }
+
+    /// Flushes and closes all files
+    pub fn finish(mut self) -> io::Result<()> {
+        for (_, mut file) in self.open.drain(..) {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let dir = std::env::temp_dir().join(format!("tt-split-{}", std::process::id()));
+        let template = format!("{}/logs/{{key}}.log", dir.display());
+        fs::create_dir_all(dir.join("logs")).unwrap();
+        fs::write(dir.join("logs/a.log"), "old\n").unwrap();
+        // Only one file is open at a time, so that every switch of the key
+        // closes and reopens a file
+        let mut files = SplitFiles::new(&template, 1).unwrap();
+        for (key, text) in [("a", "1\n"), ("b", "2\n"), ("a", "3\n"), ("c/d", "4\n")] {
+            files.write(key, text).unwrap();
+        }
+        files.write("e", "").unwrap();
+        files.finish().unwrap();
+        let read = |name: &str| fs::read_to_string(dir.join("logs").join(name)).unwrap();
+        assert_eq!(read("a.log"), "1\n3\n");
+        assert_eq!(read("b.log"), "2\n");
+        assert_eq!(read("c_d.log"), "4\n");
+        assert!(!dir.join("logs/e.log").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(SplitFiles::new("out.log", 1).is_err());
+        assert!(SplitFiles::new("{key}.log", 0).is_err());
+    }
+}
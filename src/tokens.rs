@@ -1,65 +1,222 @@
 #[derive(Debug, Eq, PartialEq)]
 pub enum Token {
     Alphanum(String),
+    String(String),
     OpenParen,
     CloseParen,
     OpenBracket,
     CloseBracket,
     Underscore,
     Semicolon,
+    Comma,
+    OpenBrace,
+    CloseBrace,
+    Colon,
+    Assign,
+    /// Arithmetic, comparison and logical operators, and compound assignments
+    /// like `+=`
+    Operator(String),
+    /// A regex literal like `/^a+$/`
+    Regex(String),
 }
 
 enum State {
     Init,
     InAlphanum,
+    InString,
+    InStringEscape,
+    InOperator(char),
+    InRegex,
+    InRegexEscape,
 }
 
+const OPERATORS: [&str; 21] = [
+    "+", "-", "*", "/", "%", "==", "!=", "<", "<=", ">", ">=", "&&", "||", "!", "+=", "-=", "*=",
+    "/=", "%=", "~", "!~",
+];
+
 pub fn scan(source_code: String) -> Result<Vec<Token>, String> {
+    let tokens = scan_located(&source_code).map_err(|(msg, _)| msg)?;
+    Ok(tokens.into_iter().map(|(token, _)| token).collect())
+}
+
+/// Like `scan`, but with the line number of each token, starting at 1. A token
+/// that spans lines, i.e. a string literal, has the line it ends on. Errors
+/// have the line they occur on.
+pub fn scan_located(source_code: &str) -> Result<Vec<(Token, usize)>, (String, usize)> {
     let mut tokens = Vec::new();
+    let mut lines = Vec::new();
+    let mut line = 1;
+    scan_lines(source_code, &mut tokens, &mut lines, &mut line).map_err(|msg| (msg, line))?;
+    Ok(tokens.into_iter().zip(lines).collect())
+}
+
+fn scan_lines(
+    source_code: &str,
+    tokens: &mut Vec<Token>,
+    lines: &mut Vec<usize>,
+    line: &mut usize,
+) -> Result<(), String> {
     let mut charbuffer = vec![];
     let mut state = State::Init;
     for ch in source_code.chars() {
         match (&state, ch) {
-            (State::Init, _) if ch.is_whitespace() => continue,
-            (State::Init, _) if ch.is_alphanumeric() => {
+            (State::Init, _) => state = scan_init(ch, tokens, &mut charbuffer)?,
+            (State::InString, '"') => {
+                tokens.push(Token::String(charbuffer.iter().collect()));
+                charbuffer.clear();
+                state = State::Init;
+            }
+            (State::InString, '\\') => state = State::InStringEscape,
+            (State::InString, _) => charbuffer.push(ch),
+            (State::InStringEscape, _) => {
+                charbuffer.push(match ch {
+                    'n' => '\n',
+                    't' => '\t',
+                    'r' => '\r',
+                    '0' => '\0',
+                    '"' | '\\' => ch,
+                    other => return Result::Err(format!("Invalid escape sequence '\\{}'", other)),
+                });
+                state = State::InString;
+            }
+            (State::InRegex, '/') => {
+                tokens.push(Token::Regex(charbuffer.iter().collect()));
+                charbuffer.clear();
+                state = State::Init;
+            }
+            (State::InRegex, '\\') => state = State::InRegexEscape,
+            (State::InRegex, _) => charbuffer.push(ch),
+            // Escape sequences are passed on to the regex engine, except for
+            // an escaped '/', which would end the literal otherwise
+            (State::InRegexEscape, _) => {
+                if ch != '/' {
+                    charbuffer.push('\\');
+                }
                 charbuffer.push(ch);
-                state = State::InAlphanum;
+                state = State::InRegex;
             }
-            (State::Init, '(') => tokens.push(Token::OpenParen),
-            (State::Init, ')') => tokens.push(Token::CloseParen),
-            (State::Init, '[') => tokens.push(Token::OpenBracket),
-            (State::Init, ']') => tokens.push(Token::CloseBracket),
-            (State::Init, ';') => tokens.push(Token::Semicolon),
-            (State::Init, '_') => tokens.push(Token::Underscore),
-            (State::Init, _) => return Result::Err(format!("Invalid character '{}'", ch)),
             (State::InAlphanum, _) if ch.is_alphanumeric() || ch == '_' => {
                 charbuffer.push(ch);
             }
+            (State::InAlphanum, '.' | '+' | '-') if continues_number(&charbuffer, ch) => {
+                charbuffer.push(ch);
+            }
+            // Qualified names like `agg.sum`
+            (State::InAlphanum, '.') if charbuffer[0].is_alphabetic() => {
+                charbuffer.push(ch);
+            }
             (State::InAlphanum, other) => {
                 tokens.push(Token::Alphanum(charbuffer.iter().collect()));
                 charbuffer.clear();
-
-                match other {
-                    ws if ws.is_whitespace() => {}
-                    '(' => tokens.push(Token::OpenParen),
-                    ')' => tokens.push(Token::CloseParen),
-                    '[' => tokens.push(Token::OpenBracket),
-                    ']' => tokens.push(Token::CloseBracket),
-                    ';' => tokens.push(Token::Semicolon),
-                    unexpected => {
-                        return Result::Err(format!("Invalid character '{}'", unexpected))
-                    }
+                state = scan_init(other, tokens, &mut charbuffer)?;
+            }
+            (State::InOperator(first), _) => {
+                let operator: String = [*first, ch].iter().collect();
+                if OPERATORS.contains(&operator.as_str()) {
+                    tokens.push(Token::Operator(operator));
+                    state = State::Init;
+                } else {
+                    tokens.push(single_char_operator(*first)?);
+                    state = scan_init(ch, tokens, &mut charbuffer)?;
                 }
-
-                state = State::Init;
             }
         }
+        lines.resize(tokens.len(), *line);
+        if ch == '\n' {
+            *line += 1;
+        }
+    }
+    if let State::InString | State::InStringEscape = state {
+        return Result::Err(String::from("Unterminated string literal"));
+    }
+    if let State::InRegex | State::InRegexEscape = state {
+        return Result::Err(String::from("Unterminated regex literal"));
+    }
+    if let State::InOperator(ch) = state {
+        tokens.push(single_char_operator(ch)?);
     }
     if !charbuffer.is_empty() {
         let token = Token::Alphanum(charbuffer.iter().collect());
         tokens.push(token);
     }
-    Ok(tokens)
+    lines.resize(tokens.len(), *line);
+    Ok(())
+}
+
+/// Handles a char that is not part of a token that is currently being
+/// scanned, returning the state for scanning the next char
+fn scan_init(
+    ch: char,
+    tokens: &mut Vec<Token>,
+    charbuffer: &mut Vec<char>,
+) -> Result<State, String> {
+    match ch {
+        _ if ch.is_whitespace() => {}
+        _ if ch.is_alphanumeric() => {
+            charbuffer.push(ch);
+            return Ok(State::InAlphanum);
+        }
+        '"' => return Ok(State::InString),
+        '(' => tokens.push(Token::OpenParen),
+        ')' => tokens.push(Token::CloseParen),
+        '[' => tokens.push(Token::OpenBracket),
+        ']' => tokens.push(Token::CloseBracket),
+        '{' => tokens.push(Token::OpenBrace),
+        '}' => tokens.push(Token::CloseBrace),
+        ';' => tokens.push(Token::Semicolon),
+        ',' => tokens.push(Token::Comma),
+        ':' => tokens.push(Token::Colon),
+        '_' => tokens.push(Token::Underscore),
+        // A '/' where an operand is expected starts a regex rather than a
+        // division, e.g. in `_ ~ /a/` as opposed to `a / b`
+        '/' if !ends_operand(tokens.last()) => return Ok(State::InRegex),
+        '+' | '-' | '*' | '/' | '%' | '=' | '!' | '<' | '>' | '&' | '|' | '~' => {
+            return Ok(State::InOperator(ch))
+        }
+        _ => return Result::Err(format!("Invalid character '{}'", ch)),
+    }
+    Ok(State::Init)
+}
+
+fn single_char_operator(ch: char) -> Result<Token, String> {
+    match ch {
+        '=' => Ok(Token::Assign),
+        '&' | '|' => Err(format!(
+            "Invalid operator '{}', did you mean '{}{}'?",
+            ch, ch, ch
+        )),
+        _ => Ok(Token::Operator(ch.to_string())),
+    }
+}
+
+fn ends_operand(token: Option<&Token>) -> bool {
+    matches!(
+        token,
+        Some(
+            Token::Alphanum(_)
+                | Token::String(_)
+                | Token::Regex(_)
+                | Token::Underscore
+                | Token::CloseParen
+                | Token::CloseBracket
+        )
+    )
+}
+
+/// Number literals may contain a decimal point and an exponent with a sign,
+/// e.g. `1.5e-3`, unless they are hexadecimal
+fn continues_number(charbuffer: &[char], ch: char) -> bool {
+    let is_decimal = charbuffer
+        .first()
+        .map(|c| c.is_ascii_digit())
+        .unwrap_or(false)
+        && !matches!(charbuffer, ['0', 'x' | 'X', ..]);
+    let last = charbuffer.last();
+    match ch {
+        '.' => is_decimal && !charbuffer.iter().any(|c| matches!(c, '.' | 'e' | 'E')),
+        _ => is_decimal && matches!(last, Some('e' | 'E')),
+    }
 }
 
 #[cfg(test)]
@@ -83,6 +240,25 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_lines() {
+        let source_code = "a = 1;\n\nprint(\"x\ny\")\n";
+        let lines: Vec<usize> = scan_located(source_code)
+            .unwrap()
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect();
+        assert_eq!(lines, [1, 1, 1, 1, 3, 3, 4, 4]);
+        assert_eq!(
+            scan_located("a;\nb = \"x"),
+            Err(("Unterminated string literal".to_string(), 2))
+        );
+        assert_eq!(
+            scan_located("a;\n\n$"),
+            Err(("Invalid character '$'".to_string(), 3))
+        );
+    }
+
     #[test]
     fn test_superfluous_ws() {
         let source_code = String::from("print   (_);");
@@ -97,6 +273,144 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_multiple_args() {
+        let source_code = String::from("foo(_,bar, \"baz\")");
+        let expected = vec![
+            Token::Alphanum(String::from("foo")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::Comma,
+            Token::Alphanum(String::from("bar")),
+            Token::Comma,
+            Token::String(String::from("baz")),
+            Token::CloseParen,
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_numbers() {
+        let source_code = String::from("1.5e-3 0x1e-3 2-1");
+        let expected = vec![
+            Token::Alphanum(String::from("1.5e-3")),
+            Token::Alphanum(String::from("0x1e")),
+            Token::Operator(String::from("-")),
+            Token::Alphanum(String::from("3")),
+            Token::Alphanum(String::from("2")),
+            Token::Operator(String::from("-")),
+            Token::Alphanum(String::from("1")),
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_operators() {
+        let source_code = String::from("x+=-a<=b&&!c==d=e");
+        let expected = vec![
+            Token::Alphanum(String::from("x")),
+            Token::Operator(String::from("+=")),
+            Token::Operator(String::from("-")),
+            Token::Alphanum(String::from("a")),
+            Token::Operator(String::from("<=")),
+            Token::Alphanum(String::from("b")),
+            Token::Operator(String::from("&&")),
+            Token::Operator(String::from("!")),
+            Token::Alphanum(String::from("c")),
+            Token::Operator(String::from("==")),
+            Token::Alphanum(String::from("d")),
+            Token::Assign,
+            Token::Alphanum(String::from("e")),
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_regex() {
+        let source_code = String::from(r#"a/b ~ /^\d+\/x$/; _ !~/a/"#);
+        let expected = vec![
+            Token::Alphanum(String::from("a")),
+            Token::Operator(String::from("/")),
+            Token::Alphanum(String::from("b")),
+            Token::Operator(String::from("~")),
+            Token::Regex(String::from(r"^\d+/x$")),
+            Token::Semicolon,
+            Token::Underscore,
+            Token::Operator(String::from("!~")),
+            Token::Regex(String::from("a")),
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+        assert!(scan(String::from("split(_, /a)")).is_err());
+    }
+
+    #[test]
+    fn test_qualified_name() {
+        let source_code = String::from("agg.p99.9(x.5)");
+        let expected = vec![
+            Token::Alphanum(String::from("agg.p99.9")),
+            Token::OpenParen,
+            Token::Alphanum(String::from("x.5")),
+            Token::CloseParen,
+        ];
+        assert_eq!(scan(source_code).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_single_ampersand() {
+        assert!(scan(String::from("a & b")).is_err());
+    }
+
+    #[test]
+    fn test_string_literal() {
+        let source_code = String::from(r#"foo(_["say \"hi\""])"#);
+        let expected = vec![
+            Token::Alphanum(String::from("foo")),
+            Token::OpenParen,
+            Token::Underscore,
+            Token::OpenBracket,
+            Token::String(String::from("say \"hi\"")),
+            Token::CloseBracket,
+            Token::CloseParen,
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn test_unterminated_string_literal() {
+        let source_code = String::from("foo(\"bar)");
+        assert!(scan(source_code).is_err());
+    }
+
+    #[test]
+    fn test_block() {
+        let source_code = String::from("for k in m{x[k]={\"a\":k}}");
+        let expected = vec![
+            Token::Alphanum(String::from("for")),
+            Token::Alphanum(String::from("k")),
+            Token::Alphanum(String::from("in")),
+            Token::Alphanum(String::from("m")),
+            Token::OpenBrace,
+            Token::Alphanum(String::from("x")),
+            Token::OpenBracket,
+            Token::Alphanum(String::from("k")),
+            Token::CloseBracket,
+            Token::Assign,
+            Token::OpenBrace,
+            Token::String(String::from("a")),
+            Token::Colon,
+            Token::Alphanum(String::from("k")),
+            Token::CloseBrace,
+            Token::CloseBrace,
+        ];
+        let actual = scan(source_code).unwrap();
+        assert_eq!(actual, expected);
+    }
+
     #[test]
     fn test_underscore_identifier() {
         let source_code = String::from("foo_bar");